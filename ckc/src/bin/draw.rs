@@ -1,10 +1,12 @@
+#![forbid(unsafe_code)]
+
 use color_eyre::Report;
-use plotters::prelude::*;
+use plotters::{coord::Shift, prelude::*};
 use structopt::StructOpt;
 
-use std::{path::PathBuf, time::Instant};
+use std::{fs::File, io::BufWriter, path::PathBuf, time::Instant};
 
-use ckc::get_data;
+use ckc::{get_data, write_csv, AcceptanceCurves};
 
 /// Command line options
 #[derive(Debug, StructOpt)]
@@ -20,6 +22,26 @@ struct Opt {
     /// Delta u range
     #[structopt(short, long, default_value = "0.1")]
     delta: f64,
+
+    /// Smallest kappa plotted
+    #[structopt(long, default_value = "144")]
+    kappa_min: usize,
+
+    /// Largest kappa plotted
+    #[structopt(long, default_value = "159")]
+    kappa_max: usize,
+
+    /// Number of kappa values plotted between the bounds
+    #[structopt(long, default_value = "5")]
+    kappa_num: usize,
+
+    /// Graph file, written as SVG if its extension is .svg and as PNG otherwise
+    #[structopt(short, long, parse(from_os_str), default_value = "graph.png")]
+    output: PathBuf,
+
+    /// Write the acceptance curves to a CSV file instead of plotting them
+    #[structopt(long, parse(from_os_str))]
+    csv: Option<PathBuf>,
 }
 
 fn main() -> Result<(), Report> {
@@ -29,12 +51,47 @@ fn main() -> Result<(), Report> {
     let delta = opt.delta;
 
     let u_max = ((1.0 + delta) * u as f64) as usize;
-    let u_min = ((1.0 - delta) * u as f64) as usize;
 
-    let data = get_data(opt.program, u, u_max)?;
+    let kappas = opt.kappa_min..=opt.kappa_max;
+    let (data, stats) = get_data(opt.program, u, u_max, kappas, opt.kappa_num)?;
+    for stat in stats {
+        println!(
+            "Kappa = 2^{}: {} valid hashes, observed p = {:e}, expected p = {:e}",
+            stat.kappa, stat.valid, stat.empirical_p, stat.expected_p
+        );
+    }
+
+    if let Some(path) = opt.csv {
+        return write_csv(&data, BufWriter::new(File::create(path)?));
+    }
+
+    let is_svg = opt
+        .output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("svg"));
+    if is_svg {
+        let root = SVGBackend::new(&opt.output, (1024, 768)).into_drawing_area();
+        plot(&root, data, u, delta)
+    } else {
+        let root = BitMapBackend::new(&opt.output, (1024, 768)).into_drawing_area();
+        plot(&root, data, u, delta)
+    }
+}
+
+/// Plot the acceptance curves, globally and around the claimed value
+fn plot<DB>(
+    root: &DrawingArea<DB, Shift>,
+    data: AcceptanceCurves,
+    u: usize,
+    delta: f64,
+) -> Result<(), Report>
+where
+    DB: DrawingBackend,
+    DB::ErrorType: 'static,
+{
+    let u_max = ((1.0 + delta) * u as f64) as usize;
+    let u_min = ((1.0 - delta) * u as f64) as usize;
 
-    // Graph part
-    let root = BitMapBackend::new("graph.png", (1024, 768)).into_drawing_area();
     root.fill(&WHITE)?;
 
     // Two parts: one global [0, (1+delta)*u] and another localised [(1-delta)*u, (1+delta)*u]
@@ -92,7 +149,7 @@ fn main() -> Result<(), Report> {
             ))
             .unwrap()
             .label(format!("Kappa = 2^{}", kappa))
-            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &Palette99::pick(k)));
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], Palette99::pick(k)));
 
         chart2
             .draw_series(LineSeries::new(
@@ -101,7 +158,7 @@ fn main() -> Result<(), Report> {
             ))
             .unwrap()
             .label(format!("Kappa = 2^{}", kappa))
-            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &Palette99::pick(k)));
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], Palette99::pick(k)));
 
         println!(
             "Printed for kappa = 2^{} in {:?}",
@@ -117,16 +174,18 @@ fn main() -> Result<(), Report> {
     chart1
         .configure_series_labels()
         .position(SeriesLabelPosition::MiddleLeft)
-        .background_style(&WHITE.mix(0.8))
-        .border_style(&BLACK)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
         .draw()?;
 
     chart2
         .configure_series_labels()
         .position(SeriesLabelPosition::MiddleLeft)
-        .background_style(&WHITE.mix(0.8))
-        .border_style(&BLACK)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
         .draw()?;
 
+    root.present()?;
+
     Ok(())
 }
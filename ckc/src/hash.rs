@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+/// Enum representing the hash functions available to hash the VM states
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HashAlgo {
+    /// SHA-1, 160-bit digest
+    #[default]
+    Sha1,
+    /// SHA-256, 256-bit digest
+    Sha256,
+    /// BLAKE3, 256-bit digest
+    Blake3,
+}
+
+impl HashAlgo {
+    /// Bit length of the digest produced by the hash function
+    pub const fn digest_bits(self) -> u64 {
+        match self {
+            Self::Sha1 => 160,
+            Self::Sha256 | Self::Blake3 => 256,
+        }
+    }
+}
+
+/// Hasher for the selected hash function
+#[derive(Clone)]
+pub enum Hasher {
+    /// SHA-1 hasher
+    Sha1(Sha1),
+    /// SHA-256 hasher
+    Sha256(Sha256),
+    /// BLAKE3 hasher
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    /// Create a new hasher for the given hash function
+    pub fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Sha1 => Self::Sha1(Sha1::new()),
+            HashAlgo::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgo::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// Feed data to the hasher
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Consume the hasher and return the digest
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Sha1(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
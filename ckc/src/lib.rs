@@ -1,34 +1,48 @@
+#![forbid(unsafe_code)]
+
+mod hash;
 mod proof;
 mod prover;
 mod stats;
 mod verifier;
 mod vm;
 
-pub use proof::{ProofParams, ProofStrategy};
-pub use prover::Prover;
-pub use verifier::Verifier;
-pub use vm::get_data;
+pub use hash::HashAlgo;
+pub use proof::{
+    CachedRun, Manifest, Proof, ProofParams, ProofParamsBuilder, ProofReport, ProofStrategy,
+    ResultSource, WitnessCache, PROOF_VERSION,
+};
+pub use prover::{Prover, PROGRESS_INTERVAL};
+pub use stats::{compute_failure_probability, compute_q};
+pub use tinyvm::Endianness;
+pub use verifier::{ValidationResult, Verifier};
+pub use vm::{
+    get_data, write_csv, AcceptanceCurves, InstrumentedVM, KappaStats, Replay, ReplayStep,
+    RunResult,
+};
 
 #[cfg(test)]
 mod tests {
     use crate::*;
     use color_eyre::Report;
 
+    use std::{cell::RefCell, env};
+
     #[test]
     fn run_prover_and_verifier() -> Result<(), Report> {
         let params = ProofParams::new(
             "../assets/collatz_v0.tr",
-            1..1000,
+            1..1001,
             0,
             155,
             1000,
             ProofStrategy::BestEffort,
         );
-        let prover = Prover::new(params);
+        let prover = Prover::new(params)?;
 
         // Get proof
         let proof = prover.obtain_proof()?;
-        let verifier = Verifier::new(proof);
+        let verifier = Verifier::new(proof)?;
 
         // Check proof
         let result = verifier.check_proof();
@@ -37,4 +51,492 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn run_prover_and_verifier_with_sha256() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..200,
+            0,
+            252,
+            5,
+            ProofStrategy::BestEffort,
+        )
+        .with_hash_algo(HashAlgo::Sha256);
+        let prover = Prover::new(params)?;
+
+        // Get proof
+        let proof = prover.obtain_proof()?;
+        assert!(!proof.vset.is_empty());
+
+        // Check proof
+        let result = Verifier::new(proof.clone())?.check_proof();
+        result.display();
+        assert!(result.valid);
+
+        // Check proof with another hash function of the same digest size
+        let mut mismatched = proof;
+        mismatched.params.hash_algo = HashAlgo::Blake3;
+
+        let result = Verifier::new(mismatched)?.check_proof();
+        result.display();
+        assert!(!result.valid);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_prover_and_verifier_with_salts() -> Result<(), Report> {
+        let salted_proof = |salt| {
+            let params = ProofParams::new(
+                "../assets/collatz_v0.tr",
+                1..1000,
+                0,
+                155,
+                10,
+                ProofStrategy::ReTestingSalt(salt),
+            );
+            Prover::new(params)?.obtain_proof()
+        };
+
+        let proof1 = salted_proof(1)?;
+        let proof2 = salted_proof(2)?;
+
+        assert_ne!(proof1.vset, proof2.vset);
+
+        // Check proofs
+        for proof in [proof1, proof2] {
+            let result = Verifier::new(proof)?.check_proof();
+            result.display();
+            assert!(result.valid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn save_and_load_proof() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            155,
+            10,
+            ProofStrategy::OverTesting(0.9),
+        );
+        let proof = Prover::new(params)?.obtain_proof()?;
+        assert!(proof.extended_domain.is_some());
+
+        let path = env::temp_dir().join("ckc_save_and_load_proof.json");
+        proof.save(&path)?;
+        let loaded = Proof::load(&path)?;
+
+        assert_eq!(loaded.vset, proof.vset);
+        assert_eq!(loaded.extended_domain, proof.extended_domain);
+        assert_eq!(loaded.params.input_domain, proof.params.input_domain);
+
+        // Check proofs
+        let expected = Verifier::new(proof)?.check_proof();
+        let result = Verifier::new(loaded)?.check_proof();
+
+        assert_eq!(result.export(), expected.export());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_params() {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..10,
+            0,
+            155,
+            10,
+            ProofStrategy::BestEffort,
+        );
+        let proof = Proof {
+            version: PROOF_VERSION,
+            vset: vec![],
+            extended_domain: None,
+            params: params.clone(),
+            cache: None,
+        };
+
+        let expected = "Cannot require 10 witnesses from a domain of 9 inputs";
+        assert_eq!(Prover::new(params).err().unwrap().to_string(), expected);
+        assert_eq!(Verifier::new(proof).err().unwrap().to_string(), expected);
+    }
+
+    #[test]
+    fn merge_adjacent_proofs() -> Result<(), Report> {
+        let prove = |domain, kappa| {
+            let params = ProofParams::new(
+                "../assets/collatz_v0.tr",
+                domain,
+                0,
+                kappa,
+                5,
+                ProofStrategy::BestEffort,
+            );
+            Prover::new(params)?.obtain_proof()
+        };
+        let (low, high) = (prove(1..1000, 155)?, prove(1000..2000, 155)?);
+
+        let merged = low.clone().merge(high)?;
+        let expected = prove(1..2000, 155)?;
+        assert_eq!(merged.params.input_domain, 1..2000);
+        assert_eq!(merged.params.v, 10);
+        assert_eq!(merged.vset, expected.vset);
+
+        let result = Verifier::new(merged)?.check_proof();
+        result.display();
+        assert!(result.valid);
+
+        // Overlapping witnesses are only kept once, and only required for the union
+        let merged = low.clone().merge(prove(500..1500, 155)?)?;
+        assert!(merged.vset.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(merged.params.v, 8);
+        assert!(Verifier::new(merged)?.check_proof().valid);
+
+        let merged = low.clone().merge(low.clone())?;
+        assert_eq!(merged.params.v, 5);
+        assert_eq!(merged.vset, low.vset);
+        assert!(Verifier::new(merged)?.check_proof().valid);
+
+        // The domains may be written differently
+        let params = ProofParams::builder()
+            .program("../assets/collatz_v0.tr")
+            .domain_inclusive(1000..=1999)
+            .kappa(155)
+            .v(5)
+            .strategy(ProofStrategy::BestEffort)
+            .build()?;
+        let merged = low.clone().merge(Prover::new(params)?.obtain_proof()?)?;
+        assert_eq!(merged.params.input_domain, 1..2000);
+        assert!(!merged.params.inclusive_domain);
+
+        let error = |other: Proof| low.clone().merge(other).unwrap_err().to_string();
+        assert_eq!(
+            error(prove(1500..2000, 155)?),
+            "Cannot merge proofs over 1..1000 and 1500..2000, the domains are not adjacent"
+        );
+        assert_eq!(
+            error(prove(1000..2000, 154)?),
+            "Cannot merge proofs with different params"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_diverging_inputs() -> Result<(), Report> {
+        let params = ProofParams::builder()
+            .program("../assets/test_diverge.tr")
+            .domain(1..300)
+            .kappa(158)
+            .v(5)
+            .step_limit(1000)
+            .build()?;
+        let proof = Prover::new(params)?.obtain_proof()?;
+        println!("Witnesses = {:?}", proof.vset);
+
+        assert!(proof.vset.len() >= 5);
+        assert!(proof.vset.iter().all(|i| i % 7 != 0));
+
+        let result = Verifier::new(proof.clone())?.check_proof();
+        assert!(result.valid);
+
+        // A diverging witness is rejected by the verifier
+        let mut proof = proof;
+        proof.vset.push(7);
+        proof.vset.sort_unstable();
+        let result = Verifier::new(proof)?.check_proof();
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::ExecutionError)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn prove_with_each_endianness() -> Result<(), Report> {
+        let prove = |endianness| {
+            let params = ProofParams::builder()
+                .program("../assets/collatz_v0.tr")
+                .domain(1..1000)
+                .kappa(155)
+                .v(1)
+                .endianness(endianness)
+                .build()?;
+            Prover::new(params)?.with_witness_cache().obtain_proof()
+        };
+        let big = prove(Endianness::Big)?;
+        let little = prove(Endianness::Little)?;
+        println!("Big: {:?}, little: {:?}", big.vset, little.vset);
+
+        assert_eq!(
+            big.params,
+            ProofParams {
+                endianness: Endianness::Big,
+                ..little.params.clone()
+            }
+        );
+        assert_ne!(big.vset, little.vset);
+        for proof in [&big, &little] {
+            let result = Verifier::new(proof.clone())?
+                .with_spot_check()
+                .check_proof();
+            assert!(result.valid);
+        }
+
+        // The verifier hashes the states with the byte order recorded in the proof
+        let mut mismatched = little;
+        mismatched.params.endianness = Endianness::Big;
+        let result = Verifier::new(mismatched)?.with_spot_check().check_proof();
+        assert!(!result.valid);
+
+        Ok(())
+    }
+
+    #[test]
+    fn prove_several_outputs() -> Result<(), Report> {
+        let params = ProofParams::builder()
+            .program("../assets/test_outputs.tr")
+            .domain(1..500)
+            .expected_outputs(&[0, 1])
+            .kappa(156)
+            .v(5)
+            .build()?;
+        let proof = Prover::new(params)?.obtain_proof()?;
+        println!("Witnesses = {:?}", proof.vset);
+
+        let result = Verifier::new(proof.clone())?.check_proof();
+        result.display();
+        assert!(result.valid);
+
+        // Both outputs are part of the claim
+        let mut wrong = proof;
+        wrong.params = wrong.params.with_expected_outputs(&[0, 0]);
+        let result = Verifier::new(wrong)?.check_proof();
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::IncorrectOutput(1))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn prove_answer_code() -> Result<(), Report> {
+        let params = ProofParams::builder()
+            .program("../assets/test_answer.tr")
+            .domain(1..500)
+            .expected_output(1)
+            .kappa(156)
+            .v(5)
+            .result_source(ResultSource::Answer)
+            .build()?;
+        let proof = Prover::new(params)?.obtain_proof()?;
+        println!("Witnesses = {:?}", proof.vset);
+
+        assert!(proof.vset.len() >= 5);
+        assert!(proof.vset.iter().all(|i| i % 2 == 1));
+
+        let result = Verifier::new(proof.clone())?.check_proof();
+        result.display();
+        assert!(result.valid);
+
+        // An even input answers 0
+        let mut wrong = proof.clone();
+        wrong.vset.push(2);
+        wrong.vset.sort_unstable();
+        let result = Verifier::new(wrong)?.check_proof();
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::IncorrectOutput(0))
+        );
+
+        // Read from the memory, a non-zero answer is an execution error
+        let mut wrong = proof;
+        wrong.params.result_source = ResultSource::Memory;
+        let result = Verifier::new(wrong)?.check_proof();
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::ExecutionError)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn prove_negative_output() -> Result<(), Report> {
+        let params = ProofParams::builder()
+            .program("../assets/test_signed.tr")
+            .domain(1..500)
+            .expected_signed_output(-3)
+            .kappa(156)
+            .v(5)
+            .build()?;
+        let proof = Prover::new(params)?.obtain_proof()?;
+        println!("Witnesses = {:?}", proof.vset);
+
+        assert!(proof.vset.len() >= 5);
+        assert!(proof.vset.iter().all(|i| i % 4 == 0));
+
+        let result = Verifier::new(proof.clone())?.check_proof();
+        result.display();
+        assert!(result.valid);
+
+        // Compared as unsigned, the 16-bit result is not -3
+        let mut unsigned = proof;
+        unsigned.params.signed_output = false;
+        let result = Verifier::new(unsigned)?.check_proof();
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::IncorrectOutput(0xfffd))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn seeded_provers_are_reproducible() -> Result<(), Report> {
+        // The threshold must be reachable before the end of the domain, or every
+        // seed sweeps the whole of it and finds the same witnesses
+        let seeded_proof = |seed| {
+            let params = ProofParams::new(
+                "../assets/collatz_v0.tr",
+                1..1000,
+                0,
+                156,
+                10,
+                ProofStrategy::BestEffortAdaptive(0.99),
+            )
+            .with_seed(seed);
+            Prover::new(params)?.obtain_proof()
+        };
+
+        let proof1 = seeded_proof(1)?;
+        let proof2 = seeded_proof(1)?;
+        let proof3 = seeded_proof(2)?;
+        println!("Witnesses = {:?}, {:?}", proof1.vset, proof3.vset);
+
+        assert_eq!(serde_json::to_vec(&proof1)?, serde_json::to_vec(&proof2)?);
+        assert_ne!(proof1.vset, proof3.vset);
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_prover_progress() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..3000,
+            0,
+            155,
+            10,
+            ProofStrategy::BestEffort,
+        );
+        let calls = RefCell::new(vec![]);
+        let progress = |tested, size| calls.borrow_mut().push((tested, size));
+
+        let proof = Prover::new(params.clone())?.obtain_proof_with_progress(Some(&progress))?;
+        let calls = calls.into_inner();
+        println!("Progress = {:?}", calls);
+
+        assert_eq!(calls, vec![(1024, 2999), (2048, 2999), (2999, 2999)]);
+        assert_eq!(proof.vset, Prover::new(params)?.obtain_proof()?.vset);
+
+        Ok(())
+    }
+
+    #[test]
+    fn collatz_manifest() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..201,
+            0,
+            155,
+            10,
+            ProofStrategy::BestEffort,
+        );
+        let proof = Prover::new(params)?.obtain_proof()?;
+        let report = Verifier::new(proof)?.check_proof();
+        let manifest = report.manifest();
+
+        assert_eq!(
+            manifest,
+            Manifest {
+                program: String::from("../assets/collatz_v0.tr"),
+                hash_algo: HashAlgo::Sha1,
+                program_hash: String::from("fd381dea9ac2b6603bf90f81311a325fc4120540"),
+                domain: 1..201,
+                expected_outputs: vec![0],
+                signed_output: false,
+                result_source: ResultSource::Memory,
+                strategy: ProofStrategy::BestEffort,
+                witnesses: 2,
+                confidence: report.q,
+                accepted: true,
+            }
+        );
+
+        let q = report.q;
+        assert_eq!(
+            manifest.to_markdown(),
+            format!(
+                "## Claim\n\
+                 \n\
+                 For all n in [1, 201), program `../assets/collatz_v0.tr` returns 0, \
+                 with confidence {q}.\n\
+                 \n\
+                 | Field | Value |\n\
+                 | --- | --- |\n\
+                 | Program | `../assets/collatz_v0.tr` |\n\
+                 | Program hash (Sha1) | `fd381dea9ac2b6603bf90f81311a325fc4120540` |\n\
+                 | Domain | [1, 201) |\n\
+                 | Expected output | 0 |\n\
+                 | Result source | Memory |\n\
+                 | Strategy | BestEffort |\n\
+                 | Witnesses | 2 |\n\
+                 | Confidence | {q} |\n\
+                 | Accepted | true |",
+                q = q
+            )
+        );
+
+        // The manifest is machine-readable
+        let json = serde_json::to_value(&manifest)?;
+        assert_eq!(json["domain"], serde_json::json!({"start": 1, "end": 201}));
+        assert_eq!(json["expected_outputs"], serde_json::json!([0]));
+        assert_eq!(json["accepted"], true);
+
+        Ok(())
+    }
+
+    #[test]
+    fn prove_inclusive_domain() -> Result<(), Report> {
+        let params = ProofParams::builder()
+            .program("../assets/collatz_v0.tr")
+            .domain_inclusive(1..=200)
+            .kappa(155)
+            .v(2)
+            .build()?;
+        assert_eq!(params.input_domain, 1..201);
+
+        let proof = Prover::new(params)?.obtain_proof()?;
+        let report = Verifier::new(proof)?.check_proof();
+        let text = report.render();
+        println!("{}", text);
+
+        assert!(report.valid);
+        assert!(text.contains("Request: all values in 1..=200"));
+        assert!(text.contains("Claim: all values in 1..=200"));
+
+        // Both ends of the range are part of the claim
+        assert_eq!(report.summary().domain_size, 200);
+
+        Ok(())
+    }
 }
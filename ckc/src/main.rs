@@ -0,0 +1,132 @@
+#![forbid(unsafe_code)]
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report,
+};
+use structopt::StructOpt;
+
+use std::{ops::Range, path::PathBuf};
+
+use ckc::{HashAlgo, Proof, ProofParams, ProofStrategy, Prover, Verifier};
+
+/// Command line options
+#[derive(Debug, StructOpt)]
+enum Opt {
+    /// Produce a proof of a claim and write it to a file
+    Prove {
+        /// Program file
+        #[structopt(parse(from_os_str))]
+        program: PathBuf,
+
+        /// Testing domain of the claim, e.g. 1..1000
+        #[structopt(short, long, parse(try_from_str = parse_domain))]
+        domain: Range<usize>,
+
+        /// Expected output of the program
+        #[structopt(short = "o", long, default_value = "0")]
+        expected_output: usize,
+
+        /// Agreed upon hash max value
+        #[structopt(short, long)]
+        kappa: u64,
+
+        /// Agreed upon number of witnesses
+        #[structopt(short, long, default_value = "0")]
+        v: usize,
+
+        /// Proof strategy, e.g. best-effort or fixed-effort:0.9
+        #[structopt(short, long, default_value = "best-effort")]
+        strategy: ProofStrategy,
+
+        /// Use SHA-256 instead of SHA-1 to hash the VM states
+        #[structopt(long)]
+        sha256: bool,
+
+        /// Save the proof in the compact binary format instead of json
+        #[structopt(long)]
+        binary: bool,
+
+        /// Proof file to write
+        #[structopt(short, long, parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Check a proof read from a file and print the report
+    Verify {
+        /// Proof file to read, either json or binary
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Accepted probability of the claim being false
+        #[structopt(short, long, default_value = "0.01")]
+        epsilon: f64,
+    },
+}
+
+/// Parse a domain written as `start..end`
+fn parse_domain(s: &str) -> Result<Range<usize>, Report> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| eyre!("Invalid domain '{}', expected start..end", s))?;
+    let bound = |value: &str| {
+        value
+            .parse()
+            .wrap_err_with(|| format!("Invalid domain bound '{}'", value))
+    };
+
+    Ok(bound(start)?..bound(end)?)
+}
+
+fn main() -> Result<(), Report> {
+    match Opt::from_args() {
+        Opt::Prove {
+            program,
+            domain,
+            expected_output,
+            kappa,
+            v,
+            strategy,
+            sha256,
+            binary,
+            file,
+        } => {
+            let hash_algo = if sha256 {
+                HashAlgo::Sha256
+            } else {
+                HashAlgo::Sha1
+            };
+            let program = program
+                .to_str()
+                .ok_or_else(|| eyre!("Invalid program path {:?}", program))?;
+            let params = ProofParams::builder()
+                .program(program)
+                .domain(domain)
+                .expected_output(expected_output)
+                .kappa(kappa)
+                .v(v)
+                .strategy(strategy)
+                .hash_algo(hash_algo)
+                .build()?;
+
+            let proof = Prover::new(params)?.obtain_proof()?;
+            println!("Witnesses: {}", proof.vset.len());
+
+            if binary {
+                proof.save_binary(&file)?;
+            } else {
+                proof.save(&file)?;
+            }
+        }
+        Opt::Verify { file, epsilon } => {
+            let proof = Proof::load_any(&file)?;
+            let report = Verifier::check_proofs(vec![proof], epsilon).remove(0);
+            report.display();
+
+            if !report.valid {
+                return Err(eyre!("Proof rejected"));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,9 +1,44 @@
-use serde::{Deserialize, Serialize};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report,
+};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 
-use std::ops::Range;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Bytes, Read, Take, Write},
+    ops::{Range, RangeInclusive},
+    path::Path,
+    str::FromStr,
+};
+
+use tinyvm::{Endianness, HASH_SCHEME_VERSION};
+
+use crate::{
+    hash::{HashAlgo, Hasher},
+    stats::{compute_q_interval, compute_v_min},
+    verifier::ValidationResult,
+};
+
+/// Magic bytes at the start of a binary proof file
+const BINARY_MAGIC: &[u8; 4] = b"CKCP";
+
+/// Risk level of the confidence interval shown in reports
+const REPORT_ALPHA: f64 = 0.05;
+
+/// Version of the binary proof layout
+const BINARY_VERSION: u16 = 13;
+
+/// Version of the json proof layout
+pub const PROOF_VERSION: u32 = 3;
+
+/// Risk level given to fixed effort proofs saved before it was recorded
+const LEGACY_FIXED_EFFORT_EPSILON: f64 = 0.01;
 
 /// Enum representing the available strategies
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ProofStrategy {
     /// Fixed Effort: Verifier check if a specific threshold is obtained
     FixedEffort(f64),
@@ -13,23 +48,111 @@ pub enum ProofStrategy {
     BestEffortAdaptive(f64),
     /// Overtesting: Proves goes beyond the claim to get enough valid samples
     OverTesting(f64),
+    /// Re-testing with salt: Best effort with a salt mixed into the hashes
+    ReTestingSalt(u64),
+}
+
+impl ProofStrategy {
+    /// Return the salt mixed into the hashes, if any
+    pub const fn salt(&self) -> Option<u64> {
+        match self {
+            Self::ReTestingSalt(salt) => Some(*salt),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ProofStrategy {
+    type Err = Report;
+
+    /// Parse a strategy written as `name` or `name:value`, e.g. `fixed-effort:0.9`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            _ => (s, None),
+        };
+
+        let float = |value: Option<&str>| -> Result<f64, Report> {
+            let value = value.ok_or_else(|| eyre!("Strategy '{}' requires a value", name))?;
+            value
+                .parse()
+                .wrap_err_with(|| format!("Invalid value '{}' for strategy '{}'", value, name))
+        };
+
+        match name {
+            "fixed-effort" => Ok(Self::FixedEffort(float(value)?)),
+            "best-effort" => Ok(Self::BestEffort),
+            "best-effort-adaptive" => Ok(Self::BestEffortAdaptive(float(value)?)),
+            "over-testing" => Ok(Self::OverTesting(float(value)?)),
+            "retesting-salt" => {
+                let value = value.ok_or_else(|| eyre!("Strategy '{}' requires a value", name))?;
+                let salt = value
+                    .parse()
+                    .wrap_err_with(|| format!("Invalid salt '{}'", value))?;
+                Ok(Self::ReTestingSalt(salt))
+            }
+            _ => Err(eyre!("Unknown proof strategy '{}'", name)),
+        }
+    }
+}
+
+/// Where the result of a run is read from
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ResultSource {
+    /// The output region of the memory
+    #[default]
+    Memory,
+    /// The code given to `answer`
+    Answer,
 }
 
 /// Parameters used for the proof
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProofParams {
     /// The program used for the proof
     pub program_file: String,
     /// The testing domain of the claim
     pub input_domain: Range<usize>,
-    /// The expected output of the program
-    pub expected_output: usize,
+    /// Whether the domain was claimed as an inclusive range, only used for display
+    #[serde(default)]
+    pub inclusive_domain: bool,
+    /// The expected values of the output region, a single one for most programs
+    pub expected_outputs: Vec<usize>,
     /// The agreed upon hash max value
     pub kappa: u64,
     /// The agreed upon number of witnesses
     pub v: usize,
     /// The proof strategy
     pub strategy: ProofStrategy,
+    /// The hash function used to hash the VM states
+    #[serde(default)]
+    pub hash_algo: HashAlgo,
+    /// Seed of the randomized choices of the prover, if any
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Version of the scheme used to hash the VM states
+    #[serde(default = "legacy_hash_scheme")]
+    pub hash_scheme: u32,
+    /// Hash of the program source, captured by the prover
+    #[serde(default)]
+    pub program_hash: Vec<u8>,
+    /// Maximal number of steps of a run, longer runs are not witnesses
+    #[serde(default)]
+    pub max_steps: Option<usize>,
+    /// Byte order of the words of the hashed states
+    #[serde(default)]
+    pub endianness: Endianness,
+    /// Where the result compared to the expected output is read from
+    #[serde(default)]
+    pub result_source: ResultSource,
+    /// Whether the results are signed words, compared as 64-bit two's complement values
+    #[serde(default)]
+    pub signed_output: bool,
+}
+
+/// Hash scheme of the proofs saved before the scheme was recorded
+const fn legacy_hash_scheme() -> u32 {
+    1
 }
 
 impl ProofParams {
@@ -45,23 +168,855 @@ impl ProofParams {
         Self {
             program_file: String::from(filename),
             input_domain,
-            expected_output: output,
+            inclusive_domain: false,
+            expected_outputs: vec![output],
             kappa,
             v,
             strategy,
+            hash_algo: HashAlgo::default(),
+            seed: None,
+            hash_scheme: HASH_SCHEME_VERSION,
+            program_hash: vec![],
+            max_steps: None,
+            endianness: Endianness::default(),
+            result_source: ResultSource::default(),
+            signed_output: false,
+        }
+    }
+
+    /// Select the hash function used to hash the VM states
+    pub const fn with_hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+        self.hash_algo = hash_algo;
+        self
+    }
+
+    /// Seed the randomized choices of the prover
+    pub const fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Abort the runs exceeding the given number of steps
+    pub const fn with_step_limit(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Select the byte order of the words of the hashed states
+    pub const fn with_endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Read the result of the runs from the given source
+    pub const fn with_result_source(mut self, result_source: ResultSource) -> Self {
+        self.result_source = result_source;
+        self
+    }
+
+    /// Compare the results as signed words
+    ///
+    /// The results are sign-extended from the word size of the program, so the
+    /// expected outputs hold 64-bit two's complement values, e.g. `-3i64 as usize`.
+    pub const fn with_signed_output(mut self) -> Self {
+        self.signed_output = true;
+        self
+    }
+
+    /// Expect several outputs, read from the first memory words
+    pub fn with_expected_outputs(mut self, outputs: &[usize]) -> Self {
+        self.expected_outputs = outputs.to_vec();
+        self
+    }
+
+    /// Return the values the output region of the program should hold
+    pub fn expected_values(&self) -> &[usize] {
+        &self.expected_outputs
+    }
+
+    /// Number of witnesses at which best effort adaptive proofs stop
+    ///
+    /// This is the number of witnesses expected to be found with probability
+    /// `eta0`, but never fewer than the agreed `v`, nor than a single witness.
+    pub fn adaptive_threshold(&self, eta0: f64) -> usize {
+        let n = self.hash_algo.digest_bits();
+        let v_min = compute_v_min(eta0, self.kappa, n, self.input_domain.len());
+
+        v_min.max(self.v).max(1)
+    }
+
+    /// Hash the source of the program with the hash function of the proof
+    pub fn hash_program(&self) -> Result<Vec<u8>, Report> {
+        let source = fs::read(&self.program_file)
+            .wrap_err_with(|| format!("Could not read program {}", self.program_file))?;
+        let mut hasher = Hasher::new(self.hash_algo);
+        hasher.update(&source);
+
+        Ok(hasher.finalize())
+    }
+
+    /// Check that the params describe an achievable claim
+    pub fn validate(&self) -> Result<(), Report> {
+        let digest_bits = self.hash_algo.digest_bits();
+
+        if self.kappa >= digest_bits {
+            return Err(eyre!(
+                "Kappa should be lower than the digest size ({}), got {}",
+                digest_bits,
+                self.kappa
+            ));
+        } else if self.hash_scheme != HASH_SCHEME_VERSION {
+            return Err(eyre!(
+                "Unsupported hash scheme version {}, expected {}",
+                self.hash_scheme,
+                HASH_SCHEME_VERSION
+            ));
+        } else if self.input_domain.is_empty() {
+            return Err(eyre!("Empty input domain {:?}", self.input_domain));
+        } else if self.expected_outputs.is_empty() {
+            return Err(eyre!("At least one expected output is required"));
+        } else if self.v > self.input_domain.len() {
+            return Err(eyre!(
+                "Cannot require {} witnesses from a domain of {} inputs",
+                self.v,
+                self.input_domain.len()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Write a domain the way the claim was stated, e.g. `1..=1000` for inclusive claims
+    fn format_domain(&self, domain: &Range<usize>) -> String {
+        match self.inclusive_domain && !domain.is_empty() {
+            true => format!("{:?}", domain.start..=domain.end - 1),
+            false => format!("{:?}", domain),
+        }
+    }
+
+    /// Start building params
+    pub fn builder() -> ProofParamsBuilder {
+        ProofParamsBuilder::default()
+    }
+}
+
+/// Builder for the proof parameters
+#[derive(Debug, Clone, Default)]
+pub struct ProofParamsBuilder {
+    /// The program used for the proof
+    program_file: Option<String>,
+    /// The testing domain of the claim
+    input_domain: Option<Range<usize>>,
+    /// The testing domain of the claim, when given as an inclusive range
+    inclusive_domain: Option<RangeInclusive<usize>>,
+    /// The expected values of the output region
+    expected_outputs: Option<Vec<usize>>,
+    /// The agreed upon hash max value
+    kappa: Option<u64>,
+    /// The agreed upon number of witnesses
+    v: usize,
+    /// The proof strategy
+    strategy: Option<ProofStrategy>,
+    /// The hash function used to hash the VM states
+    hash_algo: HashAlgo,
+    /// Seed of the randomized choices of the prover
+    seed: Option<u64>,
+    /// Maximal number of steps of a run
+    max_steps: Option<usize>,
+    /// Byte order of the words of the hashed states
+    endianness: Endianness,
+    /// Where the result of the runs is read from
+    result_source: ResultSource,
+    /// Whether the results are signed words
+    signed_output: bool,
+}
+
+impl ProofParamsBuilder {
+    /// Set the program used for the proof
+    pub fn program(mut self, filename: &str) -> Self {
+        self.program_file = Some(String::from(filename));
+        self
+    }
+
+    /// Set the testing domain of the claim
+    pub fn domain(mut self, input_domain: Range<usize>) -> Self {
+        self.input_domain = Some(input_domain);
+        self.inclusive_domain = None;
+        self
+    }
+
+    /// Set the testing domain of the claim as an inclusive range, e.g. `1..=1000`
+    ///
+    /// The domain is stored as the equivalent exclusive range, but reports keep
+    /// showing it the way it was claimed.
+    pub fn domain_inclusive(mut self, input_domain: RangeInclusive<usize>) -> Self {
+        self.inclusive_domain = Some(input_domain);
+        self.input_domain = None;
+        self
+    }
+
+    /// Set the expected output of the program, 0 by default
+    pub fn expected_output(mut self, output: usize) -> Self {
+        self.expected_outputs = Some(vec![output]);
+        self
+    }
+
+    /// Set a negative or positive expected output, comparing the results as signed words
+    pub fn expected_signed_output(mut self, output: i64) -> Self {
+        self.expected_outputs = Some(vec![output as usize]);
+        self.signed_output = true;
+        self
+    }
+
+    /// Compare the results as signed words, as unsigned ones by default
+    pub const fn signed_output(mut self) -> Self {
+        self.signed_output = true;
+        self
+    }
+
+    /// Set the expected values of the output region of a program with several outputs
+    pub fn expected_outputs(mut self, outputs: &[usize]) -> Self {
+        self.expected_outputs = Some(outputs.to_vec());
+        self
+    }
+
+    /// Set the agreed upon hash max value
+    pub const fn kappa(mut self, kappa: u64) -> Self {
+        self.kappa = Some(kappa);
+        self
+    }
+
+    /// Set the agreed upon number of witnesses, 0 by default
+    pub const fn v(mut self, v: usize) -> Self {
+        self.v = v;
+        self
+    }
+
+    /// Set the proof strategy, best effort by default
+    pub const fn strategy(mut self, strategy: ProofStrategy) -> Self {
+        self.strategy = Some(strategy);
+        self
+    }
+
+    /// Set the hash function used to hash the VM states
+    pub const fn hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+        self.hash_algo = hash_algo;
+        self
+    }
+
+    /// Seed the randomized choices of the prover
+    pub const fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Abort the runs exceeding the given number of steps, unlimited by default
+    pub const fn step_limit(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Set the byte order of the words of the hashed states, big-endian by default
+    pub const fn endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Set where the result of the runs is read from, the memory by default
+    pub const fn result_source(mut self, result_source: ResultSource) -> Self {
+        self.result_source = result_source;
+        self
+    }
+
+    /// Build the params, checking that they are consistent
+    pub fn build(self) -> Result<ProofParams, Report> {
+        let program_file = self.program_file.ok_or_else(|| eyre!("Missing program"))?;
+        let (input_domain, inclusive_domain) = match (self.input_domain, self.inclusive_domain) {
+            (_, Some(domain)) => {
+                let end = domain
+                    .end()
+                    .checked_add(1)
+                    .ok_or_else(|| eyre!("Input domain {:?} is too large", domain))?;
+                (*domain.start()..end, true)
+            }
+            (Some(domain), None) => (domain, false),
+            (None, None) => return Err(eyre!("Missing input domain")),
+        };
+        let kappa = self.kappa.ok_or_else(|| eyre!("Missing kappa"))?;
+
+        let params = ProofParams {
+            program_file,
+            input_domain,
+            inclusive_domain,
+            expected_outputs: self.expected_outputs.unwrap_or_else(|| vec![0]),
+            kappa,
+            v: self.v,
+            strategy: self.strategy.unwrap_or(ProofStrategy::BestEffort),
+            hash_algo: self.hash_algo,
+            seed: self.seed,
+            hash_scheme: HASH_SCHEME_VERSION,
+            program_hash: vec![],
+            max_steps: self.max_steps,
+            endianness: self.endianness,
+            result_source: self.result_source,
+            signed_output: self.signed_output,
+        };
+        params.validate()?;
+
+        Ok(params)
+    }
+}
+
+/// Output and hash of a witness run, as recorded by the prover
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedRun {
+    /// Program output
+    pub output: usize,
+    /// Values of the output region, if the program has several outputs
+    #[serde(default)]
+    pub outputs: Vec<usize>,
+    /// Hash of the program run
+    pub hash: Vec<u8>,
+}
+
+/// Runs of the witnesses, letting the verifier skip their execution
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WitnessCache {
+    /// Recorded runs by input
+    runs: BTreeMap<usize, CachedRun>,
+}
+
+impl CachedRun {
+    /// Return the recorded values of the output region
+    pub fn values(&self) -> Vec<usize> {
+        match self.outputs.is_empty() {
+            true => vec![self.output],
+            false => self.outputs.clone(),
         }
     }
 }
 
+impl WitnessCache {
+    /// Record the run of a witness
+    pub fn insert(&mut self, input: usize, outputs: &[usize], hash: Vec<u8>) {
+        let run = CachedRun {
+            output: outputs.first().copied().unwrap_or_default(),
+            outputs: outputs.to_vec(),
+            hash,
+        };
+        self.runs.insert(input, run);
+    }
+
+    /// Return the recorded run of a witness, if any
+    pub fn get(&self, input: usize) -> Option<&CachedRun> {
+        self.runs.get(&input)
+    }
+
+    /// Return the number of recorded runs
+    pub fn len(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Check if no run is recorded
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Record the runs of another cache
+    fn extend(&mut self, other: Self) {
+        self.runs.extend(other.runs);
+    }
+}
+
 /// Struct representing the proof
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Proof {
+    /// Version of the layout of the proof, `PROOF_VERSION` once loaded
+    pub version: u32,
     /// Witness set
     pub vset: Vec<usize>,
     /// Extended domain (for overting strategy)
     pub extended_domain: Option<Range<usize>>,
     /// Parameters of the proof
     pub params: ProofParams,
+    /// Runs of the witnesses, only attached on request as it inflates the proof
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<WitnessCache>,
+}
+
+/// Fields of the proof in the current layout
+#[derive(Deserialize)]
+struct ProofLayout {
+    /// Witness set
+    vset: Vec<usize>,
+    /// Extended domain (for overting strategy)
+    extended_domain: Option<Range<usize>>,
+    /// Parameters of the proof
+    params: ProofParams,
+    /// Runs of the witnesses
+    #[serde(default)]
+    cache: Option<WitnessCache>,
+}
+
+impl<'de> Deserialize<'de> for Proof {
+    /// Deserialize a proof, migrating the older layouts to the current one
+    ///
+    /// Proofs without a version are from the first layout, where the fixed
+    /// effort strategy did not carry its risk level. Up to the second layout,
+    /// the expected output was stored apart from the expected output region.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut value = Value::deserialize(deserializer)?;
+
+        let version = match value.get("version") {
+            Some(version) => version
+                .as_u64()
+                .and_then(|version| u32::try_from(version).ok())
+                .ok_or_else(|| D::Error::custom(format!("Invalid proof version {}", version)))?,
+            _ => 1,
+        };
+
+        if version > PROOF_VERSION {
+            return Err(D::Error::custom(format!(
+                "Unsupported proof version {}, expected at most {}",
+                version, PROOF_VERSION
+            )));
+        }
+
+        if version < 2 {
+            let strategy = value.pointer_mut("/params/strategy");
+            if let Some(strategy) = strategy.filter(|s| *s == "FixedEffort") {
+                *strategy = serde_json::json!({ "FixedEffort": LEGACY_FIXED_EFFORT_EPSILON });
+            }
+        }
+
+        if version < 3 {
+            // The single expected output was kept apart from the output region
+            if let Some(params) = value.get_mut("params").and_then(Value::as_object_mut) {
+                let output = params.remove("expected_output").unwrap_or_else(|| 0.into());
+                let outputs = params.get("expected_outputs").and_then(Value::as_array);
+                if outputs.is_none_or(|outputs| outputs.is_empty()) {
+                    params.insert(
+                        String::from("expected_outputs"),
+                        serde_json::json!([output]),
+                    );
+                }
+            }
+        }
+
+        let layout = ProofLayout::deserialize(value).map_err(D::Error::custom)?;
+
+        Ok(Self {
+            version: PROOF_VERSION,
+            vset: layout.vset,
+            extended_domain: layout.extended_domain,
+            params: layout.params,
+            cache: layout.cache,
+        })
+    }
+}
+
+/// On-disk form of the proof in the binary format
+///
+/// The witness set comes last so that it can be streamed after the header.
+#[derive(Serialize, Deserialize)]
+struct BinaryProof {
+    /// Everything but the witness set
+    header: BinaryHeader,
+    /// Witness set, delta and LEB128 encoded
+    vset: Vec<u8>,
+}
+
+/// Head of a binary proof, read before its witness set
+#[derive(Serialize, Deserialize)]
+struct BinaryHeader {
+    /// Parameters of the proof
+    params: ProofParams,
+    /// Extended domain (for overting strategy)
+    extended_domain: Option<Range<usize>>,
+    /// Runs of the witnesses
+    cache: Option<WitnessCache>,
+    /// Number of witnesses in the witness set
+    witnesses: usize,
+}
+
+/// Check the magic bytes and the layout version of a binary proof
+fn read_binary_version<R: Read>(reader: &mut R) -> Result<(), Report> {
+    let mut header = [0; 6];
+    reader
+        .read_exact(&mut header)
+        .wrap_err("Truncated binary proof header")?;
+
+    if &header[..4] != BINARY_MAGIC {
+        return Err(eyre!("Not a binary proof file"));
+    }
+
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != BINARY_VERSION {
+        return Err(eyre!("Unsupported binary proof version: {}", version));
+    }
+
+    Ok(())
+}
+
+/// Witnesses of a binary proof, decoded one at a time from a reader
+pub(crate) struct WitnessStream<R: Read> {
+    /// Bytes of the encoded witness set
+    bytes: Bytes<Take<R>>,
+    /// Last decoded witness
+    previous: usize,
+    /// Number of witnesses left to decode
+    remaining: usize,
+}
+
+impl<R: Read> Iterator for WitnessStream<R> {
+    type Item = Result<usize, Report>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let delta = match read_leb128(&mut self.bytes) {
+            Ok(Some(delta)) => delta,
+            Ok(None) => return Some(Err(eyre!("Truncated witness set encoding"))),
+            Err(e) => return Some(Err(e)),
+        };
+        self.previous = self.previous.wrapping_add(delta);
+
+        Some(Ok(self.previous))
+    }
+}
+
+/// Encode the witness set as its first value followed by the successive gaps, in LEB128
+fn encode_vset(vset: &[usize]) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut previous = 0usize;
+
+    for &value in vset {
+        let mut delta = value.wrapping_sub(previous);
+        previous = value;
+
+        loop {
+            let byte = (delta & 0x7f) as u8;
+            delta >>= 7;
+            if delta == 0 {
+                bytes.push(byte);
+                break;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    bytes
+}
+
+/// Decode a witness set encoded by `encode_vset`
+fn decode_vset(bytes: &[u8]) -> Result<Vec<usize>, Report> {
+    let mut bytes = bytes.iter().map(|&byte| Ok(byte));
+    let mut vset = vec![];
+    let mut previous = 0usize;
+
+    while let Some(delta) = read_leb128(&mut bytes)? {
+        previous = previous.wrapping_add(delta);
+        vset.push(previous);
+    }
+
+    Ok(vset)
+}
+
+/// Read the next LEB128 value, or `None` if there are no bytes left
+fn read_leb128<I>(bytes: &mut I) -> Result<Option<usize>, Report>
+where
+    I: Iterator<Item = io::Result<u8>>,
+{
+    let mut value = 0usize;
+    let mut shift = 0;
+
+    for byte in bytes {
+        let byte = byte.wrap_err("Truncated witness set encoding")?;
+        if shift >= usize::BITS {
+            return Err(eyre!("Invalid witness set encoding"));
+        }
+
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+
+    match shift {
+        0 => Ok(None),
+        _ => Err(eyre!("Truncated witness set encoding")),
+    }
+}
+
+impl Proof {
+    /// Save the proof as json
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Report> {
+        let file = File::create(path)?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+
+        Ok(())
+    }
+
+    /// Load a proof saved as json
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Report> {
+        let file = File::open(path)?;
+        let proof = serde_json::from_reader(BufReader::new(file))?;
+
+        Ok(proof)
+    }
+
+    /// Save the proof in the compact binary format
+    pub fn save_binary<P: AsRef<Path>>(&self, path: P) -> Result<(), Report> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writer.write_all(BINARY_MAGIC)?;
+        writer.write_all(&BINARY_VERSION.to_le_bytes())?;
+        let binary = BinaryProof {
+            header: BinaryHeader {
+                params: self.params.clone(),
+                extended_domain: self.extended_domain.clone(),
+                cache: self.cache.clone(),
+                witnesses: self.vset.len(),
+            },
+            vset: encode_vset(&self.vset),
+        };
+        bincode::serialize_into(&mut writer, &binary)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Load a proof saved in the compact binary format
+    pub fn load_binary<P: AsRef<Path>>(path: P) -> Result<Self, Report> {
+        let mut reader = BufReader::new(File::open(path)?);
+        read_binary_version(&mut reader)?;
+
+        let binary: BinaryProof =
+            bincode::deserialize_from(reader).wrap_err("Invalid or truncated binary proof")?;
+        let vset = decode_vset(&binary.vset)?;
+        if vset.len() != binary.header.witnesses {
+            return Err(eyre!("Invalid or truncated binary proof"));
+        }
+
+        Ok(Self {
+            vset,
+            ..Self::from_header(binary.header)
+        })
+    }
+
+    /// Read the header of a binary proof, leaving its witnesses to be decoded one at a time
+    ///
+    /// The proof is returned with an empty witness set, along with the number
+    /// of witnesses the stream holds.
+    pub(crate) fn stream_binary<R: BufRead>(
+        mut reader: R,
+    ) -> Result<(Self, usize, WitnessStream<R>), Report> {
+        read_binary_version(&mut reader)?;
+
+        let header: BinaryHeader =
+            bincode::deserialize_from(&mut reader).wrap_err("Invalid or truncated binary proof")?;
+        let len: u64 =
+            bincode::deserialize_from(&mut reader).wrap_err("Invalid or truncated binary proof")?;
+
+        let witnesses = header.witnesses;
+        let stream = WitnessStream {
+            bytes: reader.take(len).bytes(),
+            previous: 0,
+            remaining: witnesses,
+        };
+
+        Ok((Self::from_header(header), witnesses, stream))
+    }
+
+    /// Build a proof without witnesses from the header of a binary proof
+    fn from_header(header: BinaryHeader) -> Self {
+        Self {
+            version: PROOF_VERSION,
+            vset: vec![],
+            extended_domain: header.extended_domain,
+            params: header.params,
+            cache: header.cache,
+        }
+    }
+
+    /// Combine two proofs of the same claim over adjacent or overlapping domains
+    ///
+    /// The merged proof covers the union of the domains and requires the
+    /// witnesses of both proofs, scaled down by the share of the domains that
+    /// overlap. Over-testing proofs cannot be merged as their extended domains
+    /// would overlap the other claim.
+    pub fn merge(self, other: Self) -> Result<Self, Report> {
+        let (a, b) = (&self.params, &other.params);
+
+        if a.program_file != b.program_file || a.program_hash != b.program_hash {
+            return Err(eyre!("Cannot merge proofs of different programs"));
+        } else if self.extended_domain.is_some() || other.extended_domain.is_some() {
+            return Err(eyre!("Cannot merge over-testing proofs"));
+        } else if a.input_domain.start > b.input_domain.end
+            || b.input_domain.start > a.input_domain.end
+        {
+            return Err(eyre!(
+                "Cannot merge proofs over {:?} and {:?}, the domains are not adjacent",
+                a.input_domain,
+                b.input_domain
+            ));
+        }
+
+        // Only the domain, the way it was written, and the number of witnesses may differ
+        let claim = |params: &ProofParams| ProofParams {
+            input_domain: 0..0,
+            inclusive_domain: false,
+            v: 0,
+            ..params.clone()
+        };
+        if claim(a) != claim(b) {
+            return Err(eyre!("Cannot merge proofs with different params"));
+        }
+
+        let input_domain = a.input_domain.start.min(b.input_domain.start)
+            ..a.input_domain.end.max(b.input_domain.end);
+        // Adjacent domains keep the sum of the witnesses, overlapping ones only count the union
+        let covered = a.input_domain.len() as u128 + b.input_domain.len() as u128;
+        let v = ((a.v + b.v) as u128 * input_domain.len() as u128).div_ceil(covered.max(1));
+        let params = ProofParams {
+            inclusive_domain: a.inclusive_domain && b.inclusive_domain,
+            input_domain,
+            v: v as usize,
+            ..a.clone()
+        };
+        params.validate()?;
+
+        let mut vset = self.vset;
+        vset.extend(other.vset);
+        vset.sort_unstable();
+        vset.dedup();
+
+        let cache = match (self.cache, other.cache) {
+            (Some(mut cache), Some(other)) => {
+                cache.extend(other);
+                Some(cache)
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            version: PROOF_VERSION,
+            vset,
+            extended_domain: None,
+            params,
+            cache,
+        })
+    }
+
+    /// Load a proof, detecting whether it was saved as json or binary
+    pub fn load_any<P: AsRef<Path>>(path: P) -> Result<Self, Report> {
+        let mut magic = [0; 4];
+        let is_binary = File::open(&path)?.read_exact(&mut magic).is_ok() && &magic == BINARY_MAGIC;
+
+        if is_binary {
+            Self::load_binary(path)
+        } else {
+            Self::load(path)
+        }
+    }
+}
+
+/// Statistics of a proof report, without the witness set
+#[derive(Debug, Serialize)]
+pub struct ProofSummary {
+    /// The proof strategy
+    pub strategy: ProofStrategy,
+    /// The size of the claimed domain
+    pub domain_size: usize,
+    /// The agreed upon hash max value
+    pub kappa: u64,
+    /// The number of witnesses in the proof
+    pub witnesses: usize,
+    /// The probability of getting an acceptable proof
+    pub eta: f64,
+    /// The probability of the proof being valid
+    pub q: f64,
+    /// The conclusion of the report on whether the proof should be accepted
+    pub valid: bool,
+}
+
+/// What a proof asserts, independently of how the report presents it
+///
+/// Reads as "for all n in the domain, the program returns the expected
+/// outputs, with the given confidence".
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The program the claim is about
+    pub program: String,
+    /// The hash function used to hash the program and the VM states
+    pub hash_algo: HashAlgo,
+    /// Hex digest of the program source, empty if it was not captured
+    pub program_hash: String,
+    /// The inputs covered by the claim
+    pub domain: Range<usize>,
+    /// The values the output region of the program holds for every input
+    pub expected_outputs: Vec<usize>,
+    /// Whether the outputs are signed words
+    pub signed_output: bool,
+    /// Where the result of the runs is read from
+    pub result_source: ResultSource,
+    /// The proof strategy
+    pub strategy: ProofStrategy,
+    /// The number of witnesses in the proof
+    pub witnesses: usize,
+    /// The probability that the claim is true
+    pub confidence: f64,
+    /// Whether the proof was accepted
+    pub accepted: bool,
+}
+
+impl Manifest {
+    /// Render the manifest as markdown, to embed it in reports
+    pub fn to_markdown(&self) -> String {
+        let outputs: Vec<String> = self
+            .expected_outputs
+            .iter()
+            .map(|&output| match self.signed_output {
+                true => (output as i64).to_string(),
+                false => output.to_string(),
+            })
+            .collect();
+        let outputs = match outputs.len() {
+            1 => outputs[0].clone(),
+            _ => format!("[{}]", outputs.join(", ")),
+        };
+        let program_hash = match self.program_hash.is_empty() {
+            true => String::from("not captured"),
+            false => format!("`{}`", self.program_hash),
+        };
+
+        [
+            String::from("## Claim"),
+            String::new(),
+            format!(
+                "For all n in [{}, {}), program `{}` returns {}, with confidence {}.",
+                self.domain.start, self.domain.end, self.program, outputs, self.confidence
+            ),
+            String::new(),
+            String::from("| Field | Value |"),
+            String::from("| --- | --- |"),
+            format!("| Program | `{}` |", self.program),
+            format!("| Program hash ({:?}) | {} |", self.hash_algo, program_hash),
+            format!("| Domain | [{}, {}) |", self.domain.start, self.domain.end),
+            format!("| Expected output | {} |", outputs),
+            format!("| Result source | {:?} |", self.result_source),
+            format!("| Strategy | {:?} |", self.strategy),
+            format!("| Witnesses | {} |", self.witnesses),
+            format!("| Confidence | {} |", self.confidence),
+            format!("| Accepted | {} |", self.accepted),
+        ]
+        .join("\n")
+    }
 }
 
 /// Report of the validity of the proof
@@ -75,38 +1030,70 @@ pub struct ProofReport {
     pub q: f64,
     /// The conclusion of the report on whether the proof should be accepted
     pub valid: bool,
+    /// The outcome of the check of the witness set, if it was checked
+    pub detailed_result: Option<ValidationResult>,
+    /// Number of witnesses of the proof, which holds none if it was streamed
+    pub witnesses: usize,
 }
 
 impl ProofReport {
     /// Create a new report
-    pub fn create(proof: &Proof, eta: f64, q: f64, valid: bool) -> Self {
+    pub fn create(
+        proof: &Proof,
+        eta: f64,
+        q: f64,
+        valid: bool,
+        detailed_result: Option<ValidationResult>,
+    ) -> Self {
         Self {
             proof: proof.clone(),
             eta,
             q,
             valid,
+            detailed_result,
+            witnesses: proof.vset.len(),
         }
     }
 
     /// Print the report
     pub fn display(&self) {
-        let program = &self.proof.params.program_file;
-        let proof_strategy = format!("Proof strategy: {:?}", self.proof.params.strategy);
+        println!("{}", self.render());
+    }
+
+    /// Render the report as printed by `display`
+    pub fn render(&self) -> String {
+        let params = &self.proof.params;
+        let program = &params.program_file;
+        let proof_strategy = format!("Proof strategy: {:?}", params.strategy);
         let proof_valid = format!("Proof is accepted: *{}*", self.valid);
-        let proof_contents = format!("Witnesses: {}", self.proof.vset.len());
+        let proof_contents = format!("Witnesses: {}", self.witnesses);
         let request = format!(
-            "Request: all values in {:?}",
-            self.proof.params.input_domain
+            "Request: all values in {}",
+            params.format_domain(&params.input_domain)
         );
 
         let actual_domain = match self.proof.extended_domain {
             Some(ref extended) => extended,
-            _ => &self.proof.params.input_domain,
+            _ => &params.input_domain,
         };
 
-        let claim = format!("Claim: all values in {:?}", actual_domain);
+        let claim = format!(
+            "Claim: all values in {}",
+            params.format_domain(actual_domain)
+        );
         let proof_eta = format!("Probability to find this proof: {}", self.eta);
         let proof_q = format!("Probability that claim is true: {}", self.q);
+        let (lower, upper) = self.confidence_interval(REPORT_ALPHA);
+        let proof_interval = format!(
+            "{}% confidence interval: [{}, {}]",
+            100.0 * (1.0 - REPORT_ALPHA),
+            lower,
+            upper
+        );
+        let witnesses_check = match self.detailed_result {
+            Some(ref result) => format!("Witnesses check: {}", result),
+            _ => String::from("Witnesses check: skipped, the claim is too weak"),
+        };
 
         let report = [
             proof_strategy,
@@ -115,42 +1102,479 @@ impl ProofReport {
             proof_contents,
             proof_eta,
             proof_q,
+            proof_interval,
+            witnesses_check,
         ]
         .join("\n\t");
-        let report = format!("REPORT for {}\n\t{}\n\t{}", program, report, proof_valid);
+        format!("REPORT for {}\n\t{}\n\t{}", program, report, proof_valid)
+    }
+
+    /// Compute a `1 - alpha` confidence interval of the probability that the claim is true
+    pub fn confidence_interval(&self, alpha: f64) -> (f64, f64) {
+        let params = &self.proof.params;
+        let u = params.input_domain.end - params.input_domain.start;
 
-        println!("{}", report);
+        compute_q_interval(
+            params.kappa,
+            params.hash_algo.digest_bits(),
+            u,
+            self.witnesses,
+            alpha,
+        )
     }
 
     /// Export the report as the json
     pub fn export(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+        let mut report = serde_json::to_value(self).unwrap();
+        report["confidence_interval"] = serde_json::json!(self.confidence_interval(REPORT_ALPHA));
+
+        report.to_string()
+    }
+
+    /// Summarize the report without the witness set
+    pub fn summary(&self) -> ProofSummary {
+        let domain = match self.proof.extended_domain {
+            Some(ref extended) => extended,
+            _ => &self.proof.params.input_domain,
+        };
+
+        ProofSummary {
+            strategy: self.proof.params.strategy,
+            domain_size: domain.end - domain.start,
+            kappa: self.proof.params.kappa,
+            witnesses: self.witnesses,
+            eta: self.eta,
+            q: self.q,
+            valid: self.valid,
+        }
+    }
+
+    /// Export the summary of the report as json
+    pub fn export_summary(&self) -> String {
+        serde_json::to_string(&self.summary()).unwrap()
+    }
+
+    /// State what the proof asserts, without the way it was checked
+    pub fn manifest(&self) -> Manifest {
+        let params = &self.proof.params;
+        let domain = match self.proof.extended_domain {
+            Some(ref extended) => extended,
+            _ => &params.input_domain,
+        };
+        let program_hash = params
+            .program_hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        Manifest {
+            program: params.program_file.clone(),
+            hash_algo: params.hash_algo,
+            program_hash,
+            domain: domain.clone(),
+            expected_outputs: params.expected_values().to_vec(),
+            signed_output: params.signed_output,
+            result_source: params.result_source,
+            strategy: params.strategy,
+            witnesses: self.witnesses,
+            confidence: self.q,
+            accepted: self.valid,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::stats::compute_q;
+
+    use std::{env, fs};
+
+    fn fake_proof() -> Proof {
+        Proof {
+            version: PROOF_VERSION,
+            vset: vec![3, 5, 8, 13, 21, 34],
+            params: ProofParams {
+                program_file: String::from("none.txt"),
+                input_domain: 1..42,
+                inclusive_domain: false,
+                expected_outputs: vec![33],
+                kappa: 12,
+                v: 3,
+                strategy: ProofStrategy::OverTesting(0.9),
+                hash_algo: HashAlgo::Sha256,
+                seed: None,
+                hash_scheme: HASH_SCHEME_VERSION,
+                program_hash: vec![],
+                max_steps: None,
+                endianness: Endianness::Big,
+                result_source: ResultSource::Memory,
+                signed_output: false,
+            },
+            extended_domain: Some(1..69),
+            cache: None,
+        }
+    }
+
+    #[test]
+    fn parse_strategy() -> Result<(), Report> {
+        assert!(matches!(
+            "fixed-effort:0.9".parse()?,
+            ProofStrategy::FixedEffort(e) if e == 0.9
+        ));
+        assert!(matches!("best-effort".parse()?, ProofStrategy::BestEffort));
+        assert!(matches!(
+            "retesting-salt:42".parse()?,
+            ProofStrategy::ReTestingSalt(42)
+        ));
+
+        let error = |s: &str| s.parse::<ProofStrategy>().unwrap_err().to_string();
+        assert_eq!(
+            error("over-testing"),
+            "Strategy 'over-testing' requires a value"
+        );
+        assert_eq!(error("fast"), "Unknown proof strategy 'fast'");
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_params() -> Result<(), Report> {
+        let params = ProofParams::builder()
+            .program("../assets/collatz_v0.tr")
+            .domain(1..100)
+            .kappa(12)
+            .v(3)
+            .strategy(ProofStrategy::FixedEffort(0.9))
+            .hash_algo(HashAlgo::Sha256)
+            .build()?;
+
+        assert_eq!(params.program_file, "../assets/collatz_v0.tr");
+        assert_eq!(params.input_domain, 1..100);
+        assert_eq!(params.expected_values(), [0]);
+        assert_eq!(params.kappa, 12);
+        assert_eq!(params.v, 3);
+        assert!(matches!(params.strategy, ProofStrategy::FixedEffort(_)));
+        assert!(matches!(params.hash_algo, HashAlgo::Sha256));
+        assert!(!params.inclusive_domain);
+
+        // Inclusive domains are stored as the equivalent exclusive ones
+        let params = ProofParams::builder()
+            .program("../assets/collatz_v0.tr")
+            .domain_inclusive(1..=99)
+            .kappa(12)
+            .build()?;
+        assert_eq!(params.input_domain, 1..100);
+        assert!(params.inclusive_domain);
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_params_rejects_invalid_values() {
+        let builder = ProofParams::builder()
+            .program("../assets/collatz_v0.tr")
+            .domain(1..100)
+            .kappa(12);
+        let error = |builder: ProofParamsBuilder| builder.build().unwrap_err().to_string();
+
+        assert_eq!(
+            error(builder.clone().kappa(160)),
+            "Kappa should be lower than the digest size (160), got 160"
+        );
+        assert_eq!(
+            error(builder.clone().domain(5..5)),
+            "Empty input domain 5..5"
+        );
+        assert_eq!(
+            error(builder.clone().v(100)),
+            "Cannot require 100 witnesses from a domain of 99 inputs"
+        );
+        assert_eq!(
+            error(ProofParams::builder().domain(1..100).kappa(12)),
+            "Missing program"
+        );
+        assert_eq!(
+            error(ProofParams::builder().program("none.txt").kappa(12)),
+            "Missing input domain"
+        );
+        assert_eq!(
+            error(ProofParams::builder().program("none.txt").domain(1..100)),
+            "Missing kappa"
+        );
+        assert_eq!(
+            error(builder.clone().domain_inclusive(1..=usize::MAX)),
+            format!("Input domain 1..={} is too large", usize::MAX)
+        );
+
+        // A larger digest allows a larger kappa
+        assert!(builder
+            .kappa(200)
+            .hash_algo(HashAlgo::Sha256)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn validate_params() {
+        let params = fake_proof().params;
+        assert!(params.validate().is_ok());
+
+        let error = |params: ProofParams| params.validate().unwrap_err().to_string();
+
+        let mut invalid = params.clone();
+        invalid.kappa = 256;
+        assert_eq!(
+            error(invalid),
+            "Kappa should be lower than the digest size (256), got 256"
+        );
+
+        let mut invalid = params.clone();
+        invalid.hash_scheme = 1;
+        assert_eq!(
+            error(invalid),
+            "Unsupported hash scheme version 1, expected 2"
+        );
+
+        let mut invalid = params.clone();
+        invalid.input_domain = 42..42;
+        assert_eq!(error(invalid), "Empty input domain 42..42");
+
+        let mut invalid = params;
+        invalid.v = 42;
+        assert_eq!(
+            error(invalid),
+            "Cannot require 42 witnesses from a domain of 41 inputs"
+        );
+    }
+
     #[test]
     fn report_display() {
         let fake_proof = ProofReport {
             proof: Proof {
+                version: PROOF_VERSION,
                 vset: vec![],
                 params: ProofParams {
                     program_file: String::from("none.txt"),
                     input_domain: 42..69,
-                    expected_output: 33,
+                    inclusive_domain: false,
+                    expected_outputs: vec![33],
                     kappa: 12,
                     v: 3,
                     strategy: ProofStrategy::BestEffortAdaptive(0.99),
+                    hash_algo: HashAlgo::Sha1,
+                    seed: None,
+                    hash_scheme: HASH_SCHEME_VERSION,
+                    program_hash: vec![],
+                    max_steps: None,
+                    endianness: Endianness::Big,
+                    result_source: ResultSource::Memory,
+                    signed_output: false,
                 },
                 extended_domain: None,
+                cache: None,
             },
             eta: 0.4,
             q: 0.6,
             valid: false,
+            detailed_result: Some(ValidationResult::IncorrectHash),
+            witnesses: 0,
         };
 
         fake_proof.display();
     }
+
+    #[test]
+    fn report_confidence_interval() {
+        let report = |v: usize| {
+            let mut proof = fake_proof();
+            proof.params.input_domain = 0..1000;
+            proof.params.kappa = 250;
+            proof.vset = (0..v).collect();
+
+            let q = compute_q(proof.params.kappa, 256, 1000, v);
+            ProofReport::create(&proof, 0.5, q, true, None)
+        };
+
+        let mut previous_width = 1.0;
+        for v in [24, 32, 48, 64] {
+            let report = report(v);
+            let (lower, upper) = report.confidence_interval(0.05);
+            println!(
+                "v = {}, q = {}, interval = [{}, {}]",
+                v, report.q, lower, upper
+            );
+
+            assert!(lower <= report.q && report.q <= upper);
+            assert!(upper - lower < previous_width);
+            previous_width = upper - lower;
+        }
+
+        assert!(report(8).export().contains("confidence_interval"));
+    }
+
+    #[test]
+    fn report_summary() {
+        let fake_proof = ProofReport {
+            proof: Proof {
+                version: PROOF_VERSION,
+                vset: (42..69).collect(),
+                params: ProofParams {
+                    program_file: String::from("none.txt"),
+                    input_domain: 42..69,
+                    inclusive_domain: false,
+                    expected_outputs: vec![33],
+                    kappa: 12,
+                    v: 3,
+                    strategy: ProofStrategy::BestEffort,
+                    hash_algo: HashAlgo::Sha1,
+                    seed: None,
+                    hash_scheme: HASH_SCHEME_VERSION,
+                    program_hash: vec![],
+                    max_steps: None,
+                    endianness: Endianness::Big,
+                    result_source: ResultSource::Memory,
+                    signed_output: false,
+                },
+                extended_domain: None,
+                cache: None,
+            },
+            eta: 0.4,
+            q: 0.6,
+            valid: true,
+            detailed_result: Some(ValidationResult::Valid),
+            witnesses: 27,
+        };
+
+        let summary = fake_proof.summary();
+        assert_eq!(summary.domain_size, 27);
+        assert_eq!(summary.kappa, fake_proof.proof.params.kappa);
+        assert_eq!(summary.witnesses, fake_proof.proof.vset.len());
+        assert_eq!(summary.eta, fake_proof.eta);
+        assert_eq!(summary.q, fake_proof.q);
+        assert_eq!(summary.valid, fake_proof.valid);
+
+        let json = fake_proof.export_summary();
+        println!("Summary = {}", json);
+
+        assert!(!json.contains("vset"));
+        assert!(json.len() < fake_proof.export().len());
+    }
+
+    #[test]
+    fn binary_and_json_round_trips() -> Result<(), Report> {
+        let proof = fake_proof();
+        let json_path = env::temp_dir().join("ckc_round_trip_proof.json");
+        let binary_path = env::temp_dir().join("ckc_round_trip_proof.bin");
+
+        proof.save(&json_path)?;
+        proof.save_binary(&binary_path)?;
+
+        let from_json = Proof::load_any(&json_path)?;
+        let from_binary = Proof::load_any(&binary_path)?;
+
+        let expected = serde_json::to_vec(&proof)?;
+        assert_eq!(serde_json::to_vec(&from_json)?, expected);
+        assert_eq!(serde_json::to_vec(&from_binary)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_versions() -> Result<(), Report> {
+        let proof = fake_proof();
+        let mut json: Value = serde_json::to_value(&proof)?;
+        assert_eq!(json["version"], PROOF_VERSION);
+
+        let loaded: Proof = serde_json::from_value(json.clone())?;
+        assert_eq!(serde_json::to_value(&loaded)?, json);
+
+        // Newer layouts are refused
+        json["version"] = (PROOF_VERSION + 1).into();
+        let error = serde_json::from_value::<Proof>(json.clone()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Unsupported proof version {}, expected at most {}",
+                PROOF_VERSION + 1,
+                PROOF_VERSION
+            )
+        );
+
+        // The second layout kept the single expected output apart
+        let mut legacy = json.clone();
+        legacy["version"] = 2.into();
+        legacy["params"]["expected_output"] = 42.into();
+        legacy["params"]["expected_outputs"] = serde_json::json!([]);
+        let migrated: Proof = serde_json::from_value(legacy.clone())?;
+        assert_eq!(migrated.params.expected_values(), [42]);
+
+        legacy["params"]["expected_outputs"] = serde_json::json!([42, 7]);
+        let migrated: Proof = serde_json::from_value(legacy)?;
+        assert_eq!(migrated.params.expected_values(), [42, 7]);
+
+        // The first layout had no version and no risk level for fixed effort
+        json.as_object_mut().unwrap().remove("version");
+        json["params"]["strategy"] = "FixedEffort".into();
+        let migrated: Proof = serde_json::from_value(json)?;
+        assert_eq!(migrated.version, PROOF_VERSION);
+        assert_eq!(migrated.vset, proof.vset);
+        assert!(matches!(
+            migrated.params.strategy,
+            ProofStrategy::FixedEffort(e) if e == LEGACY_FIXED_EFFORT_EPSILON
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn binary_rejects_truncated_file() -> Result<(), Report> {
+        let path = env::temp_dir().join("ckc_truncated_proof.bin");
+        fake_proof().save_binary(&path)?;
+
+        let bytes = fs::read(&path)?;
+        fs::write(&path, &bytes[..bytes.len() - 4])?;
+
+        let error = Proof::load_binary(&path).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid or truncated binary proof");
+
+        fs::write(&path, &bytes[..3])?;
+
+        let error = Proof::load_binary(&path).unwrap_err();
+        assert_eq!(error.to_string(), "Truncated binary proof header");
+
+        Ok(())
+    }
+
+    #[test]
+    fn vset_encoding_round_trips() -> Result<(), Report> {
+        // Xorshift generator for reproducible random witness sets
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+
+        for _ in 0..100 {
+            let len = next() % 1000;
+            let mut vset: Vec<usize> = (0..len).map(|_| next() % 1_000_000).collect();
+            vset.sort_unstable();
+            vset.dedup();
+
+            assert_eq!(decode_vset(&encode_vset(&vset))?, vset);
+        }
+
+        // Edge cases
+        for vset in [vec![], vec![0], vec![usize::MAX], vec![0, usize::MAX]] {
+            assert_eq!(decode_vset(&encode_vset(&vset))?, vset);
+        }
+
+        // Dense witness sets use a single byte per witness
+        let dense: Vec<usize> = (1000..2000).collect();
+        assert_eq!(encode_vset(&dense).len(), 1001);
+
+        Ok(())
+    }
 }
@@ -1,34 +1,115 @@
-use color_eyre::Report;
+use color_eyre::{eyre::eyre, Report};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use std::time::Instant;
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 use crate::{
-    proof::{Proof, ProofParams, ProofStrategy},
-    stats::{compute_delta_u, compute_v_min},
-    vm::{validate_hash, InstrumentedVM, RunResult},
+    hash::{HashAlgo, Hasher},
+    proof::{Proof, ProofParams, ProofStrategy, WitnessCache, PROOF_VERSION},
+    stats::{compute_delta_u, compute_extension},
+    vm::{is_run_failure, validate_hash, InstrumentedVM, RunResult},
 };
 
+/// Number of tested inputs between two progress reports
+pub const PROGRESS_INTERVAL: usize = 1024;
+
+/// Number of tested inputs between two checkpoints
+const CHECKPOINT_INTERVAL: usize = 65536;
+
+/// Partial sweep saved to disk by resumable provers
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Hash of the params of the proof being obtained
+    params_hash: Vec<u8>,
+    /// Number of inputs already tested
+    tested: usize,
+    /// Witnesses found so far
+    witnesses: Vec<RunResult>,
+}
+
 /// Prover
 pub struct Prover {
     /// Params used for the validation of the proof
     params: ProofParams,
+    /// Generator for the randomized choices, seeded from the params
+    rng: Option<StdRng>,
+    /// Whether the runs of the witnesses are attached to the proof
+    cache_witnesses: bool,
+    /// File where the progress of the sweep is saved, if any
+    checkpoint: Option<PathBuf>,
+    /// Maximal number of inputs tested beyond the claim when over-testing, if it may grow
+    extension_cap: Option<usize>,
 }
 
 impl Prover {
-    /// Create the Prover
-    pub fn new(params: ProofParams) -> Self {
-        assert!(params.kappa < 160);
-        Self { params }
+    /// Create the Prover, checking that the params are valid
+    pub fn new(mut params: ProofParams) -> Result<Self, Report> {
+        params.validate()?;
+        params.program_hash = params.hash_program()?;
+        let rng = params.seed.map(StdRng::seed_from_u64);
+
+        Ok(Self {
+            params,
+            rng,
+            cache_witnesses: false,
+            checkpoint: None,
+            extension_cap: None,
+        })
+    }
+
+    /// Attach the runs of the witnesses to the proof so the verifier can skip them
+    pub const fn with_witness_cache(mut self) -> Self {
+        self.cache_witnesses = true;
+        self
+    }
+
+    /// Keep extending the domain when over-testing falls short of witnesses
+    ///
+    /// The domain never grows beyond `cap` inputs past the end of the claim.
+    pub const fn with_extension_cap(mut self, cap: usize) -> Self {
+        self.extension_cap = Some(cap);
+        self
     }
 
     /// Get a proof for the claim
     pub fn obtain_proof(self) -> Result<Proof, Report> {
+        self.obtain_proof_with_progress(None)
+    }
+
+    /// Get a proof for the claim, saving the progress to resume an interrupted sweep
+    ///
+    /// The checkpoint is written every `CHECKPOINT_INTERVAL` inputs and removed
+    /// once the proof is obtained.
+    pub fn obtain_proof_resumable<P: AsRef<Path>>(
+        mut self,
+        checkpoint_path: P,
+    ) -> Result<Proof, Report> {
+        self.checkpoint = Some(checkpoint_path.as_ref().to_path_buf());
+        self.obtain_proof()
+    }
+
+    /// Get a proof for the claim, reporting the number of inputs tested so far
+    ///
+    /// The callback receives the number of tested inputs and the size of the
+    /// domain being swept, every `PROGRESS_INTERVAL` inputs and once at the end.
+    pub fn obtain_proof_with_progress(
+        self,
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<Proof, Report> {
         let start = Instant::now();
         let result = match self.params.strategy {
-            ProofStrategy::BestEffort => self.obtain_proof_best_effort(),
-            ProofStrategy::FixedEffort(_epsilon) => self.obtain_proof_fixed_effort(),
-            ProofStrategy::OverTesting(eta0) => self.obtain_proof_overtesting(eta0),
-            ProofStrategy::BestEffortAdaptive(eta0) => self.obtain_proof_bea(eta0),
+            ProofStrategy::BestEffort => self.obtain_proof_best_effort(progress),
+            ProofStrategy::FixedEffort(_epsilon) => self.obtain_proof_fixed_effort(progress),
+            ProofStrategy::OverTesting(eta0) => self.obtain_proof_overtesting(eta0, progress),
+            ProofStrategy::BestEffortAdaptive(eta0) => self.obtain_proof_bea(eta0, progress),
+            ProofStrategy::ReTestingSalt(_salt) => self.obtain_proof_best_effort(progress),
         };
         let duration = start.elapsed();
 
@@ -38,92 +119,625 @@ impl Prover {
     }
 
     /// Proof for best effort adaptive
-    fn obtain_proof_bea(self, eta0: f64) -> Result<Proof, Report> {
-        let u = self.params.input_domain.end - self.params.input_domain.start;
-        let threshold = compute_v_min(eta0, self.params.kappa, u);
+    fn obtain_proof_bea(
+        mut self,
+        eta0: f64,
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<Proof, Report> {
+        let threshold = self.params.adaptive_threshold(eta0);
 
-        let mut vset = vec![];
-        let mut vm = InstrumentedVM::new(&self.params.program_file)?;
-
-        for i in self.params.input_domain.clone() {
-            let run_result = vm.run(i).unwrap();
-            if self.select_witness(&run_result) {
-                vset.push(i);
-            }
-            if vset.len() > threshold {
-                break;
+        // With a seed the domain is probed in a random order, otherwise lazily in order
+        let domain = self.params.input_domain.clone();
+        let witnesses = match self.rng.take() {
+            Some(mut rng) => {
+                let mut domain: Vec<_> = domain.collect();
+                domain.shuffle(&mut rng);
+                self.collect_witnesses(domain.into_iter(), Some(threshold), progress)?
             }
-        }
+            _ => self.collect_witnesses(domain, Some(threshold), progress)?,
+        };
 
-        Ok(Proof {
-            vset,
-            extended_domain: None,
-            params: self.params,
-        })
+        Ok(self.into_proof(witnesses, None))
     }
 
     /// Proof for fixed effort
-    fn obtain_proof_fixed_effort(self) -> Result<Proof, Report> {
-        self.obtain_proof_best_effort()
+    fn obtain_proof_fixed_effort(
+        self,
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<Proof, Report> {
+        self.obtain_proof_best_effort(progress)
     }
 
     /// Proof for best effort
-    fn obtain_proof_best_effort(self) -> Result<Proof, Report> {
-        let mut vset = vec![];
+    fn obtain_proof_best_effort(
+        self,
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<Proof, Report> {
         let domain = self.params.input_domain.clone();
+        let witnesses = self.collect_witnesses(domain, None, progress)?;
 
-        let mut vm = InstrumentedVM::new(&self.params.program_file)?;
+        Ok(self.into_proof(witnesses, None))
+    }
 
-        domain.for_each(|i| {
-            let run_result = vm.run(i).unwrap();
-            if self.select_witness(&run_result) {
-                vset.push(i);
-            }
-        });
+    /// Proof for overtesting
+    ///
+    /// The witnesses are collected over the whole extended domain, including the
+    /// inputs beyond the end of the claim, which the verifier accepts as well.
+    fn obtain_proof_overtesting(
+        mut self,
+        eta0: f64,
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<Proof, Report> {
+        let extended_domain = self.extended_domain(eta0);
+        let witnesses = self.collect_witnesses(extended_domain.clone(), None, progress)?;
 
-        Ok(Proof {
-            vset,
-            extended_domain: None,
-            params: self.params,
-        })
+        // Only the first sweep is checkpointed, the extensions are tested again on resume
+        self.checkpoint = None;
+
+        let (witnesses, extended_domain) =
+            self.extend_overtesting(eta0, witnesses, extended_domain, progress)?;
+
+        Ok(self.into_proof(witnesses, Some(extended_domain)))
     }
 
-    /// Proof for overtesting
-    fn obtain_proof_overtesting(self, eta0: f64) -> Result<Proof, Report> {
+    /// Keep extending the over-tested domain up to the cap while witnesses are missing
+    fn extend_overtesting(
+        &self,
+        eta0: f64,
+        mut witnesses: Vec<RunResult>,
+        mut extended_domain: Range<usize>,
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<(Vec<RunResult>, Range<usize>), Report> {
+        let v = self.params.v;
+        let n = self.params.hash_algo.digest_bits();
+        let cap = self
+            .extension_cap
+            .map(|cap| self.params.input_domain.end.saturating_add(cap));
+        while let Some(cap) = cap.filter(|&cap| witnesses.len() < v && extended_domain.end < cap) {
+            let missing = v - witnesses.len();
+            let extension = compute_extension(eta0, self.params.kappa, n, missing).max(1);
+            let end = extended_domain.end.saturating_add(extension).min(cap);
+
+            witnesses.extend(self.collect_witnesses(extended_domain.end..end, None, progress)?);
+            extended_domain.end = end;
+        }
+
+        Ok((witnesses, extended_domain))
+    }
+
+    /// Extend the domain of the claim so that enough witnesses are found with probability eta0
+    fn extended_domain(&self, eta0: f64) -> Range<usize> {
         let start = self.params.input_domain.start;
         let end = self.params.input_domain.end;
 
-        let delta = compute_delta_u(eta0, self.params.kappa, end - start, self.params.v);
-        let extended_domain = start..(end + delta);
+        let n = self.params.hash_algo.digest_bits();
+        let delta = compute_delta_u(eta0, self.params.kappa, n, end - start, self.params.v);
+
+        start..(end + delta)
+    }
+
+    /// Domain swept by the strategy of the proof
+    fn sweep_domain(&self) -> Range<usize> {
+        match self.params.strategy {
+            ProofStrategy::OverTesting(eta0) => self.extended_domain(eta0),
+            _ => self.params.input_domain.clone(),
+        }
+    }
+
+    /// Split the swept domain in consecutive chunks of at most `chunk_size` inputs
+    pub fn domain_chunks(&self, chunk_size: usize) -> Vec<Range<usize>> {
+        let domain = self.sweep_domain();
+        let chunk_size = chunk_size.max(1);
+
+        (domain.start..domain.end)
+            .step_by(chunk_size)
+            .map(|start| start..domain.end.min(start.saturating_add(chunk_size)))
+            .collect()
+    }
+
+    /// Get a proof for the claim, sweeping chunks of the domain in parallel
+    ///
+    /// Each worker owns a VM and tests a whole chunk, and the sorted witnesses
+    /// of the chunks are merged in order. The proof is the one `obtain_proof`
+    /// gives: over-testing extensions are swept in order once the chunks are
+    /// done. The inputs of the chunks are tested in their natural order, so
+    /// seeded adaptive proofs are refused, and no progress is reported.
+    pub fn obtain_proof_chunked(self, chunk_size: usize) -> Result<Proof, Report> {
+        if chunk_size == 0 {
+            return Err(eyre!("Chunk size should be positive"));
+        } else if self.checkpoint.is_some() {
+            return Err(eyre!("Chunked sweeps cannot be checkpointed"));
+        } else if self.rng.is_some()
+            && matches!(self.params.strategy, ProofStrategy::BestEffortAdaptive(_))
+        {
+            return Err(eyre!(
+                "Chunked sweeps test the inputs in order, seeded adaptive proofs are not supported"
+            ));
+        }
+
+        let start = Instant::now();
+        let vm = InstrumentedVM::from_params(&self.params)?;
+
+        let chunks: Vec<Vec<RunResult>> = self
+            .domain_chunks(chunk_size)
+            .into_par_iter()
+            .map_init(
+                || vm.clone(),
+                |vm, chunk| {
+                    chunk
+                        .filter_map(|i| self.test_input(vm, i).transpose())
+                        .collect()
+                },
+            )
+            .collect::<Result<_, Report>>()?;
+        let mut witnesses: Vec<_> = chunks.into_iter().flatten().collect();
+
+        let extended_domain = match self.params.strategy {
+            ProofStrategy::OverTesting(eta0) => {
+                let (extended, extended_domain) =
+                    self.extend_overtesting(eta0, witnesses, self.sweep_domain(), None)?;
+                witnesses = extended;
+                Some(extended_domain)
+            }
+            ProofStrategy::BestEffortAdaptive(eta0) => {
+                // The sequential prover stops at the first witnesses reaching the threshold
+                witnesses.truncate(self.params.adaptive_threshold(eta0));
+                None
+            }
+            _ => None,
+        };
+
+        println!("Prover time: {:?}", start.elapsed());
+
+        Ok(self.into_proof(witnesses, extended_domain))
+    }
+
+    /// Test the inputs in order, stopping early once `limit` witnesses are found
+    fn collect_witnesses<I>(
+        &self,
+        inputs: I,
+        limit: Option<usize>,
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<Vec<RunResult>, Report>
+    where
+        I: ExactSizeIterator<Item = usize>,
+    {
+        let size = inputs.len();
+        let (mut tested, mut witnesses) = match self.checkpoint {
+            Some(ref path) => self.load_checkpoint(path)?,
+            _ => (0, vec![]),
+        };
+
+        let mut vm = InstrumentedVM::from_params(&self.params)?;
+
+        for i in inputs.skip(tested) {
+            if let Some(run_result) = self.test_input(&mut vm, i)? {
+                witnesses.push(run_result);
+            }
+            tested += 1;
+
+            if let Some(ref path) = self.checkpoint {
+                if tested.is_multiple_of(CHECKPOINT_INTERVAL) {
+                    self.save_checkpoint(path, tested, &witnesses)?;
+                }
+            }
+
+            if limit.is_some_and(|limit| witnesses.len() >= limit) {
+                break;
+            }
+            if let Some(progress) = progress {
+                if tested.is_multiple_of(PROGRESS_INTERVAL) && tested < size {
+                    progress(tested, size);
+                }
+            }
+        }
+
+        if let Some(progress) = progress {
+            progress(tested, size);
+        }
+
+        if let Some(ref path) = self.checkpoint {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(witnesses)
+    }
+
+    /// Hash the params so that checkpoints are only resumed for the same proof
+    fn params_hash(&self) -> Result<Vec<u8>, Report> {
+        let mut hasher = Hasher::new(HashAlgo::Sha256);
+        hasher.update(&serde_json::to_vec(&self.params)?);
+
+        Ok(hasher.finalize())
+    }
+
+    /// Load the number of tested inputs and the witnesses found from a checkpoint
+    fn load_checkpoint(&self, path: &Path) -> Result<(usize, Vec<RunResult>), Report> {
+        if !path.exists() {
+            return Ok((0, vec![]));
+        }
 
-        let mut vset = vec![];
+        let checkpoint: Checkpoint = serde_json::from_slice(&fs::read(path)?)?;
+        if checkpoint.params_hash != self.params_hash()? {
+            return Err(eyre!(
+                "Checkpoint {:?} was saved for different proof params",
+                path
+            ));
+        }
 
-        let mut vm = InstrumentedVM::new(&self.params.program_file)?;
+        Ok((checkpoint.tested, checkpoint.witnesses))
+    }
 
-        extended_domain.clone().for_each(|i| {
-            let run_result = vm.run(i).unwrap();
-            if self.select_witness(&run_result) {
-                vset.push(i);
+    /// Save the number of tested inputs and the witnesses found to a checkpoint
+    fn save_checkpoint(
+        &self,
+        path: &Path,
+        tested: usize,
+        witnesses: &[RunResult],
+    ) -> Result<(), Report> {
+        let checkpoint = Checkpoint {
+            params_hash: self.params_hash()?,
+            tested,
+            witnesses: witnesses.to_vec(),
+        };
+
+        // Write then rename so that an interruption never leaves a partial checkpoint
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(&checkpoint)?)?;
+        fs::rename(partial, path)?;
+
+        Ok(())
+    }
+
+    /// Assemble the proof from the runs of the witnesses
+    fn into_proof(
+        self,
+        mut witnesses: Vec<RunResult>,
+        extended_domain: Option<Range<usize>>,
+    ) -> Proof {
+        // The witness set is canonical: sorted, each input listed once
+        witnesses.sort_unstable_by_key(|run_result| run_result.input);
+        debug_assert!(
+            witnesses
+                .windows(2)
+                .all(|pair| pair[0].input != pair[1].input),
+            "An input was selected twice as a witness"
+        );
+        witnesses.dedup_by_key(|run_result| run_result.input);
+
+        let cache = self.cache_witnesses.then(|| {
+            let mut cache = WitnessCache::default();
+            for run_result in &witnesses {
+                cache.insert(
+                    run_result.input,
+                    &run_result.outputs,
+                    run_result.hash.clone(),
+                );
             }
+            cache
         });
 
-        Ok(Proof {
-            vset,
-            extended_domain: Some(extended_domain),
+        Proof {
+            version: PROOF_VERSION,
+            vset: witnesses
+                .iter()
+                .map(|run_result| run_result.input)
+                .collect(),
+            extended_domain,
             params: self.params,
-        })
+            cache,
+        }
+    }
+
+    /// Run the program on the given input, returning the run if it is a witness
+    fn test_input(
+        &self,
+        vm: &mut InstrumentedVM,
+        input: usize,
+    ) -> Result<Option<RunResult>, Report> {
+        match vm.run(input) {
+            Ok(run_result) => Ok(self.select_witness(&run_result).then_some(run_result)),
+            // Runs failing on their input are not witnesses, whatever the failure
+            Err(e) if is_run_failure(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
     /// Picking the witness based on the program result
     fn select_witness(&self, run_result: &RunResult) -> bool {
-        if run_result.output != self.params.expected_output {
+        if run_result.outputs != self.params.expected_values() {
             return false;
         }
 
-        if !self.params.input_domain.contains(&run_result.input) {
+        // Over-testing keeps the witnesses of its extended domain, beyond the end of the claim
+        let overtesting = matches!(self.params.strategy, ProofStrategy::OverTesting(_));
+        if !overtesting && !self.params.input_domain.contains(&run_result.input) {
             return false;
         }
 
         validate_hash(&run_result.hash, self.params.kappa as usize)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ValidationResult, Verifier};
+
+    use std::env;
+
+    #[test]
+    fn resume_interrupted_sweep() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            155,
+            10,
+            ProofStrategy::BestEffort,
+        );
+        let expected = Prover::new(params.clone())?.obtain_proof()?;
+
+        // Simulate an interruption after the first 500 inputs
+        let path = env::temp_dir().join("ckc_resume_interrupted_sweep.json");
+        let prover = Prover::new(params.clone())?;
+        let witnesses = prover.collect_witnesses(1..501, None, None)?;
+        prover.save_checkpoint(&path, 500, &witnesses)?;
+
+        let proof = Prover::new(params.clone())?.obtain_proof_resumable(&path)?;
+        assert_eq!(proof.vset, expected.vset);
+        assert!(!path.exists());
+
+        // Mismatched params are refused
+        prover.save_checkpoint(&path, 500, &witnesses)?;
+        let error = Prover::new(params.with_hash_algo(HashAlgo::Sha256))?
+            .obtain_proof_resumable(&path)
+            .unwrap_err();
+        assert!(error.to_string().contains("different proof params"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_witness_sets() -> Result<(), Report> {
+        let strategies = [
+            ProofStrategy::BestEffort,
+            ProofStrategy::FixedEffort(0.9),
+            ProofStrategy::BestEffortAdaptive(0.99),
+            ProofStrategy::OverTesting(0.9),
+            ProofStrategy::ReTestingSalt(3),
+        ];
+
+        for strategy in strategies {
+            let params = ProofParams::new("../assets/collatz_v0.tr", 1..2000, 0, 155, 5, strategy);
+            let proofs = [
+                Prover::new(params.clone().with_seed(7))?.obtain_proof()?,
+                Prover::new(params)?.obtain_proof_chunked(300)?,
+            ];
+
+            for proof in proofs {
+                assert!(!proof.vset.is_empty(), "{:?}", strategy);
+                assert!(
+                    proof.vset.windows(2).all(|pair| pair[0] < pair[1]),
+                    "{:?}: {:?}",
+                    strategy,
+                    proof.vset
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_proof_matches_sequential() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..20000,
+            0,
+            152,
+            10,
+            ProofStrategy::BestEffort,
+        );
+        let expected = Prover::new(params.clone())?.obtain_proof()?;
+        println!("Witnesses = {}", expected.vset.len());
+
+        // The last chunk is shorter than the others
+        let prover = Prover::new(params.clone())?;
+        let chunks = prover.domain_chunks(3000);
+        assert_eq!(chunks.len(), 7);
+        assert_eq!(chunks[6], 18001..20000);
+
+        let proof = prover.with_witness_cache().obtain_proof_chunked(3000)?;
+        assert_eq!(proof.vset, expected.vset);
+        assert_eq!(
+            proof.cache.as_ref().map(WitnessCache::len),
+            Some(expected.vset.len())
+        );
+        assert!(Prover::new(params)?.obtain_proof_chunked(0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_proof_follows_strategy() -> Result<(), Report> {
+        let params =
+            |strategy| ProofParams::new("../assets/collatz_v0.tr", 1..300, 0, 155, 20, strategy);
+
+        // Adaptive proofs stop at the same witnesses when the inputs are tested in order
+        let adaptive = params(ProofStrategy::BestEffortAdaptive(0.5));
+        let expected = Prover::new(adaptive.clone())?.obtain_proof()?;
+        let proof = Prover::new(adaptive.clone())?.obtain_proof_chunked(50)?;
+        assert_eq!(proof.vset, expected.vset);
+
+        // Seeded adaptive proofs test the inputs in a random order
+        let seeded = Prover::new(adaptive.with_seed(7))?.obtain_proof_chunked(50);
+        assert!(seeded.is_err());
+
+        // Over-testing extends the domain as the sequential prover does
+        let overtesting = params(ProofStrategy::OverTesting(0.99));
+        let expected = Prover::new(overtesting.clone())?
+            .with_extension_cap(10000)
+            .obtain_proof()?;
+        let proof = Prover::new(overtesting)?
+            .with_extension_cap(10000)
+            .obtain_proof_chunked(50)?;
+        assert!(proof.vset.len() >= 20);
+        assert_eq!(proof.vset, expected.vset);
+        assert_eq!(proof.extended_domain, expected.extended_domain);
+
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_threshold_floor() -> Result<(), Report> {
+        // So few witnesses are expected at kappa 150 that none is required, yet one is still looked for
+        let params = |kappa, v| {
+            ProofParams::new(
+                "../assets/collatz_v0.tr",
+                1..2000,
+                0,
+                kappa,
+                v,
+                ProofStrategy::BestEffortAdaptive(0.999_999),
+            )
+        };
+        assert_eq!(params(150, 0).adaptive_threshold(0.999_999), 1);
+
+        let proof = Prover::new(params(150, 0))?.obtain_proof()?;
+        assert_eq!(proof.vset.len(), 1);
+        assert!(Verifier::new(proof.clone())?.check_proof().valid);
+
+        // An empty witness set never proves anything
+        let mut empty = proof;
+        empty.vset.clear();
+        let result = Verifier::new(empty)?.check_proof();
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::ValidButTooFewHashes(0))
+        );
+        assert!(!result.valid);
+
+        // The agreed number of witnesses is still required
+        let proof = Prover::new(params(155, 50))?.obtain_proof()?;
+        assert_eq!(proof.vset.len(), 50);
+        assert!(Verifier::new(proof)?.check_proof().valid);
+
+        Ok(())
+    }
+
+    #[test]
+    fn extend_overtesting_domain() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..300,
+            0,
+            155,
+            20,
+            ProofStrategy::OverTesting(0.99),
+        );
+
+        // A single extension falls short of witnesses
+        let single = Prover::new(params.clone())?.obtain_proof()?;
+        let first_end = single.extended_domain.as_ref().unwrap().end;
+        println!(
+            "First extension = {:?}, witnesses = {}",
+            single.extended_domain,
+            single.vset.len()
+        );
+        assert!(single.vset.len() < 20);
+
+        let proof = Prover::new(params)?
+            .with_extension_cap(10000)
+            .obtain_proof()?;
+        let extended_domain = proof.extended_domain.clone().unwrap();
+        println!(
+            "Final extension = {:?}, witnesses = {}",
+            extended_domain,
+            proof.vset.len()
+        );
+
+        assert!(proof.vset.len() >= 20);
+        assert!(extended_domain.end > first_end);
+        assert!(proof.vset.iter().all(|i| extended_domain.contains(i)));
+        assert!(Verifier::new(proof)?.check_proof().valid);
+
+        Ok(())
+    }
+
+    #[test]
+    fn overtesting_keeps_extended_witnesses() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            155,
+            20,
+            ProofStrategy::OverTesting(0.99),
+        );
+
+        // Without extension cap, the witnesses of the first extension are kept too
+        let proof = Prover::new(params)?.obtain_proof()?;
+        let extended_domain = proof.extended_domain.clone().unwrap();
+        println!(
+            "Extension = {:?}, witnesses = {:?}",
+            extended_domain, proof.vset
+        );
+
+        assert!(extended_domain.end > 1000);
+        assert!(proof.vset.iter().any(|&i| i >= 1000));
+        assert!(proof.vset.iter().all(|i| extended_domain.contains(i)));
+        assert!(Verifier::new(proof)?.check_proof().valid);
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_segfaulting_inputs() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/test_segfault.tr",
+            0..200,
+            0,
+            158,
+            1,
+            ProofStrategy::BestEffort,
+        );
+        let mut proof = Prover::new(params)?.obtain_proof()?;
+        println!("Witnesses = {:?}", proof.vset);
+
+        assert!(!proof.vset.is_empty());
+        assert!(!proof.vset.contains(&0));
+
+        // A segfaulting witness is rejected by the verifier
+        proof.vset.insert(0, 0);
+        let result = Verifier::new(proof)?.check_proof();
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::ExecutionError)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_failing_inputs() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/test_run_errors.tr",
+            0..200,
+            0,
+            158,
+            1,
+            ProofStrategy::BestEffort,
+        );
+        let proof = Prover::new(params)?.obtain_proof()?;
+        println!("Witnesses = {:?}", proof.vset);
+
+        // Out of bounds jumps and stores and error codes do not abort the proof
+        assert!(!proof.vset.is_empty());
+        assert!(proof.vset.iter().all(|&input| input > 2));
+
+        Ok(())
+    }
+}
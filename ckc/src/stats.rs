@@ -1,35 +1,122 @@
 use statrs::{
-    distribution::{DiscreteCDF, NegativeBinomial},
-    function::erf::{erfc, erfc_inv},
+    distribution::{Binomial, DiscreteCDF, NegativeBinomial},
+    function::{
+        beta::checked_beta_reg,
+        erf::{erfc, erfc_inv},
+    },
 };
 
 /// Compute the probability of getting an acceptable witness set
-pub fn compute_eta(kappa: u64, u: usize, v: usize) -> f64 {
-    let p = derive_p(kappa);
+///
+/// When the distribution degenerates (no variance), the number of witnesses is
+/// exactly `u * p`, so the result is either 0.0 or 1.0.
+pub fn compute_eta(kappa: u64, n: u64, u: usize, v: usize) -> f64 {
+    let p = derive_p(kappa, n);
     let u = u as f64;
     let v = v as f64;
     let term1 = v - u * p;
     let term2 = (2.0 * u * p * (1.0 - p)).sqrt();
 
-    0.5 * erfc(term1 / term2)
+    if term2.is_nan() || term2 <= 0.0 {
+        return if v <= u * p { 1.0 } else { 0.0 };
+    }
+
+    clamp_probability(0.5 * erfc(term1 / term2))
 }
 
 /// Compute the probability of validity of the proof
-pub fn compute_q(kappa: u64, u: usize, r: usize) -> f64 {
+///
+/// Degenerate inputs give a well-defined value in `[0, 1]` instead of NaN.
+pub fn compute_q(kappa: u64, n: u64, u: usize, r: usize) -> f64 {
     if u < 1 || r < 1 {
         return 0.0;
     }
 
-    let p = derive_p(kappa);
-    let d = (u - r + 1).try_into().unwrap();
-    let nb = NegativeBinomial::new(r as f64, p).unwrap();
+    let p = derive_p(kappa, n);
+    if p.is_nan() || p >= 1.0 {
+        return 0.0;
+    } else if p <= 0.0 || r > u + 1 {
+        return 1.0;
+    }
+
+    let d = (u + 1 - r) as u64;
+    match NegativeBinomial::new(r as f64, p) {
+        Ok(nb) => clamp_probability(1.0 - nb.cdf(d)),
+        _ => 0.0,
+    }
+}
+
+/// Compute a `1 - alpha` confidence interval of the probability of validity of the proof
+///
+/// The number of witnesses is treated as a binomial sample of the domain with
+/// the observed rate `r / u`, and `compute_q` is evaluated at the bounds of
+/// its two-sided interval.
+pub fn compute_q_interval(kappa: u64, n: u64, u: usize, r: usize, alpha: f64) -> (f64, f64) {
+    if u < 1 || alpha.is_nan() {
+        return (0.0, 1.0);
+    }
+
+    let rate = (r.min(u) as f64) / u as f64;
+    let binomial = match Binomial::new(rate, u as u64) {
+        Ok(binomial) => binomial,
+        _ => return (0.0, 1.0),
+    };
+
+    let alpha = alpha.clamp(0.0, 1.0);
+    let lower = binomial_quantile(&binomial, u, alpha / 2.0);
+    let upper = binomial_quantile(&binomial, u, 1.0 - alpha / 2.0);
+
+    (compute_q(kappa, n, u, lower), compute_q(kappa, n, u, upper))
+}
+
+/// Compute the smallest count whose cumulative probability reaches `level`
+fn binomial_quantile(binomial: &Binomial, u: usize, level: f64) -> usize {
+    let (mut low, mut high) = (0, u);
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if binomial.cdf(mid as u64) >= level {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+
+    low
+}
 
-    1.0 - nb.cdf(d)
+/// Compute the probability that the claim is false given the proof
+///
+/// This is the complement of `compute_q`, but it is obtained directly from the
+/// tail probability instead of `1 - q`, which would lose precision when `q` is
+/// close to 1.
+/// Use `compute_q` to report the confidence in a claim, and this function when
+/// the residual risk itself has to be communicated.
+///
+/// Degenerate inputs give the complement of the value `compute_q` returns for them.
+pub fn compute_failure_probability(kappa: u64, n: u64, u: usize, r: usize) -> f64 {
+    if u < 1 || r < 1 {
+        return 1.0;
+    }
+
+    let p = derive_p(kappa, n);
+    if p.is_nan() || p >= 1.0 {
+        return 1.0;
+    } else if p <= 0.0 || r > u + 1 {
+        return 0.0;
+    }
+
+    // The negative binomial CDF is the regularized incomplete beta I_p(r, d + 1)
+    let d = (u + 1 - r) as f64;
+    match checked_beta_reg(r as f64, d + 1.0, p) {
+        Ok(probability) => clamp_probability(probability),
+        _ => 1.0,
+    }
 }
 
 /// Compute the extra number of step required to attain the eta0 threshold
-pub fn compute_delta_u(eta0: f64, kappa: u64, u: usize, v: usize) -> usize {
-    let p = derive_p(kappa);
+pub fn compute_delta_u(eta0: f64, kappa: u64, n: u64, u: usize, v: usize) -> usize {
+    let p = derive_p(kappa, n);
     let alpha = erfc_inv(2.0 * eta0);
 
     ((u as f64)
@@ -42,16 +129,191 @@ pub fn compute_delta_u(eta0: f64, kappa: u64, u: usize, v: usize) -> usize {
         ) / p) as usize
 }
 
+/// Compute the number of inputs to test to find `v` more witnesses with probability eta0
+///
+/// Unlike `compute_delta_u`, this does not depend on an already tested domain,
+/// so it can be used to extend a domain that fell short of witnesses.
+pub fn compute_extension(eta0: f64, kappa: u64, n: u64, v: usize) -> usize {
+    if v == 0 {
+        return 0;
+    }
+
+    let enough = |u: usize| compute_eta(kappa, n, u, v) >= eta0;
+    let mut high = v;
+    while !enough(high) {
+        if high > usize::MAX / 2 {
+            return usize::MAX;
+        }
+        high *= 2;
+    }
+
+    let mut low = v;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if enough(mid) {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+
+    low
+}
+
 /// Compute the minimal number of witness to attain the eta0 threshold
-pub fn compute_v_min(eta0: f64, kappa: u64, u: usize) -> usize {
-    let p = derive_p(kappa);
+pub fn compute_v_min(eta0: f64, kappa: u64, n: u64, u: usize) -> usize {
+    let p = derive_p(kappa, n);
     let alpha = erfc_inv(2.0 * eta0);
     let beta = u as f64 * p;
 
     (beta * (1.0 - p)).sqrt().mul_add(alpha, beta) as usize
 }
 
-/// Derivee the probability from the kappa value
-fn derive_p(kappa: u64) -> f64 {
-    (kappa as f64 - 160.0).exp2()
+/// Clamp a computed probability to `[0, 1]`, mapping NaN to 0.0
+fn clamp_probability(value: f64) -> f64 {
+    if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(0.0, 1.0)
+    }
+}
+
+/// Derivee the probability from the kappa value and the digest bit length
+pub(crate) fn derive_p(kappa: u64, n: u64) -> f64 {
+    (kappa as f64 - n as f64).exp2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_probability_precision() {
+        let (kappa, n, u, r) = (140, 160, 2, 2);
+
+        // P(X <= 1) = p^2 + 2 p^2 (1 - p) for two successes, computed without cancellation
+        let p = derive_p(kappa, n);
+        let exact = p * p * 2.0f64.mul_add(-p, 3.0);
+
+        let q = compute_q(kappa, n, u, r);
+        let direct = compute_failure_probability(kappa, n, u, r);
+        let complement = 1.0 - q;
+
+        assert!(1.0 - q < 1e-11);
+
+        let direct_error = ((direct - exact) / exact).abs();
+        let complement_error = ((complement - exact) / exact).abs();
+        println!("direct: {}, complement: {}", direct_error, complement_error);
+
+        assert!(direct_error < complement_error);
+    }
+
+    #[test]
+    fn failure_probability_out_of_range() {
+        let (kappa, n, u) = (150, 160, 1000);
+
+        // More witnesses than inputs leave no room for failure
+        assert_eq!(compute_failure_probability(kappa, n, u, u + 2), 0.0);
+        assert_eq!(compute_failure_probability(kappa, n, u, usize::MAX), 0.0);
+        let last = compute_failure_probability(kappa, n, u, u + 1);
+        assert!((0.0..=derive_p(kappa, n)).contains(&last));
+
+        // Kappa above the digest size makes every hash acceptable
+        assert_eq!(compute_failure_probability(n + 10, n, u, 3), 1.0);
+        assert_eq!(compute_q(n + 10, n, u, 3), 0.0);
+    }
+
+    #[test]
+    fn large_digest_kappa() {
+        // Kappa above 160 is meaningful with a 256-bit digest
+        let (kappa, n) = (200, 256);
+        let u = 1 << 60;
+
+        let p = derive_p(kappa, n);
+        assert!(p < 1.0);
+        assert_eq!(p, derive_p(104, 160));
+
+        // Only the distance between kappa and the digest size matters
+        let v = compute_v_min(0.99, kappa, n, u);
+        println!("v_min = {}", v);
+
+        assert!(v > 0);
+        assert_eq!(v, compute_v_min(0.99, 104, 160, u));
+        assert_eq!(compute_eta(kappa, n, u, v), compute_eta(104, 160, u, v));
+        assert!(compute_eta(kappa, n, u, v) > 0.9);
+
+        let q = compute_q(kappa, n, u, v);
+        assert!((0.0..=1.0).contains(&q));
+        assert_eq!(q, compute_q(104, 160, u, v));
+        assert_eq!(
+            compute_delta_u(0.99, kappa, n, u, v),
+            compute_delta_u(0.99, 104, 160, u, v)
+        );
+    }
+
+    #[test]
+    fn extension_reaches_threshold() {
+        let (kappa, n) = (155, 160);
+
+        let u = compute_extension(0.99, kappa, n, 11);
+        println!("u = {}", u);
+
+        assert!(compute_eta(kappa, n, u, 11) >= 0.99);
+        assert!(compute_eta(kappa, n, u - 1, 11) < 0.99);
+        assert_eq!(compute_extension(0.99, kappa, n, 0), 0);
+        assert_eq!(compute_extension(0.99, 0, 1100, 1), usize::MAX);
+    }
+
+    #[test]
+    fn degenerate_inputs() {
+        let (kappa, n, u) = (150, 160, 1000);
+
+        // No witness or no input gives no confidence
+        assert_eq!(compute_q(kappa, n, u, 0), 0.0);
+        assert_eq!(compute_q(kappa, n, 0, 3), 0.0);
+        assert_eq!(compute_eta(kappa, n, 0, 0), 1.0);
+        assert_eq!(compute_eta(kappa, n, 0, 3), 0.0);
+        assert_eq!(compute_failure_probability(kappa, n, u, 0), 1.0);
+        assert_eq!(compute_failure_probability(kappa, n, 0, 3), 1.0);
+
+        // Every hash is acceptable when p = 1
+        assert_eq!(derive_p(n, n), 1.0);
+        assert_eq!(compute_q(n, n, u, 3), 0.0);
+        assert_eq!(compute_failure_probability(n, n, u, 3), 1.0);
+        assert_eq!(compute_eta(n, n, u, u), 1.0);
+        assert_eq!(compute_eta(n, n, u, u + 1), 0.0);
+
+        // No hash is acceptable when p underflows to 0
+        assert_eq!(derive_p(0, 1100), 0.0);
+        assert_eq!(compute_q(0, 1100, u, 3), 1.0);
+        assert_eq!(compute_failure_probability(0, 1100, u, 3), 0.0);
+        assert_eq!(compute_eta(0, 1100, u, 0), 1.0);
+        assert_eq!(compute_eta(0, 1100, u, 3), 0.0);
+
+        // More witnesses than inputs
+        assert_eq!(compute_q(kappa, n, u, u + 2), 1.0);
+        assert_eq!(compute_failure_probability(kappa, n, u, u + 2), 0.0);
+
+        // The confidence never decreases as witnesses are added
+        let mut previous = 0.0;
+        for r in 0..=(u + 2) {
+            let q = compute_q(kappa, n, u, r);
+            assert!((0.0..=1.0).contains(&q));
+            assert!(q >= previous, "q({}) = {} < {}", r, q, previous);
+            previous = q;
+
+            // The failure probability stays the complement of the confidence
+            let failure = compute_failure_probability(kappa, n, u, r);
+            assert!((q + failure - 1.0).abs() < 1e-9, "r = {}", r);
+        }
+
+        // The probability of success never increases with the required witnesses
+        let mut previous = 1.0;
+        for v in 0..=u {
+            let eta = compute_eta(kappa, n, u, v);
+            assert!((0.0..=1.0).contains(&eta));
+            assert!(eta <= previous, "eta({}) = {} > {}", v, eta, previous);
+            previous = eta;
+        }
+    }
 }
@@ -1,51 +1,149 @@
-use std::{ops::Range, time::Instant};
+use color_eyre::Report;
+use rand::{rngs::StdRng, seq::index, SeedableRng};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufReader, Read},
+    ops::Range,
+    time::Instant,
+};
 
 use crate::{
     proof::{Proof, ProofReport, ProofStrategy},
     stats::{compute_eta, compute_q},
-    vm::{validate_hash, InstrumentedVM},
+    vm::{validate_hash, InstrumentedVM, Replay},
 };
 
 /// Enum of the possible outcome of the verification of the witnesses
-#[derive(PartialEq, Eq, Debug)]
-enum ValidationResult {
+#[derive(PartialEq, Eq, Debug, Clone, Serialize)]
+pub enum ValidationResult {
     /// An incorrect hash was found in the given witnesses set
     IncorrectHash,
     /// Program is not valid
     InvalidProgram,
+    /// Params of the proof do not describe an achievable claim
+    InvalidParams,
+    /// Program file changed since the proof was made
+    ProgramMismatch,
     /// Witness given is outside the agreed domain
     IncorrectInput(usize),
     /// Program does not give exoected result
     IncorrectOutput(usize),
     /// Runtime Error of the program
     ExecutionError,
+    /// Witness repeated or out of order, the witness set should be strictly increasing
+    NonCanonicalWitness(usize),
     /// No error but the number of witness if not enough
     ValidButTooFewHashes(usize),
     /// Valid witnesses set
     Valid,
 }
 
+impl fmt::Display for ValidationResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IncorrectHash => write!(f, "A witness has an incorrect hash"),
+            Self::InvalidProgram => write!(f, "The program could not be loaded"),
+            Self::InvalidParams => write!(f, "The params of the proof are invalid"),
+            Self::ProgramMismatch => write!(f, "The program changed since the proof was made"),
+            Self::IncorrectInput(i) => write!(f, "Witness {} is outside the domain", i),
+            Self::IncorrectOutput(output) => write!(f, "A witness gave the output {}", output),
+            Self::ExecutionError => write!(f, "A witness failed to execute"),
+            Self::NonCanonicalWitness(i) => {
+                write!(f, "Witness {} is repeated or out of order", i)
+            }
+            Self::ValidButTooFewHashes(v) => write!(f, "Only {} valid witnesses were given", v),
+            Self::Valid => write!(f, "All witnesses are valid"),
+        }
+    }
+}
+
 /// Verifier
 pub struct Verifier {
     /// Proof being verified
     proof: Proof,
+    /// VM primed with the program of the proof, shared by batch verifications
+    vm: Option<InstrumentedVM>,
+    /// Whether cached witness runs are re-executed and compared
+    spot_check: bool,
 }
 
 impl Verifier {
-    /// Create new verifier
-    pub const fn new(proof: Proof) -> Self {
-        Self { proof }
+    /// Create new verifier, checking that the proof params are valid
+    pub fn new(proof: Proof) -> Result<Self, Report> {
+        proof.params.validate()?;
+        Ok(Self {
+            proof,
+            vm: None,
+            spot_check: false,
+        })
+    }
+
+    /// Re-execute the witnesses even when their runs are cached in the proof
+    pub const fn with_spot_check(mut self) -> Self {
+        self.spot_check = true;
+        self
+    }
+
+    /// Validate several proofs, loading each program only once
+    ///
+    /// Proofs whose params are invalid are reported as such, and
+    /// proofs are only accepted if the claim holds with probability at least
+    /// `1 - epsilon`.
+    pub fn check_proofs(proofs: Vec<Proof>, epsilon: f64) -> Vec<ProofReport> {
+        let mut vms = HashMap::new();
+
+        proofs
+            .into_iter()
+            .map(|proof| {
+                let params = &proof.params;
+                if params.validate().is_err() {
+                    let result = Some(ValidationResult::InvalidParams);
+                    return ProofReport::create(&proof, 0.0, 0.0, false, result);
+                }
+
+                // VMs differ by program, hashing and run configuration
+                let key = (
+                    params.program_file.clone(),
+                    params.hash_algo,
+                    params.strategy.salt(),
+                    params.endianness,
+                    params.max_steps,
+                    params.expected_values().len(),
+                    params.result_source,
+                    params.signed_output,
+                );
+                let vm = vms
+                    .entry(key)
+                    .or_insert_with(|| InstrumentedVM::from_params(params).ok())
+                    .clone();
+
+                let verifier = Self {
+                    proof,
+                    vm,
+                    spot_check: false,
+                };
+                let mut report = verifier.check_proof();
+                report.valid &= report.q >= 1.0 - epsilon;
+                report
+            })
+            .collect()
     }
 
     /// Validate proof
     pub fn check_proof(&self) -> ProofReport {
+        if let Some(report) = self.check_program() {
+            return report;
+        }
+
         let start = Instant::now();
-        let result = match self.proof.params.strategy {
-            ProofStrategy::FixedEffort(epsilon) => self.check_proof_fixed_effort(epsilon),
-            ProofStrategy::BestEffort => self.check_proof_best_effort(),
-            ProofStrategy::BestEffortAdaptive(_eta0) => self.check_proof_best_effort(),
-            ProofStrategy::OverTesting(_eta0) => self.check_proof_overtesting(),
-        };
+        let vset = &self.proof.vset;
+        let result = self.check_claim(vset.len(), |domain| {
+            Self::check_canonical(vset).or_else(|| self.validate_witnesses(vset, domain))
+        });
 
         let duration = start.elapsed();
 
@@ -54,100 +152,808 @@ impl Verifier {
         result
     }
 
+    /// Validate a binary proof read from a stream, decoding one witness at a time
+    ///
+    /// The witness set is never held in memory: the witnesses are checked in
+    /// order as they are decoded, stopping at the first failure. The report
+    /// holds the proof without its witness set.
+    pub fn verify_streaming<R: Read>(reader: R) -> Result<ProofReport, Report> {
+        let (proof, witnesses, stream) = Proof::stream_binary(BufReader::new(reader))?;
+        let verifier = Self::new(proof)?;
+
+        if let Some(mut report) = verifier.check_program() {
+            report.witnesses = witnesses;
+            return Ok(report);
+        }
+
+        let start = Instant::now();
+        let mut error = None;
+        let mut report = verifier.check_claim(witnesses, |domain| {
+            verifier
+                .validate_stream(stream, domain)
+                .unwrap_or_else(|e| {
+                    error = Some(e);
+                    None
+                })
+        });
+        report.witnesses = witnesses;
+
+        println!("Verifier time: {:?}", start.elapsed());
+
+        match error {
+            Some(e) => Err(e),
+            _ => Ok(report),
+        }
+    }
+
+    /// Check the claim according to the proof strategy
+    ///
+    /// `validate` returns the first failure among the witnesses for the
+    /// domain they should belong to, if any.
+    fn check_claim<F>(&self, witnesses: usize, validate: F) -> ProofReport
+    where
+        F: FnOnce(&Range<usize>) -> Option<ValidationResult>,
+    {
+        match self.proof.params.strategy {
+            ProofStrategy::FixedEffort(epsilon) => {
+                self.check_proof_fixed_effort(epsilon, witnesses, validate)
+            }
+            ProofStrategy::BestEffort => self.check_proof_best_effort(witnesses, validate),
+            ProofStrategy::BestEffortAdaptive(eta0) => {
+                self.check_proof_bea(eta0, witnesses, validate)
+            }
+            ProofStrategy::OverTesting(_eta0) => self.check_proof_overtesting(witnesses, validate),
+            ProofStrategy::ReTestingSalt(_salt) => {
+                self.check_proof_best_effort(witnesses, validate)
+            }
+        }
+    }
+
+    /// Re-execute the program of the proof on an input, keeping the full hash chain
+    ///
+    /// Unlike the checks of the proof, this does not trust the digest of the
+    /// run: each step and its digest are returned so that an auditor can diff
+    /// them against an independent implementation.
+    pub fn replay(&self, input: usize) -> Result<Replay, Report> {
+        let mut vm = match self.vm {
+            Some(ref vm) => vm.clone(),
+            _ => InstrumentedVM::from_params(&self.proof.params)?,
+        };
+
+        vm.replay(input)
+    }
+
+    /// Validate proof by re-running only a random sample of the witnesses
+    ///
+    /// This is a weaker guarantee than `check_proof`: a proof with a few
+    /// invalid witnesses may be accepted. With probability at least
+    /// `1 - epsilon`, at most a fraction `1 - epsilon^(1 / sample_size)` of the
+    /// witnesses are invalid, and `q` is computed for the remaining ones only.
+    /// The witness set is accepted under the rules of the proof strategy.
+    pub fn check_proof_sampled(&self, epsilon: f64, sample_size: usize, seed: u64) -> ProofReport {
+        if let Some(report) = self.check_program() {
+            return report;
+        }
+
+        let start = Instant::now();
+        let proof = &self.proof;
+        let u = proof.params.input_domain.end - proof.params.input_domain.start;
+        let kappa = proof.params.kappa;
+        let n = proof.params.hash_algo.digest_bits();
+
+        // Witnesses are checked in the order of the witness set
+        let sample_size = sample_size.min(proof.vset.len());
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut sample: Vec<_> = index::sample(&mut rng, proof.vset.len(), sample_size)
+            .into_iter()
+            .map(|idx| proof.vset[idx])
+            .collect();
+        sample.sort_unstable();
+
+        // The sample is accepted under the same rules as the full witness set
+        let mut report = self.check_claim(proof.vset.len(), |domain| {
+            Self::check_canonical(&proof.vset).or_else(|| self.validate_witnesses(&sample, domain))
+        });
+
+        // Only the witnesses guaranteed to be valid are accounted for
+        let valid_fraction = match sample_size {
+            0 => 0.0,
+            s => epsilon.powf(1.0 / s as f64),
+        };
+        let v = (proof.vset.len() as f64 * valid_fraction).floor() as usize;
+        report.eta = compute_eta(kappa, n, u, v);
+        report.q = (1.0 - epsilon) * compute_q(kappa, n, u, v);
+
+        println!("Verifier time: {:?}", start.elapsed());
+
+        report
+    }
+
+    /// Reject the proof if the program file changed since the proof was made
+    ///
+    /// Proofs made before the program hash was recorded are not checked.
+    fn check_program(&self) -> Option<ProofReport> {
+        let params = &self.proof.params;
+        if params.program_hash.is_empty() {
+            return None;
+        }
+
+        let result = match params.hash_program() {
+            Ok(hash) if hash == params.program_hash => return None,
+            Ok(_) => ValidationResult::ProgramMismatch,
+            Err(_) => ValidationResult::InvalidProgram,
+        };
+
+        // Nothing was checked against the claim, so it holds no confidence
+        Some(ProofReport::create(
+            &self.proof,
+            0.0,
+            0.0,
+            false,
+            Some(result),
+        ))
+    }
+
     /// Validation for fixed effort
-    fn check_proof_fixed_effort(&self, epsilon: f64) -> ProofReport {
+    fn check_proof_fixed_effort<F>(
+        &self,
+        epsilon: f64,
+        witnesses: usize,
+        validate: F,
+    ) -> ProofReport
+    where
+        F: FnOnce(&Range<usize>) -> Option<ValidationResult>,
+    {
         let proof = &self.proof;
         let u = proof.params.input_domain.end - proof.params.input_domain.start;
         let kappa = proof.params.kappa;
+        let n = proof.params.hash_algo.digest_bits();
 
         let v = proof.params.v;
-        let eta = compute_eta(kappa, u, v);
-        let q = compute_q(kappa, u, v);
+        let eta = compute_eta(kappa, n, u, v);
+        let q = compute_q(kappa, n, u, v);
 
-        let valid = !q.is_nan()
-            && !eta.is_nan()
-            && q > 1.0 - epsilon
-            && self.validate_vset(&proof.params.input_domain) == ValidationResult::Valid;
+        // The witnesses are only checked when the claim is statistically acceptable
+        let result = (q > 1.0 - epsilon)
+            .then(|| self.vset_result(witnesses, validate(&proof.params.input_domain)));
+        let valid = result == Some(ValidationResult::Valid);
 
-        ProofReport::create(proof, eta, q, valid)
+        ProofReport::create(proof, eta, q, valid, result)
     }
 
     /// Validation for best effort
-    fn check_proof_best_effort(&self) -> ProofReport {
+    fn check_proof_best_effort<F>(&self, witnesses: usize, validate: F) -> ProofReport
+    where
+        F: FnOnce(&Range<usize>) -> Option<ValidationResult>,
+    {
         let proof = &self.proof;
         let u = proof.params.input_domain.end - proof.params.input_domain.start;
         let kappa = proof.params.kappa;
+        let n = proof.params.hash_algo.digest_bits();
 
-        let v = proof.vset.len();
-        let eta = compute_eta(kappa, u, v);
-        let q = compute_q(kappa, u, v);
+        let v = witnesses;
+        let eta = compute_eta(kappa, n, u, v);
+        let q = compute_q(kappa, n, u, v);
 
+        let result = self.vset_result(v, validate(&proof.params.input_domain));
         let valid = matches!(
-            self.validate_vset(&proof.params.input_domain),
+            result,
+            ValidationResult::Valid | ValidationResult::ValidButTooFewHashes(_)
+        );
+
+        ProofReport::create(proof, eta, q, valid, Some(result))
+    }
+
+    /// Validation for best effort adaptive
+    fn check_proof_bea<F>(&self, eta0: f64, witnesses: usize, validate: F) -> ProofReport
+    where
+        F: FnOnce(&Range<usize>) -> Option<ValidationResult>,
+    {
+        let proof = &self.proof;
+        let u = proof.params.input_domain.end - proof.params.input_domain.start;
+        let kappa = proof.params.kappa;
+        let n = proof.params.hash_algo.digest_bits();
+
+        let v = witnesses;
+        let eta = compute_eta(kappa, n, u, v);
+        let q = compute_q(kappa, n, u, v);
+
+        // The prover stops at the same threshold, which covers the agreed number of witnesses
+        let threshold = proof.params.adaptive_threshold(eta0);
+        let result = match self.vset_result(v, validate(&proof.params.input_domain)) {
             ValidationResult::Valid | ValidationResult::ValidButTooFewHashes(_)
-        ) && !q.is_nan()
-            && !eta.is_nan();
+                if v < threshold =>
+            {
+                ValidationResult::ValidButTooFewHashes(v)
+            }
+            ValidationResult::ValidButTooFewHashes(_) => ValidationResult::Valid,
+            result => result,
+        };
+        let valid = result == ValidationResult::Valid;
 
-        ProofReport::create(proof, eta, q, valid)
+        ProofReport::create(proof, eta, q, valid, Some(result))
     }
 
     /// Validation for overtesting
-    fn check_proof_overtesting(&self) -> ProofReport {
+    fn check_proof_overtesting<F>(&self, witnesses: usize, validate: F) -> ProofReport
+    where
+        F: FnOnce(&Range<usize>) -> Option<ValidationResult>,
+    {
         let proof = &self.proof;
         let u = proof.params.input_domain.end - proof.params.input_domain.start;
         let kappa = proof.params.kappa;
+        let n = proof.params.hash_algo.digest_bits();
 
-        let v = proof.vset.len();
-        let eta = compute_eta(kappa, u, v);
-        let q = compute_q(kappa, u, v);
+        let v = witnesses;
+        let eta = compute_eta(kappa, n, u, v);
+        let q = compute_q(kappa, n, u, v);
 
         let domain = match proof.extended_domain {
             Some(ref extended) => extended,
             _ => &proof.params.input_domain,
         };
 
-        let valid = matches!(self.validate_vset(domain), ValidationResult::Valid);
+        let result = self.vset_result(v, validate(domain));
+        let valid = matches!(result, ValidationResult::Valid);
 
-        ProofReport::create(proof, eta, q, valid)
+        ProofReport::create(proof, eta, q, valid, Some(result))
     }
 
-    /// Validating the witness set
-    fn validate_vset(&self, domain: &Range<usize>) -> ValidationResult {
-        let proof = &self.proof;
+    /// Outcome of the check of a witness set, given its size and its first failure if any
+    fn vset_result(&self, witnesses: usize, failure: Option<ValidationResult>) -> ValidationResult {
+        let enough_hashes = witnesses >= self.proof.params.v;
+
+        match failure {
+            Some(result) => result,
+            _ if enough_hashes => ValidationResult::Valid,
+            _ => ValidationResult::ValidButTooFewHashes(witnesses),
+        }
+    }
+
+    /// Check that a witness set is strictly increasing, returns the first offending witness if any
+    ///
+    /// A repeated witness would otherwise be counted several times.
+    fn check_canonical(witnesses: &[usize]) -> Option<ValidationResult> {
+        witnesses
+            .windows(2)
+            .find(|pair| pair[0] >= pair[1])
+            .map(|pair| ValidationResult::NonCanonicalWitness(pair[1]))
+    }
+
+    /// Validating the given witnesses, returns the first failure if any
+    fn validate_witnesses(
+        &self,
+        witnesses: &[usize],
+        domain: &Range<usize>,
+    ) -> Option<ValidationResult> {
+        let new_vm = || match self.vm {
+            Some(ref vm) => Ok(vm.clone()),
+            _ => InstrumentedVM::from_params(&self.proof.params),
+        };
+        if new_vm().is_err() {
+            return Some(ValidationResult::InvalidProgram);
+        }
 
-        let enough_hashes = proof.vset.len() >= proof.params.v;
+        // Each worker owns a VM, the failure reported is the first one in the witness set
+        witnesses
+            .par_iter()
+            .map_init(
+                || new_vm().ok(),
+                |vm, &i| match vm {
+                    Some(vm) => self.validate_witness(vm, domain, i),
+                    _ => Some(ValidationResult::InvalidProgram),
+                },
+            )
+            .find_map_first(|result| result)
+    }
 
-        let mut vm = match InstrumentedVM::new(&proof.params.program_file) {
-            Ok(ivm) => ivm,
-            _ => return ValidationResult::InvalidProgram,
+    /// Validating the witnesses of a stream in order, returns the first failure if any
+    fn validate_stream<I>(
+        &self,
+        witnesses: I,
+        domain: &Range<usize>,
+    ) -> Result<Option<ValidationResult>, Report>
+    where
+        I: Iterator<Item = Result<usize, Report>>,
+    {
+        let vm = match self.vm {
+            Some(ref vm) => Ok(vm.clone()),
+            _ => InstrumentedVM::from_params(&self.proof.params),
+        };
+        let mut vm = match vm {
+            Ok(vm) => vm,
+            _ => return Ok(Some(ValidationResult::InvalidProgram)),
         };
 
-        for &i in proof.vset.as_slice() {
-            if !domain.contains(&i) {
-                // Value is outside of authorised domain
-                return ValidationResult::IncorrectInput(i);
+        let mut previous = None;
+        for i in witnesses {
+            let i = i?;
+            if previous.is_some_and(|previous| previous >= i) {
+                return Ok(Some(ValidationResult::NonCanonicalWitness(i)));
             }
+            previous = Some(i);
 
-            match vm.run(i) {
-                Ok(res) => {
-                    if res.output != proof.params.expected_output {
-                        // Output does not match expectation
-                        return ValidationResult::IncorrectOutput(res.output);
-                    }
-
-                    if !validate_hash(&res.hash, proof.params.kappa as usize) {
-                        // Hash does not match expectation
-                        return ValidationResult::IncorrectHash;
-                    }
-                }
-                Err(_e) => return ValidationResult::ExecutionError,
+            if let Some(failure) = self.validate_witness(&mut vm, domain, i) {
+                return Ok(Some(failure));
             }
         }
 
-        if enough_hashes {
-            ValidationResult::Valid
-        } else {
-            ValidationResult::ValidButTooFewHashes(proof.vset.len())
+        Ok(None)
+    }
+
+    /// Return the first value of `outputs` differing from `expected`, 0 if it is missing
+    fn first_mismatch(outputs: &[usize], expected: &[usize]) -> usize {
+        (0..outputs.len().max(expected.len()))
+            .find(|&idx| outputs.get(idx) != expected.get(idx))
+            .and_then(|idx| outputs.get(idx).copied())
+            .unwrap_or_default()
+    }
+
+    /// Validating a single witness, returns the failure if any
+    fn validate_witness(
+        &self,
+        vm: &mut InstrumentedVM,
+        domain: &Range<usize>,
+        i: usize,
+    ) -> Option<ValidationResult> {
+        let proof = &self.proof;
+
+        if !domain.contains(&i) {
+            // Value is outside of authorised domain
+            return Some(ValidationResult::IncorrectInput(i));
         }
+
+        // Cached runs are trusted unless a spot-check is requested
+        let cached = proof.cache.as_ref().and_then(|cache| cache.get(i));
+        let (outputs, hash) = match cached {
+            Some(run) if !self.spot_check => (run.values(), run.hash.clone()),
+            _ => match vm.run(i) {
+                Ok(res) => (res.outputs, res.hash),
+                Err(_e) => return Some(ValidationResult::ExecutionError),
+            },
+        };
+        if let Some(run) = cached {
+            if run.values() != outputs {
+                // Cached output does not match the execution
+                let output = Self::first_mismatch(&run.values(), &outputs);
+                return Some(ValidationResult::IncorrectOutput(output));
+            } else if run.hash != hash {
+                // Cached hash does not match the execution
+                return Some(ValidationResult::IncorrectHash);
+            }
+        }
+
+        if outputs != proof.params.expected_values() {
+            // Output does not match expectation
+            let output = Self::first_mismatch(&outputs, proof.params.expected_values());
+            return Some(ValidationResult::IncorrectOutput(output));
+        }
+
+        if !validate_hash(&hash, proof.params.kappa as usize) {
+            // Hash does not match expectation
+            return Some(ValidationResult::IncorrectHash);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stats::compute_v_min, ProofParams, Prover, WitnessCache};
+    use color_eyre::Report;
+
+    use std::{env, fs, fs::File};
+
+    #[test]
+    fn replay_detects_tampered_program() -> Result<(), Report> {
+        let path = env::temp_dir().join("ckc_replay_collatz.tr");
+        let source = fs::read_to_string("../assets/collatz_v0.tr")?;
+        fs::write(&path, &source)?;
+
+        let params = ProofParams::new(
+            path.to_str().unwrap(),
+            1..1000,
+            0,
+            155,
+            1,
+            ProofStrategy::BestEffort,
+        );
+        let proof = Prover::new(params)?.with_witness_cache().obtain_proof()?;
+        let witness = proof.vset[0];
+        let claimed = proof
+            .cache
+            .as_ref()
+            .unwrap()
+            .get(witness)
+            .unwrap()
+            .hash
+            .clone();
+
+        let replay = Verifier::new(proof.clone())?.replay(witness)?;
+        assert_eq!(replay.result.hash, claimed);
+
+        // Count the steps by two, which keeps the output unchanged
+        let tampered = source.replacen("add     r2, r2, 1", "add     r2, r2, 2", 2);
+        assert_ne!(tampered, source);
+        fs::write(&path, tampered)?;
+
+        let replay = Verifier::new(proof)?.replay(witness)?;
+        fs::remove_file(&path)?;
+
+        assert_eq!(replay.result.output, 0);
+        assert_ne!(replay.result.hash, claimed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_modified_program() -> Result<(), Report> {
+        let path = env::temp_dir().join("ckc_modified_collatz.tr");
+        fs::copy("../assets/collatz_v0.tr", &path)?;
+
+        let params = ProofParams::new(
+            path.to_str().unwrap(),
+            1..1000,
+            0,
+            155,
+            1,
+            ProofStrategy::BestEffort,
+        );
+        let proof = Prover::new(params)?.obtain_proof()?;
+        assert!(Verifier::new(proof.clone())?.check_proof().valid);
+
+        // Even a comment change alters the program hash
+        let source = fs::read_to_string(&path)?;
+        fs::write(&path, source + "; modified\n")?;
+
+        let result = Verifier::new(proof.clone())?.check_proof();
+        let sampled = Verifier::new(proof)?.check_proof_sampled(0.01, 8, 0);
+        fs::remove_file(&path)?;
+
+        assert!(!result.valid);
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::ProgramMismatch)
+        );
+        assert_eq!((result.eta, result.q), (0.0, 0.0));
+        assert_eq!(
+            sampled.detailed_result,
+            Some(ValidationResult::ProgramMismatch)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_vset_reports_first_failure() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            155,
+            10,
+            ProofStrategy::BestEffort,
+        );
+        let mut proof = Prover::new(params)?.obtain_proof()?;
+        assert!(proof.vset.len() > 4);
+
+        // One witness outside the domain in the middle, and a later one with an invalid hash
+        let middle = proof.vset.len() / 2;
+        let invalid_hash = (1..1000).find(|i| !proof.vset.contains(i)).unwrap();
+        proof.vset.insert(middle, 5000);
+        proof.vset.insert(middle + 2, invalid_hash);
+
+        let verifier = Verifier::new(proof)?;
+        let domain = verifier.proof.params.input_domain.clone();
+
+        for _ in 0..10 {
+            assert_eq!(
+                verifier.validate_witnesses(&verifier.proof.vset, &domain),
+                Some(ValidationResult::IncorrectInput(5000))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_matches_in_memory() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..20000,
+            0,
+            152,
+            50,
+            ProofStrategy::BestEffort,
+        );
+        let proof = Prover::new(params)?.obtain_proof_chunked(2000)?;
+        println!("Witnesses = {}", proof.vset.len());
+
+        let verify = |proof: &Proof| -> Result<_, Report> {
+            let path = env::temp_dir().join("ckc_streaming_proof.bin");
+            proof.save_binary(&path)?;
+            let streamed = Verifier::verify_streaming(File::open(&path)?)?;
+            let expected = Verifier::new(proof.clone())?.check_proof();
+
+            assert_eq!(streamed.witnesses, proof.vset.len());
+            assert_eq!(streamed.q, expected.q);
+            Ok((streamed, expected))
+        };
+
+        let (streamed, expected) = verify(&proof)?;
+        assert!(streamed.valid);
+        assert_eq!(streamed.valid, expected.valid);
+        assert!(streamed.proof.vset.is_empty());
+
+        // The stream stops at the first failure
+        let mut tampered = proof;
+        let invalid_hash = (1..20000).find(|i| !tampered.vset.contains(i)).unwrap();
+        tampered.vset.push(invalid_hash);
+        tampered.vset.sort_unstable();
+        let (streamed, expected) = verify(&tampered)?;
+        assert!(!streamed.valid);
+        assert_eq!(streamed.detailed_result, expected.detailed_result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_repeated_witnesses() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            155,
+            10,
+            ProofStrategy::FixedEffort(1.0),
+        );
+        let proof = Prover::new(params)?.obtain_proof()?;
+        assert!(proof.vset.len() > 1);
+
+        // A single valid witness repeated enough times to meet the agreed number
+        let mut forged = proof.clone();
+        forged.vset = vec![proof.vset[0]; 10];
+        let result = Verifier::new(forged.clone())?.check_proof();
+        assert!(!result.valid);
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::NonCanonicalWitness(proof.vset[0]))
+        );
+
+        // The sampled check does not miss it either
+        let result = Verifier::new(forged.clone())?.check_proof_sampled(0.01, 1, 0);
+        assert!(!result.valid);
+
+        // Nor does the streaming check, nor an out of order witness set
+        let path = env::temp_dir().join("ckc_repeated_witnesses.bin");
+        forged.save_binary(&path)?;
+        let result = Verifier::verify_streaming(File::open(&path)?)?;
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::NonCanonicalWitness(proof.vset[0]))
+        );
+
+        let mut unsorted = proof;
+        unsorted.vset.reverse();
+        unsorted.save_binary(&path)?;
+        let result = Verifier::verify_streaming(File::open(&path)?)?;
+        fs::remove_file(&path)?;
+        assert!(matches!(
+            result.detailed_result,
+            Some(ValidationResult::NonCanonicalWitness(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_out_of_domain_witness() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            155,
+            1,
+            ProofStrategy::BestEffort,
+        );
+        let mut proof = Prover::new(params)?.obtain_proof()?;
+        proof.vset.push(5000);
+
+        let result = Verifier::new(proof)?.check_proof();
+        result.display();
+
+        assert!(!result.valid);
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::IncorrectInput(5000))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sampled_check_catches_bad_witness() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            155,
+            10,
+            ProofStrategy::BestEffort,
+        );
+        let mut proof = Prover::new(params)?.obtain_proof()?;
+        let full = Verifier::new(proof.clone())?.check_proof();
+
+        // Sampling a valid proof accepts it with a lower confidence
+        let sampled = Verifier::new(proof.clone())?.check_proof_sampled(0.01, 8, 42);
+        assert!(sampled.valid);
+        assert!(sampled.q < full.q);
+
+        let invalid_hash = (1..1000).find(|i| !proof.vset.contains(i)).unwrap();
+        proof.vset.push(invalid_hash);
+        proof.vset.sort_unstable();
+        let verifier = Verifier::new(proof)?;
+
+        // Sampling three quarters of the witnesses catches the bad one most of the time
+        let sample_size = verifier.proof.vset.len() * 3 / 4;
+        let caught = (0..20)
+            .filter(|&seed| !verifier.check_proof_sampled(0.01, sample_size, seed).valid)
+            .count();
+        println!("Caught = {}/20", caught);
+        assert!(caught >= 10);
+
+        // Sampling every witness always catches it
+        let report = verifier.check_proof_sampled(0.01, usize::MAX, 0);
+        assert_eq!(
+            report.detailed_result,
+            Some(ValidationResult::IncorrectHash)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sampled_check_follows_strategy() -> Result<(), Report> {
+        // Fixed effort and over-testing proofs are rejected with too few witnesses
+        for strategy in [
+            ProofStrategy::FixedEffort(1.0),
+            ProofStrategy::OverTesting(0.5),
+        ] {
+            let params =
+                ProofParams::new("../assets/collatz_v0.tr", 1..1000, 0, 155, 100, strategy);
+            let proof = Prover::new(params)?.obtain_proof()?;
+            assert!(proof.vset.len() < 100);
+
+            let full = Verifier::new(proof.clone())?.check_proof();
+            let sampled = Verifier::new(proof)?.check_proof_sampled(0.01, 8, 0);
+            println!("{:?}: {:?}", strategy, sampled.detailed_result);
+
+            assert!(!full.valid);
+            assert!(!sampled.valid);
+            assert_eq!(sampled.detailed_result, full.detailed_result);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_verification_matches_individual_checks() -> Result<(), Report> {
+        let mut proofs = vec![];
+        for domain in [1..300, 300..600, 600..900] {
+            let params = ProofParams::new(
+                "../assets/collatz_v0.tr",
+                domain,
+                0,
+                155,
+                5,
+                ProofStrategy::BestEffort,
+            );
+            proofs.push(Prover::new(params)?.obtain_proof()?);
+        }
+        // Tamper with the last proof
+        proofs[2].vset.push(5000);
+
+        let reports = Verifier::check_proofs(proofs.clone(), 1.0);
+        assert_eq!(reports.len(), 3);
+
+        for (proof, report) in proofs.iter().cloned().zip(reports) {
+            let expected = Verifier::new(proof)?.check_proof();
+            assert_eq!(report.export(), expected.export());
+        }
+
+        // Valid proofs are only accepted if the claim is likely enough
+        let q = Verifier::new(proofs[0].clone())?.check_proof().q;
+        assert!(q < 1.0);
+        let lenient = Verifier::check_proofs(vec![proofs[0].clone()], 1.0 - q / 2.0);
+        assert!(lenient[0].valid);
+        let strict = Verifier::check_proofs(vec![proofs[0].clone()], (1.0 - q) / 2.0);
+        assert!(!strict[0].valid);
+        assert_eq!(strict[0].detailed_result, lenient[0].detailed_result);
+
+        // Invalid params are reported without checking the witnesses
+        let mut invalid = proofs[0].clone();
+        invalid.params.kappa = 200;
+        let reports = Verifier::check_proofs(vec![invalid], 1.0);
+        assert!(!reports[0].valid);
+        assert_eq!(
+            reports[0].detailed_result,
+            Some(ValidationResult::InvalidParams)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_proof_stops_at_threshold() -> Result<(), Report> {
+        // At kappa 155, the domain holds fewer witnesses than the threshold
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            156,
+            10,
+            ProofStrategy::BestEffortAdaptive(0.99),
+        );
+        let threshold = compute_v_min(0.99, 156, 160, 999);
+        println!("Threshold = {}", threshold);
+
+        let mut proof = Prover::new(params)?.obtain_proof()?;
+        assert_eq!(proof.vset.len(), threshold);
+
+        let result = Verifier::new(proof.clone())?.check_proof();
+        result.display();
+        assert!(result.valid);
+
+        // One witness short of the threshold is not enough
+        proof.vset.pop();
+        let result = Verifier::new(proof)?.check_proof();
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::ValidButTooFewHashes(threshold - 1))
+        );
+        assert!(!result.valid);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_cached_proof() -> Result<(), Report> {
+        let params = ProofParams::new(
+            "../assets/collatz_v0.tr",
+            1..1000,
+            0,
+            155,
+            10,
+            ProofStrategy::BestEffort,
+        );
+        let mut proof = Prover::new(params)?.with_witness_cache().obtain_proof()?;
+        assert_eq!(
+            proof.cache.as_ref().map(WitnessCache::len),
+            Some(proof.vset.len())
+        );
+
+        let result = Verifier::new(proof.clone())?.check_proof();
+        assert!(result.valid);
+
+        // Flip the last bit of a cached hash, which keeps it below the threshold
+        let witness = proof.vset[0];
+        let cache = proof.cache.as_mut().unwrap();
+        let mut hash = cache.get(witness).unwrap().hash.clone();
+        *hash.last_mut().unwrap() ^= 1;
+        cache.insert(witness, &[0], hash);
+
+        let result = Verifier::new(proof.clone())?.check_proof();
+        assert!(result.valid);
+
+        let result = Verifier::new(proof)?.with_spot_check().check_proof();
+        assert!(!result.valid);
+        assert_eq!(
+            result.detailed_result,
+            Some(ValidationResult::IncorrectHash)
+        );
+
+        Ok(())
     }
 }
@@ -1,18 +1,25 @@
 use bitvec::prelude::*;
-use color_eyre::Report;
-use sha1::{Digest, Sha1};
+use color_eyre::{eyre::eyre, Report};
+use serde::{Deserialize, Serialize};
 
 use std::{
+    cell::RefCell,
     fmt::Debug,
+    io::Write,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     time::Instant,
 };
 
-use crate::stats::compute_q;
-use tinyvm::{parser::Parser, TinyVM};
+use crate::{
+    hash::{HashAlgo, Hasher},
+    proof::{ProofParams, ResultSource},
+    stats::{compute_q, derive_p},
+};
+use tinyvm::{parser::Parser, StepRecord, TinyVM, VmError};
 
 /// Strucr reprensenting the result of the instrumented VM run
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RunResult {
     /// Hash of the program run
     pub hash: Vec<u8>,
@@ -20,51 +27,275 @@ pub struct RunResult {
     pub input: usize,
     /// Program output
     pub output: usize,
+    /// Values of the output region, the first one being `output`
+    #[serde(default)]
+    pub outputs: Vec<usize>,
+    /// Number of steps executed by the program
+    pub steps: usize,
+}
+
+/// Step of a replayed run, with the data it fed to the hash chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayStep {
+    /// Record of the executed step
+    pub record: StepRecord,
+    /// Bytes of the state hashed after the step
+    pub state: Vec<u8>,
+    /// Digest of the hash chain up to and including the step
+    pub digest: Vec<u8>,
+}
+
+/// Run re-executed with the full hash chain, for auditing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    /// Result of the run, holding the final digest
+    pub result: RunResult,
+    /// Every executed step, in order
+    pub trace: Vec<ReplayStep>,
 }
 
 /// VM used in CKC to hash the different states
+#[derive(Clone)]
 pub struct InstrumentedVM {
     /// The VM instance
     vm: TinyVM,
-    /// The executed program
-    program: String,
+    /// Hasher primed with the executed program
+    hasher: Hasher,
+    /// Where the result of a run is read from
+    result_source: ResultSource,
+    /// Whether the results are sign-extended from the word size
+    signed_output: bool,
 }
 
 impl InstrumentedVM {
     /// Create a new VM for a given program
-    pub fn new<P>(filename: P) -> Result<Self, Report>
+    pub fn new<P>(filename: P, hash_algo: HashAlgo) -> Result<Self, Report>
     where
         P: AsRef<Path> + Debug,
     {
         let vm = Parser::load_program(&filename)?;
         let program = serde_json::to_string(&vm.instructions())?;
 
-        Ok(Self { vm, program })
+        // The program prefix is hashed once and the primed state reused for each run
+        let mut hasher = Hasher::new(hash_algo);
+        hasher.update(program.as_bytes());
+
+        Ok(Self {
+            vm,
+            hasher,
+            result_source: ResultSource::default(),
+            signed_output: false,
+        })
+    }
+
+    /// Create a new VM configured for the given proof parameters
+    pub fn from_params(params: &ProofParams) -> Result<Self, Report> {
+        let mut vm = Self::new(&params.program_file, params.hash_algo)?;
+        vm.vm = vm
+            .vm
+            .with_output_region(0, params.expected_values().len())
+            .with_endianness(params.endianness);
+        vm.result_source = params.result_source;
+        vm.signed_output = params.signed_output;
+        if let Some(max_steps) = params.max_steps {
+            vm = vm.with_step_limit(max_steps);
+        }
+
+        Ok(match params.strategy.salt() {
+            Some(salt) => vm.with_salt(salt),
+            _ => vm,
+        })
+    }
+
+    /// Mix a salt into the hash of each run
+    pub fn with_salt(mut self, salt: u64) -> Self {
+        self.hasher.update(&salt.to_be_bytes());
+        self
+    }
+
+    /// Limit the number of steps of each run
+    pub fn with_step_limit(mut self, max_steps: usize) -> Self {
+        self.vm = self.vm.with_step_limit(max_steps);
+        self
+    }
+
+    /// Read the result of each run from the given source
+    pub const fn with_result_source(mut self, result_source: ResultSource) -> Self {
+        self.result_source = result_source;
+        self
+    }
+
+    /// Sign-extend the results of each run from the word size of the program
+    pub const fn with_signed_output(mut self) -> Self {
+        self.signed_output = true;
+        self
     }
 
     /// Run the VM with the given input
     pub fn run(&mut self, input: usize) -> Result<RunResult, Report> {
-        let mut hasher = Sha1::new();
-        hasher.update(&self.program);
+        self.run_with_private_tape(input, vec![])
+    }
+
+    /// Run the VM on each input in turn, lazily yielding the results in input order
+    ///
+    /// The whole batch shares the VM and its primed hasher, and the state is
+    /// reset between inputs, so each result matches the one of a single run.
+    pub fn run_batch<'a, I>(
+        &'a mut self,
+        inputs: I,
+    ) -> impl Iterator<Item = Result<RunResult, Report>> + 'a
+    where
+        I: IntoIterator<Item = usize>,
+        I::IntoIter: 'a,
+    {
+        inputs.into_iter().map(move |input| self.run(input))
+    }
+
+    /// Run the VM with the given public input and private tape
+    pub fn run_with_private_tape(
+        &mut self,
+        input: usize,
+        private: Vec<usize>,
+    ) -> Result<RunResult, Report> {
+        let mut hasher = self.hasher.clone();
         let update_hash = |s: &[u8]| hasher.update(s);
         let output = self
             .vm
-            .run_vm_with_callback((vec![input], vec![]), update_hash)?;
-        let hash = hasher.finalize();
-        let hash = hash.as_slice().to_vec();
+            .run_vm_with_callback((vec![input], private), update_hash);
+        let steps = self.vm.step_count();
+        let output = self.select_result(output);
+        let outputs = self.outputs(&output);
         self.vm.reset_state();
 
+        let output = output?;
+        let hash = hasher.finalize();
+
         Ok(RunResult {
             hash,
             input,
             output,
+            outputs,
+            steps,
         })
     }
+
+    /// Run the VM with the given input without hashing the states, returning only the output
+    pub fn run_output_only(&mut self, input: usize) -> Result<usize, Report> {
+        let output = self.vm.run_vm((vec![input], vec![]));
+        let output = self.select_result(output);
+        self.vm.reset_state();
+
+        output
+    }
+
+    /// Re-execute the VM with the given input, keeping every link of the hash chain
+    ///
+    /// The digest of each step can be diffed against an independently computed
+    /// reference, and the last one is the digest of the run.
+    pub fn replay(&mut self, input: usize) -> Result<Replay, Report> {
+        let pending = RefCell::new(vec![]);
+        let mut steps = vec![];
+        let output = self.vm.run_vm_with_trace(
+            (vec![input], vec![]),
+            |s: &[u8]| pending.borrow_mut().extend_from_slice(s),
+            |record: &StepRecord| steps.push((record.clone(), pending.take())),
+        );
+        let output = self.select_result(output);
+        let result = RunResult {
+            hash: vec![],
+            input,
+            output: 0,
+            outputs: self.outputs(&output),
+            steps: self.vm.step_count(),
+        };
+        self.vm.reset_state();
+        let output = output?;
+
+        let mut hasher = self.hasher.clone();
+        let trace: Vec<_> = steps
+            .into_iter()
+            .map(|(record, state)| {
+                hasher.update(&state);
+                let digest = hasher.clone().finalize();
+                ReplayStep {
+                    record,
+                    state,
+                    digest,
+                }
+            })
+            .collect();
+
+        Ok(Replay {
+            result: RunResult {
+                hash: hasher.finalize(),
+                output,
+                ..result
+            },
+            trace,
+        })
+    }
+
+    /// Pick the result of the last run according to the result source
+    fn select_result(&self, output: Result<usize, Report>) -> Result<usize, Report> {
+        let output = match (self.result_source, output) {
+            (ResultSource::Memory, output) => output,
+            // Any code given to `answer` is a result, not only 0
+            (ResultSource::Answer, Ok(_)) => Ok(self.vm.answer_code()),
+            (ResultSource::Answer, Err(e)) if is_error_code(&e) => Ok(self.vm.answer_code()),
+            (ResultSource::Answer, Err(e)) => Err(e),
+        };
+
+        output.map(|value| self.normalize(value))
+    }
+
+    /// Return the values compared to the expected outputs for the last run
+    fn outputs(&self, output: &Result<usize, Report>) -> Vec<usize> {
+        match (self.result_source, output) {
+            (ResultSource::Memory, _) => self
+                .vm
+                .outputs()
+                .into_iter()
+                .map(|value| self.normalize(value))
+                .collect(),
+            (ResultSource::Answer, output) => output.iter().copied().collect(),
+        }
+    }
+
+    /// Sign-extend a result from the word size if the results are signed
+    fn normalize(&self, value: usize) -> usize {
+        match self.signed_output {
+            true => self.vm.signed_word(value) as usize,
+            false => value,
+        }
+    }
+}
+
+/// Check if a run ended with a non-zero `answer` code
+fn is_error_code(error: &Report) -> bool {
+    matches!(error.downcast_ref::<VmError>(), Some(VmError::ErrorCode(_)))
+}
+
+/// Check if a run failed on its input, as opposed to a failure of the setup
+///
+/// Any `VmError` is specific to the input being run: a step limit, a segfault,
+/// an out of bounds access, an error code or a trapped overflow.
+pub fn is_run_failure(error: &Report) -> bool {
+    error.downcast_ref::<VmError>().is_some()
 }
 
 /// Validate the output hash
+///
+/// This is the canonical witness predicate, shared by the prover and the
+/// verifier: a hash of `n` bits is accepted when its `n - kappa` most
+/// significant bits are all clear, i.e. when it is lower than `2^kappa`.
 pub fn validate_hash(hash: &[u8], kappa: usize) -> bool {
-    for hash_val in hash.view_bits::<Msb0>().iter().take(160 - kappa) {
+    let n = hash.len() * 8;
+
+    for hash_val in hash
+        .view_bits::<Msb0>()
+        .iter()
+        .take(n.saturating_sub(kappa))
+    {
         if *hash_val {
             return false;
         }
@@ -73,29 +304,67 @@ pub fn validate_hash(hash: &[u8], kappa: usize) -> bool {
     true
 }
 
+/// Observed rate of valid hashes for a kappa in an acceptance sweep
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KappaStats {
+    /// The agreed upon hash max value
+    pub kappa: usize,
+    /// Number of valid hashes found in the sweep
+    pub valid: usize,
+    /// Number of inputs of the sweep
+    pub tested: usize,
+    /// Fraction of valid hashes observed
+    pub empirical_p: f64,
+    /// Probability of a valid hash for a uniform digest
+    pub expected_p: f64,
+}
+
+/// Acceptance `q` of each kappa, by number of tested inputs
+pub type AcceptanceCurves = Vec<(usize, Vec<f64>)>;
+
+/// Sweep the inputs `0..u_max` and compute the acceptance of a claim of `u` inputs
+///
+/// The curves are computed for `kappa_num` values evenly spread over `kappas`.
 pub fn get_data(
     program: PathBuf,
     u: usize,
     u_max: usize,
-) -> Result<Vec<(usize, Vec<f64>)>, Report> {
-    let kappa_min = 144;
-    let kappa_max = 159;
-    let kappa_num = 5;
+    kappas: RangeInclusive<usize>,
+    kappa_num: usize,
+) -> Result<(AcceptanceCurves, Vec<KappaStats>), Report> {
+    let hash_algo = HashAlgo::Sha1;
+    let (kappa_min, kappa_max) = kappas.into_inner();
+
+    if kappa_max as u64 >= hash_algo.digest_bits() {
+        return Err(eyre!(
+            "Kappa should be lower than the digest size ({}), got {}",
+            hash_algo.digest_bits(),
+            kappa_max
+        ));
+    } else if kappa_min > kappa_max {
+        return Err(eyre!("Empty kappa range {}..={}", kappa_min, kappa_max));
+    } else if kappa_num < 2 {
+        return Err(eyre!(
+            "At least 2 kappa values are needed, got {}",
+            kappa_num
+        ));
+    }
+
     let get_kappa = |i: usize| (kappa_max - kappa_min) * i / (kappa_num - 1) + kappa_min;
 
-    let mut data: Vec<(usize, Vec<f64>)> = (0..kappa_num)
+    let mut data: AcceptanceCurves = (0..kappa_num)
         .map(|i| (get_kappa(i), vec![0.0; u_max]))
         .collect();
 
     let start = Instant::now();
-    let mut vm = InstrumentedVM::new(program)?;
+    let mut vm = InstrumentedVM::new(program, hash_algo)?;
 
     // Accumulator for the valid number of hashes
     let mut acc: Vec<usize> = vec![0; kappa_num];
 
     // Create data points form vm run
-    (0..u_max).for_each(|i| {
-        let h = vm.run(i).unwrap().hash;
+    for (i, run_result) in vm.run_batch(0..u_max).enumerate() {
+        let h = run_result?.hash;
 
         // Apply each hash to a kappa
         data.iter_mut()
@@ -104,13 +373,38 @@ pub fn get_data(
                 if validate_hash(&h, *kappa) {
                     acc[idx] += 1;
                 }
-                values[i] = compute_q(*kappa as u64, u, acc[idx]);
+                values[i] = compute_q(*kappa as u64, hash_algo.digest_bits(), u, acc[idx]);
             })
-    });
+    }
 
     println!("Got traces in: {:?}", start.elapsed());
 
-    Ok(data)
+    let stats = data
+        .iter()
+        .zip(acc)
+        .map(|(&(kappa, _), valid)| KappaStats {
+            kappa,
+            valid,
+            tested: u_max,
+            empirical_p: valid as f64 / u_max.max(1) as f64,
+            expected_p: derive_p(kappa as u64, hash_algo.digest_bits()),
+        })
+        .collect();
+
+    Ok((data, stats))
+}
+
+/// Write the acceptance curves as CSV, with a row per kappa and number of tested inputs
+pub fn write_csv<W: Write>(data: &AcceptanceCurves, mut writer: W) -> Result<(), Report> {
+    writeln!(writer, "u,kappa,q")?;
+    for (kappa, values) in data {
+        for (i, q) in values.iter().enumerate() {
+            writeln!(writer, "{},{},{}", i + 1, kappa, q)?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -119,7 +413,7 @@ mod tests {
 
     #[test]
     fn run_fib_with_instrumentation() -> Result<(), Report> {
-        let mut vm = InstrumentedVM::new(&String::from("../assets/fib.tr"))?;
+        let mut vm = InstrumentedVM::new(String::from("../assets/fib.tr"), HashAlgo::Sha1)?;
         let result = vm.run(39)?;
         println!("Result = {:?}", result);
 
@@ -132,7 +426,7 @@ mod tests {
 
     #[test]
     fn run_collatz_with_instrumentation() -> Result<(), Report> {
-        let mut vm = InstrumentedVM::new(&String::from("../assets/collatz_v0.tr"))?;
+        let mut vm = InstrumentedVM::new(String::from("../assets/collatz_v0.tr"), HashAlgo::Sha1)?;
 
         let result = vm.run(39)?;
         println!("Result = {:?}", result);
@@ -143,4 +437,220 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn run_with_private_tape() -> Result<(), Report> {
+        let mut vm = InstrumentedVM::new("../assets/test_tapes.tr", HashAlgo::Sha1)?;
+
+        let result = vm.run_with_private_tape(1, vec![2, 4])?;
+
+        // The second public read finds an exhausted tape and yields 0
+        assert_eq!(result.output, 0x1204);
+        assert_ne!(result.hash, vm.run_with_private_tape(1, vec![2, 5])?.hash);
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_state_bleed_between_runs() -> Result<(), Report> {
+        let mut vm = InstrumentedVM::new("../assets/collatz_v0.tr", HashAlgo::Sha1)?;
+        let fresh = InstrumentedVM::new("../assets/collatz_v0.tr", HashAlgo::Sha1)?.run(27)?;
+
+        let a = vm.run(27)?;
+        let b = vm.run(39)?;
+        assert_ne!(a.hash, b.hash);
+        assert_eq!(vm.run(27)?, a);
+        assert_eq!(a, fresh);
+
+        // Leftover tape words would be read by the next run
+        let mut vm = InstrumentedVM::new("../assets/test_tapes.tr", HashAlgo::Sha1)?;
+        let a = vm.run_with_private_tape(1, vec![2, 4])?;
+        vm.run_with_private_tape(3, vec![7, 7, 7, 7])?;
+        assert_eq!(vm.run_with_private_tape(1, vec![2, 4])?, a);
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_matches_single_runs() -> Result<(), Report> {
+        let mut vm = InstrumentedVM::new("../assets/collatz_v0.tr", HashAlgo::Sha1)?;
+        let inputs = [27, 3, 39];
+
+        let batch = vm.run_batch(inputs).collect::<Result<Vec<_>, _>>()?;
+        let single = inputs
+            .iter()
+            .map(|&input| vm.run(input))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(batch, single);
+        assert_eq!(
+            batch.iter().map(|r| r.input).collect::<Vec<_>>(),
+            inputs.to_vec()
+        );
+
+        // A failing input does not stop the rest of the batch
+        let mut vm = InstrumentedVM::new("../assets/infinite_loop.tr", HashAlgo::Sha1)?
+            .with_step_limit(1000);
+        let batch: Vec<_> = vm.run_batch(0..3).collect();
+        assert_eq!(batch.len(), 3);
+        assert!(batch.iter().all(|r| r.as_ref().is_err_and(is_run_failure)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_infinite_loop_with_step_limit() -> Result<(), Report> {
+        let mut vm = InstrumentedVM::new("../assets/infinite_loop.tr", HashAlgo::Sha1)?
+            .with_step_limit(1000);
+
+        let error = vm.run(39).unwrap_err();
+
+        assert!(is_run_failure(&error));
+        assert!(matches!(
+            error.downcast_ref::<VmError>(),
+            Some(VmError::StepLimitExceeded(1000))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_collatz_step_count() -> Result<(), Report> {
+        let mut vm = InstrumentedVM::new("../assets/collatz_v0.tr", HashAlgo::Sha1)?;
+
+        let result = vm.run(39)?;
+        println!("Steps = {}", result.steps);
+
+        assert_eq!(result.steps, 247);
+        assert_eq!(vm.run(39)?.steps, result.steps);
+
+        Ok(())
+    }
+
+    #[test]
+    fn output_only_run_matches_full_run() -> Result<(), Report> {
+        for program in ["../assets/fib.tr", "../assets/collatz_v0.tr"] {
+            let mut vm = InstrumentedVM::new(program, HashAlgo::Sha1)?;
+
+            let outputs = (3..40)
+                .map(|i| vm.run_output_only(i))
+                .collect::<Result<Vec<_>, _>>()?;
+            let expected = (3..40)
+                .map(|i| vm.run(i).map(|result| result.output))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            assert_eq!(outputs, expected, "{}", program);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sweep_rate_matches_uniform_digest() -> Result<(), Report> {
+        let (data, stats) = get_data("../assets/collatz_v0.tr".into(), 3000, 4000, 144..=159, 5)?;
+        assert_eq!(data.len(), stats.len());
+
+        for stat in stats {
+            println!("{:?}", stat);
+
+            // The valid hashes follow a binomial law of rate expected_p
+            let mean = stat.expected_p * stat.tested as f64;
+            let deviation = (mean * (1.0 - stat.expected_p)).sqrt();
+            assert!((stat.valid as f64 - mean).abs() <= 4.0 * deviation + 1.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sweep_custom_kappa_range() -> Result<(), Report> {
+        let program = || PathBuf::from("../assets/collatz_v0.tr");
+        let (data, stats) = get_data(program(), 100, 200, 150..=152, 3)?;
+
+        let kappas: Vec<_> = data.iter().map(|(kappa, _)| *kappa).collect();
+        assert_eq!(kappas, [150, 151, 152]);
+        assert!(data.iter().all(|(_, values)| values.len() == 200));
+        assert_eq!(stats.len(), 3);
+
+        let error = |kappas, kappa_num| {
+            get_data(program(), 100, 200, kappas, kappa_num)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(150..=160, 3),
+            "Kappa should be lower than the digest size (160), got 160"
+        );
+        assert_eq!(
+            error(150..=152, 1),
+            "At least 2 kappa values are needed, got 1"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn export_csv() -> Result<(), Report> {
+        let (data, _) = get_data("../assets/collatz_v0.tr".into(), 100, 150, 150..=158, 4)?;
+        let mut csv = vec![];
+        write_csv(&data, &mut csv)?;
+
+        let csv = String::from_utf8(csv)?;
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("u,kappa,q"));
+
+        let rows: Vec<(usize, usize, f64)> = lines
+            .map(|line| {
+                let fields: Vec<_> = line.split(',').collect();
+                (
+                    fields[0].parse().unwrap(),
+                    fields[1].parse().unwrap(),
+                    fields[2].parse().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(rows.len(), 4 * 150);
+        assert_eq!(rows[0].0, 1);
+        assert_eq!(rows[599], (150, 158, data[3].1[149]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn replay_matches_run() -> Result<(), Report> {
+        let mut vm = InstrumentedVM::new("../assets/collatz_v0.tr", HashAlgo::Sha256)?;
+
+        let result = vm.run(39)?;
+        let replay = vm.replay(39)?;
+
+        assert_eq!(replay.result, result);
+        assert_eq!(replay.trace.len(), result.steps);
+        assert_eq!(
+            replay.trace.last().map(|step| &step.digest),
+            Some(&result.hash)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn primed_hasher_matches_full_hash() -> Result<(), Report> {
+        let mut vm = InstrumentedVM::new("../assets/collatz_v0.tr", HashAlgo::Sha1)?;
+
+        // Hash the program and the states from scratch
+        let mut reference_vm = Parser::load_program(&"../assets/collatz_v0.tr")?;
+        let program = serde_json::to_string(&reference_vm.instructions())?;
+
+        for input in 1..20 {
+            let mut hasher = Hasher::new(HashAlgo::Sha1);
+            hasher.update(program.as_bytes());
+            reference_vm
+                .run_vm_with_callback((vec![input], vec![]), |s: &[u8]| hasher.update(s))?;
+            reference_vm.reset_state();
+
+            assert_eq!(vm.run(input)?.hash, hasher.finalize());
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,48 @@
+use color_eyre::Report;
+
+use std::{env, process::Command};
+
+#[test]
+fn prove_and_verify_collatz() -> Result<(), Report> {
+    let file = env::temp_dir().join("ckc_cli_collatz.json");
+
+    let prove = Command::new(env!("CARGO_BIN_EXE_ckc"))
+        .args(["prove", "../assets/collatz_v0.tr", "--domain", "1..1001"])
+        .args(["--kappa", "155", "--v", "10", "--strategy", "best-effort"])
+        .arg("--file")
+        .arg(&file)
+        .output()?;
+    println!("{}", String::from_utf8_lossy(&prove.stdout));
+    assert!(prove.status.success());
+
+    let verify = |epsilon: &str| {
+        Command::new(env!("CARGO_BIN_EXE_ckc"))
+            .arg("verify")
+            .arg(&file)
+            .args(["--epsilon", epsilon])
+            .output()
+    };
+
+    // The witnesses are valid, but too few of them to make the claim likely
+    let lenient = verify("1.0")?;
+    let report = String::from_utf8_lossy(&lenient.stdout);
+    println!("{}", report);
+
+    assert!(lenient.status.success());
+    assert!(report.contains("Witnesses check: All witnesses are valid"));
+    assert!(report.contains("Proof is accepted: *true*"));
+
+    let strict = verify("0.01")?;
+    assert!(!strict.status.success());
+    assert!(String::from_utf8_lossy(&strict.stdout).contains("Proof is accepted: *false*"));
+
+    // A proof that does not exist is reported as an error
+    let missing = Command::new(env!("CARGO_BIN_EXE_ckc"))
+        .args(["verify", "missing.json"])
+        .output()?;
+    assert!(!missing.status.success());
+
+    std::fs::remove_file(file)?;
+
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use color_eyre::Report;
+
+use std::{env, fs, process::Command};
+
+#[test]
+fn draw_svg_graph() -> Result<(), Report> {
+    let output = env::temp_dir().join("ckc_draw_collatz.svg");
+
+    let draw = Command::new(env!("CARGO_BIN_EXE_draw"))
+        .args(["../assets/collatz_v0.tr", "200"])
+        .arg("--output")
+        .arg(&output)
+        .output()?;
+    println!("{}", String::from_utf8_lossy(&draw.stdout));
+    assert!(draw.status.success());
+
+    let svg = fs::read_to_string(&output)?;
+    fs::remove_file(&output)?;
+
+    assert!(!svg.is_empty());
+    assert!(svg.contains("<svg"));
+
+    Ok(())
+}
@@ -1,14 +1,20 @@
-use color_eyre::Report;
+#![forbid(unsafe_code)]
+
+use color_eyre::{eyre::eyre, Report};
+use sha1::{Digest, Sha1};
 use structopt::StructOpt;
 use tracing::info;
 
-use std::path::PathBuf;
+use std::{fs, path::PathBuf};
 
 pub mod parser;
 pub mod vm;
 
 use parser::Parser;
-pub use vm::TinyVM;
+pub use vm::{
+    CostModel, Endianness, OverflowMode, ProgramStats, StepRecord, TinyVM, VmError, VmSnapshot,
+    HASH_SCHEME_VERSION,
+};
 
 /// Command line options
 #[derive(Debug, StructOpt)]
@@ -18,8 +24,35 @@ struct Opt {
     program_file: PathBuf,
 
     /// Tape file
-    #[structopt(short, parse(from_os_str))]
+    #[structopt(short, long, parse(from_os_str))]
     tape_file: Option<PathBuf>,
+
+    /// Inline tape value, may be repeated
+    #[structopt(short, long = "input", conflicts_with = "tape-file")]
+    inputs: Vec<usize>,
+
+    /// Write the hex-encoded digest of the run to this file
+    #[structopt(long, parse(from_os_str))]
+    hash_out: Option<PathBuf>,
+
+    /// Compare the digest of the run against the one stored in this file
+    #[structopt(long, parse(from_os_str))]
+    verify_hash: Option<PathBuf>,
+
+    /// Only check that the program is valid, without running it
+    #[structopt(long)]
+    check: bool,
+}
+
+impl Opt {
+    /// Input tape given either as a tape file or as inline values
+    fn input_tape(&self) -> Result<Vec<usize>, Report> {
+        match self.tape_file {
+            Some(ref filename) => Ok(Parser::load_tape_file(filename)?),
+            _ if !self.inputs.is_empty() => Ok(self.inputs.clone()),
+            _ => Err(eyre!("No input given, use --tape-file or --input")),
+        }
+    }
 }
 
 /// Program entry point
@@ -27,29 +60,82 @@ pub fn from_cli() -> Result<(), Report> {
     // Process command-line arguments
     let opt = Opt::from_args();
 
+    if opt.check {
+        println!("{}", check_program(&opt)?);
+    } else {
+        run_cli(&opt)?;
+    }
+
+    Ok(())
+}
+
+/// Check that the selected program parses and can terminate, returning a summary
+fn check_program(opt: &Opt) -> Result<String, Report> {
+    let tinyvm = Parser::load_program(&opt.program_file)?;
+    Parser::check_termination(&tinyvm, true)?;
+
+    Ok(format!("OK: {}", tinyvm.program_stats()))
+}
+
+/// Run the program selected on the command line, returning its output and hex digest
+///
+/// The SHA-1 digest covers the program source, the input tape and every state of the run.
+fn run_cli(opt: &Opt) -> Result<(usize, String), Report> {
     // Create VM
     let mut tinyvm = Parser::load_program(&opt.program_file)?;
 
     // Input handling
-    let input = match opt.tape_file {
-        Some(filename) => Parser::load_tape_file(&filename)?,
-        _ => vec![27],
-    };
+    let input = opt.input_tape()?;
+
+    let mut hasher = Sha1::new();
+    hasher.update(tinyvm.source());
+    for value in &input {
+        hasher.update(value.to_be_bytes());
+    }
 
     // Run program
-    let output = tinyvm.run_vm((input, vec![]))?;
+    let output = tinyvm.run_vm_with_callback((input, vec![]), |s: &[u8]| hasher.update(s))?;
+
+    let hash: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
 
     info!("output: {:?}", output);
+    info!("hash: {}", hash);
 
-    Ok(())
+    if let Some(ref path) = opt.hash_out {
+        fs::write(path, format!("{}\n", hash))?;
+    }
+
+    if let Some(ref path) = opt.verify_hash {
+        let expected = fs::read_to_string(path)?;
+        if expected.trim() != hash {
+            return Err(eyre!(
+                "Hash mismatch: expected {}, got {}",
+                expected.trim(),
+                hash
+            ));
+        }
+    }
+
+    Ok((output, hash))
 }
 
 #[cfg(test)]
 mod tests {
     use sha1::{Digest, Sha1};
 
-    use crate::Parser;
+    use crate::{
+        parser::{Argument, Instruction},
+        run_cli, CostModel, Opt, OverflowMode, Parser, ProgramStats, StepRecord, VmError,
+        VmSnapshot,
+    };
     use color_eyre::Report;
+    use structopt::StructOpt;
+
+    use std::{env, fs, time::Duration};
 
     #[test]
     fn run_fibo() -> Result<(), Report> {
@@ -61,6 +147,42 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn final_state_of_fibo() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/fib.tr"))?;
+        vm.run_vm((vec![39], vec![]))?;
+        println!("Registers = {:?}", vm.registers());
+
+        // r1 and r2 hold the previous terms, r4 the counter
+        assert_eq!(vm.registers(), [39, 63245986, 39088169, 63245986, 39]);
+        assert_eq!(vm.memory(), 63245986usize.to_le_bytes());
+        assert!(vm.flag());
+        assert_eq!(vm.pc(), 14);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_output_region() -> Result<(), Report> {
+        let vm = Parser::load_program(&String::from("../assets/test_outputs.tr"))?;
+        let mut vm = vm.with_output_region(0, 2);
+        let result = vm.run_vm((vec![5], vec![]))?;
+
+        assert_eq!(result, 0);
+        assert_eq!(vm.outputs(), vec![0, 1]);
+
+        let mut vm = vm.with_output_region(1, 3);
+        vm.reset_state();
+        vm.run_vm((vec![7], vec![]))?;
+        assert_eq!(vm.outputs(), vec![1, 0, 0]);
+
+        // Words past the end of the address space read as 0
+        let vm = vm.with_output_region(usize::MAX / 8, 2);
+        assert_eq!(vm.outputs(), vec![0, 0]);
+
+        Ok(())
+    }
+
     #[test]
     fn run_fib_with_callback() -> Result<(), Report> {
         let mut hasher = Sha1::new();
@@ -98,4 +220,803 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn hash_collatz_states() -> Result<(), Report> {
+        let mut hasher = Sha1::new();
+        let update_hash = |s: &[u8]| hasher.update(s);
+
+        let mut vm = Parser::load_program(&String::from("../assets/collatz_v0.tr"))?;
+        vm.run_vm_with_callback((vec![39], vec![]), update_hash)?;
+
+        let hash: String = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        println!("Hash = {}", hash);
+
+        // Digest of the run under version 2 of the hashing scheme
+        assert_eq!(hash, "2415301b7d1778fedf759df205db0a3966badf3c");
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_with_input() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/fib.tr"))?;
+        assert_eq!(vm.run_with_input(vec![10])?, 55);
+        assert_eq!(vm.run_with_input(vec![39])?, 63245986);
+        assert_eq!(vm.run_with_input(vec![10])?, 55);
+        assert_eq!(vm.step_count(), 0);
+
+        // Collatz stores its result in the second word, the output stays 0
+        let mut vm = Parser::load_program(&String::from("../assets/collatz_v0.tr"))?;
+        for input in [1, 8, 27, 39] {
+            assert_eq!(vm.run_with_input(vec![input])?, 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fib_program_stats() -> Result<(), Report> {
+        let vm = Parser::load_program(&String::from("../assets/fib.tr"))?;
+
+        assert_eq!(
+            vm.program_stats(),
+            ProgramStats {
+                instructions: 15,
+                labels: 3,
+                max_register: Some(4),
+                uses_memory: true,
+            }
+        );
+
+        // Registers given as arguments count too
+        let vm =
+            Parser::load_program_str("; TinyRAM V=2.00 M=hv W=64 K=8\nmov r0, r7\nanswer 0\n")?;
+        let stats = vm.program_stats();
+        assert_eq!(stats.max_register, Some(7));
+        assert!(!stats.uses_memory);
+
+        Ok(())
+    }
+
+    #[test]
+    fn collatz_total_cost() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/collatz_v0.tr"))?;
+        vm.run_vm((vec![8], vec![]))?;
+
+        // Setup, three loop iterations then the final check and store
+        assert_eq!(vm.step_count(), 34);
+        assert_eq!(vm.total_cost(), 4 + 3 * 9 + 7);
+
+        let mut vm = vm.with_cost_model(CostModel::uniform(1));
+        vm.reset_state();
+        vm.run_vm((vec![8], vec![]))?;
+        assert_eq!(vm.total_cost(), vm.step_count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn collatz_opcode_histogram() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/collatz_v0.tr"))?;
+        vm.run_vm((vec![3], vec![]))?;
+
+        // Five loop iterations (3 -> 5 -> 8 -> 4 -> 2 -> 1), odd ones also halving
+        let histogram = vm.opcode_histogram();
+        assert_eq!(histogram["shr"], 10);
+        assert_eq!(histogram["add"], 9);
+        assert_eq!(histogram["cmpa"], 6);
+        assert_eq!(histogram["cnjmp"], 5);
+        assert_eq!(histogram["mull"], 2);
+        assert_eq!(histogram.get("udiv"), None);
+        assert_eq!(histogram.values().sum::<usize>(), vm.step_count());
+
+        vm.reset_state();
+        assert!(vm.opcode_histogram().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterate_over_steps() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/collatz_v0.tr"))?;
+        vm.load_tapes((vec![8], vec![]));
+
+        let snapshots = {
+            let mut steps = vm.steps();
+            let snapshots = steps.by_ref().collect::<Result<Vec<_>, _>>()?;
+            assert!(steps.next().is_none());
+            snapshots
+        };
+        let last = snapshots.last().unwrap();
+
+        assert_eq!(snapshots.len(), 34);
+        assert!(snapshots[..33].iter().all(|snapshot| snapshot.running));
+        assert!(!last.running);
+        assert_eq!(last.registers[2], 3);
+        assert_eq!(last.memory[8..16], 3usize.to_le_bytes());
+
+        // The program is not advanced past its answer and keeps its output
+        assert_eq!(vm.step_count(), 34);
+        assert_eq!(vm.output(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reuse_after_reset() -> Result<(), Report> {
+        let fresh = || Parser::load_program(&String::from("../assets/fib.tr"));
+        let mut vm = fresh()?.with_step_limit(1000);
+
+        vm.run_vm((vec![39], vec![]))?;
+        vm.reset_state();
+        assert_eq!(vm.snapshot(), fresh()?.snapshot());
+
+        // A run aborted midway leaves nothing behind either
+        assert!(vm.run_vm((vec![2], vec![])).is_err());
+        vm.reset_state();
+        assert_eq!(vm.snapshot(), fresh()?.snapshot());
+
+        let mut expected = fresh()?;
+        assert_eq!(vm.run_vm((vec![10], vec![]))?, 55);
+        expected.run_vm((vec![10], vec![]))?;
+
+        assert_eq!(vm.snapshot(), expected.snapshot());
+        assert_eq!(vm.step_count(), expected.step_count());
+        assert_eq!(vm.total_cost(), expected.total_cost());
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_infinite_loop_with_timeout() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/infinite_loop.tr"))?;
+        let result = vm.run_with_timeout(Duration::from_millis(10), |_: &[u8]| {});
+
+        assert_eq!(result.unwrap_err().to_string(), "timeout");
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_invalid_tape() {
+        let result = Parser::load_tape_file(&String::from("../assets/invalid.tape"));
+        let error = result.unwrap_err().to_string();
+
+        assert_eq!(error, "Line 3: Invalid tape value '3x'");
+    }
+
+    #[test]
+    fn load_unreadable_tape() -> Result<(), Report> {
+        let path = env::temp_dir().join("tinyvm_non_utf8.tape");
+        fs::write(&path, b"1\n\xff\n3\n")?;
+
+        let result = Parser::load_tape_file(&path);
+        fs::remove_file(&path)?;
+
+        let error = result.unwrap_err().to_string();
+        assert_eq!(error, "Line 2: Could not read the tape");
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_store_and_load() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_load.tr"))?;
+        let result = vm.run_vm((vec![], vec![]))?;
+
+        assert_eq!(result, 42);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_sub_with_borrow() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_sub.tr"))?;
+        let result = vm.run_vm((vec![], vec![]))?;
+
+        assert_eq!(result, 254);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_infinite_loop_with_step_limit() -> Result<(), Report> {
+        let vm = Parser::load_program(&String::from("../assets/infinite_loop.tr"))?;
+        let mut vm = vm.with_step_limit(1000);
+        let result = vm.run_vm((vec![], vec![]));
+
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<VmError>(),
+            Some(&VmError::StepLimitExceeded(1000))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_store_byte() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_loadb.tr"))?;
+        let result = vm.run_vm((vec![], vec![]))?;
+
+        assert_eq!(result, 0x1122_3344_5566_ab88);
+
+        Ok(())
+    }
+
+    #[test]
+    fn disassemble_round_trip() -> Result<(), Report> {
+        for program in ["collatz_v0", "fib", "test_loadb"] {
+            let vm = Parser::load_program(&format!("../assets/{}.tr", program))?;
+            let source = vm.disassemble();
+            println!("{}", source);
+
+            let reparsed = Parser::load_program_str(&source)?;
+
+            assert_eq!(reparsed.instructions(), vm.instructions());
+            assert_eq!(reparsed.disassemble(), source);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_with_private_tape() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_tapes.tr"))?;
+        let result = vm.run_vm((vec![1, 3], vec![2, 4]))?;
+
+        assert_eq!(result, 0x1234);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_tape_in_order() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_fifo.tr"))?;
+        let result = vm.run_vm((vec![10, 20, 30], vec![]))?;
+
+        assert_eq!(result, 10_20_30);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_self_modifying_program() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_vn.tr"))?;
+        let result = vm.run_vm((vec![], vec![]))?;
+
+        assert_eq!(result, 42);
+
+        // The original program is restored on reset
+        vm.reset_state();
+        assert_eq!(vm.run_vm((vec![], vec![]))?, 42);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_patched_smulh() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_vn_smulh.tr"))?;
+        vm.run_vm((vec![], vec![]))?;
+
+        // The high word of -15 is all ones, and -15 fits in a signed word
+        assert_eq!(vm.registers()[1], usize::MAX);
+        assert!(!vm.flag());
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_out_of_bounds() -> Result<(), Report> {
+        let vm = Parser::load_program(&String::from("../assets/test_store_oob.tr"))?;
+        let mut vm = vm.with_max_memory(1 << 20);
+        let result = vm.run_vm((vec![], vec![]));
+
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<VmError>(),
+            Some(&VmError::MemoryOutOfBounds {
+                address: 4_000_000_000,
+                max_memory: 1 << 20
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn jump_out_of_bounds() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_jmp_oob.tr"))?;
+        let result = vm.run_vm((vec![], vec![]));
+
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<VmError>(),
+            Some(&VmError::JumpOutOfBounds {
+                target: 9999,
+                program_size: 3
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn segfault_past_program_end() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_segfault.tr"))?;
+        assert_eq!(vm.run_vm((vec![3], vec![]))?, 0);
+        vm.reset_state();
+
+        // Running off the end is an error, not an answer with error code 1
+        let error = vm.run_vm((vec![0], vec![])).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<VmError>(),
+            Some(&VmError::Segfault {
+                pc: 7,
+                program_len: 7
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn mask_immediates_to_word_size() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_imm_mask.tr"))?;
+        let result = vm.run_vm((vec![], vec![]))?;
+
+        assert_eq!(result, 44);
+
+        Ok(())
+    }
+
+    #[test]
+    fn arithmetic_flags_conformance() -> Result<(), Report> {
+        // (opcode, operand 1, operand 2, result, flag) with 8-bit words
+        let table = [
+            ("add", 100, 27, 127, false),
+            ("add", 100, 28, 128, false),
+            ("add", 200, 100, 44, true),
+            ("add", 255, 1, 0, true),
+            ("add", 0, 0, 0, false),
+            ("sub", 5, 3, 2, false),
+            ("sub", 3, 5, 254, true),
+            ("sub", 128, 1, 127, false),
+            ("sub", 0, 0, 0, false),
+            ("mull", 15, 17, 255, false),
+            ("mull", 16, 16, 0, true),
+            ("mull", 200, 2, 144, true),
+            ("mull", 0, 255, 0, false),
+        ];
+
+        for (opcode, a, b, result, flag) in table {
+            let source = format!(
+                "; TinyRAM V=2.00 M=hv W=8 K=2\n\
+                 mov r0, {}\n\
+                 {} r1, r0, {}\n\
+                 store.w 0, r1\n\
+                 answer 0\n",
+                a, opcode, b
+            );
+            let mut vm = Parser::load_program_str(&source)?;
+            vm.run_vm((vec![], vec![]))?;
+
+            let case = format!("{} {}, {}", opcode, a, b);
+            assert_eq!(vm.registers()[1], result, "{}", case);
+            assert_eq!(vm.flag(), flag, "{}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn wide_products() -> Result<(), Report> {
+        // (word size, opcode, operand 1, operand 2, result, flag)
+        let table = [
+            (8, "umulh", 15_usize, 17_usize, 0, false),
+            (8, "umulh", 200, 2, 1, true),
+            (32, "mull", 0xffff_ffff, 0xffff_ffff, 1, true),
+            (32, "umulh", 0xffff_ffff, 0xffff_ffff, 0xffff_fffe, true),
+            (32, "mull", 0x1_0000, 0xffff, 0xffff_0000, false),
+            (64, "mull", usize::MAX, 3, usize::MAX - 2, true),
+            (64, "umulh", usize::MAX, 3, 2, true),
+            (64, "umulh", 1 << 32, 1 << 32, 1, true),
+            (8, "smulh", -5_i64 as usize, 3, 0xff, false),
+            (8, "smulh", -128_i64 as usize, -128_i64 as usize, 0x40, true),
+            (32, "smulh", 0x7fff_ffff, 2, 0, true),
+            (64, "smulh", -1_i64 as usize, -1_i64 as usize, 0, false),
+            (64, "smulh", i64::MIN as usize, 2, usize::MAX, true),
+        ];
+
+        for (word_size, opcode, a, b, result, flag) in table {
+            let source = format!(
+                "; TinyRAM V=2.00 M=hv W={} K=3\n\
+                 mov r0, {}\n\
+                 mov r2, {}\n\
+                 {} r1, r0, r2\n\
+                 store.w 0, r1\n\
+                 answer 0\n",
+                word_size, a as i64, b as i64, opcode
+            );
+            let mut vm = Parser::load_program_str(&source)?;
+            vm.run_vm((vec![], vec![]))?;
+
+            let case = format!("W={} {} {}, {}", word_size, opcode, a, b);
+            assert_eq!(vm.registers()[1], result, "{}", case);
+            assert_eq!(vm.flag(), flag, "{}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn not_masks_to_word_size() -> Result<(), Report> {
+        // (operand, result, flag) with 8-bit words
+        for (a, result, flag) in [(0, 255, false), (255, 0, true), (0x0f, 0xf0, false)] {
+            let source = format!(
+                "; TinyRAM V=2.00 M=hv W=8 K=2\n\
+                 not r0, {}\n\
+                 store.w 0, r0\n\
+                 answer 0\n",
+                a
+            );
+            let mut vm = Parser::load_program_str(&source)?;
+            vm.run_vm((vec![], vec![]))?;
+
+            assert_eq!(vm.registers()[0], result, "not {}", a);
+            assert_eq!(vm.flag(), flag, "not {}", a);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn shift_amount_bounds() -> Result<(), Report> {
+        // (word size, opcode, value, amount, result, flag)
+        let table = [
+            (8, "shl", 0x81, 0, 0x81, true),
+            (8, "shl", 0x81, 1, 0x02, false),
+            (8, "shl", 0x81, 8, 0, false),
+            (8, "shl", 0x81, 9, 0, false),
+            (8, "shr", 0x81, 0, 0x81, true),
+            (8, "shr", 0x81, 8, 0, false),
+            (8, "shr", 0x81, 200, 0, false),
+            (64, "shl", 1, 63, 1_usize << 63, true),
+            (64, "shl", 1, 64, 0, false),
+            (64, "shl", 1, 100, 0, false),
+            (64, "shr", 1, 64, 0, false),
+        ];
+
+        for (word_size, opcode, a, b, result, flag) in table {
+            let source = format!(
+                "; TinyRAM V=2.00 M=hv W={} K=2\n\
+                 mov r0, {}\n\
+                 {} r1, r0, {}\n\
+                 store.w 0, r1\n\
+                 answer 0\n",
+                word_size, a, opcode, b
+            );
+            let mut vm = Parser::load_program_str(&source)?;
+            vm.run_vm((vec![], vec![]))?;
+
+            let case = format!("W={} {} {}, {}", word_size, opcode, a, b);
+            assert_eq!(vm.registers()[1], result, "{}", case);
+            assert_eq!(vm.flag(), flag, "{}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn overflow_modes() -> Result<(), Report> {
+        let run_op = |mode, opcode: &str, a: usize, b: usize| {
+            let source = format!(
+                "; TinyRAM V=2.00 M=hv W=8 K=2\n\
+                 mov r0, {}\n\
+                 {} r1, r0, {}\n\
+                 store.w 0, r1\n\
+                 answer 0\n",
+                a, opcode, b
+            );
+            let mut vm = Parser::load_program_str(&source)?.with_overflow_mode(mode);
+            let result = vm.run_vm((vec![], vec![]));
+            result.map(|_| (vm.registers()[1], vm.flag()))
+        };
+        let run = |mode, a, b| run_op(mode, "add", a, b);
+
+        assert_eq!(run(OverflowMode::Wrap, 200, 100)?, (44, true));
+        assert_eq!(run(OverflowMode::Saturate, 200, 100)?, (255, true));
+        let error = run(OverflowMode::Trap, 200, 100).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<VmError>(),
+            Some(&VmError::Overflow {
+                pc: 1,
+                opcode: "add"
+            })
+        );
+
+        // Sums that fit in the word are the same in every mode
+        for mode in [
+            OverflowMode::Wrap,
+            OverflowMode::Saturate,
+            OverflowMode::Trap,
+        ] {
+            assert_eq!(run(mode, 200, 55)?, (255, false));
+        }
+
+        // The other arithmetic instructions follow the same mode
+        assert_eq!(run_op(OverflowMode::Saturate, "sub", 3, 5)?, (0, true));
+        assert_eq!(run_op(OverflowMode::Saturate, "mull", 20, 20)?, (255, true));
+        assert_eq!(run_op(OverflowMode::Saturate, "shl", 0x41, 2)?, (255, true));
+        assert_eq!(run_op(OverflowMode::Trap, "shl", 0x21, 2)?, (0x84, true));
+        assert!(run_op(OverflowMode::Trap, "shl", 1, 8).is_err());
+        assert_eq!(run_op(OverflowMode::Trap, "shl", 0, 8)?, (0, false));
+
+        Ok(())
+    }
+
+    #[test]
+    fn comparisons_conformance() -> Result<(), Report> {
+        // (opcode, register, argument, flag) with 8-bit words, 255 being -1 when signed
+        let table = [
+            ("cmpe", 4, 4, true),
+            ("cmpe", 3, 5, false),
+            ("cmpe", 5, 3, false),
+            ("cmpa", 4, 4, false),
+            ("cmpa", 3, 5, false),
+            ("cmpa", 5, 3, true),
+            ("cmpa", 255, 1, true),
+            ("cmpa", 1, 255, false),
+            ("cmpae", 4, 4, true),
+            ("cmpae", 3, 5, false),
+            ("cmpae", 5, 3, true),
+            ("cmpae", 255, 1, true),
+            ("cmpg", 4, 4, false),
+            ("cmpg", 3, 5, false),
+            ("cmpg", 5, 3, true),
+            ("cmpg", 255, 1, false),
+            ("cmpg", 1, 255, true),
+            ("cmpg", 128, 127, false),
+            ("cmpge", 4, 4, true),
+            ("cmpge", 3, 5, false),
+            ("cmpge", 5, 3, true),
+            ("cmpge", 255, 255, true),
+            ("cmpge", 255, 1, false),
+            ("cmpge", 1, 255, true),
+        ];
+
+        for (opcode, a, b, flag) in table {
+            let source = format!(
+                "; TinyRAM V=2.00 M=hv W=8 K=2\n\
+                 mov r0, {}\n\
+                 {} r0, {}\n\
+                 store.w 0, r0\n\
+                 answer 0\n",
+                a, opcode, b
+            );
+            let mut vm = Parser::load_program_str(&source)?;
+            vm.run_vm((vec![], vec![]))?;
+
+            assert_eq!(vm.flag(), flag, "{} {}, {}", opcode, a, b);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_signed_operands() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_signed_cmp.tr"))?;
+        let result = vm.run_vm((vec![], vec![]))?;
+
+        assert_eq!(result, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_step_fib() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/fib.tr"))?;
+        vm.load_tapes((vec![39], vec![]));
+
+        let initial = vm.snapshot();
+        assert_eq!(initial.pc, 0);
+        assert_eq!(initial.tape_remaining, (1, 0));
+
+        // read r0, 0
+        assert!(vm.single_step()?);
+        let snapshot = vm.snapshot();
+        assert_eq!(
+            snapshot,
+            VmSnapshot {
+                running: true,
+                pc: 1,
+                flag: false,
+                registers: vec![39, 0, 0, 0, 0],
+                memory: vec![],
+                tape_remaining: (0, 0),
+            }
+        );
+
+        // mov r3, 1
+        assert!(vm.single_step()?);
+        let snapshot = vm.snapshot();
+        assert_eq!(snapshot.pc, 2);
+        assert_eq!(snapshot.registers, vec![39, 0, 0, 1, 0]);
+
+        // cmpa r0, 1
+        assert!(vm.single_step()?);
+        let snapshot = vm.snapshot();
+        assert_eq!(snapshot.pc, 3);
+        assert!(snapshot.flag);
+        assert_eq!(snapshot.registers, vec![39, 0, 0, 1, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pause_on_breakpoint() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/fib.tr"))?;
+        vm.load_tapes((vec![39], vec![]));
+        vm.add_breakpoint_label("_loop")?;
+        assert_eq!(vm.breakpoints().len(), 1);
+
+        // Setup is done when entering the loop
+        assert!(vm.run_until_breakpoint()?);
+        let snapshot = vm.snapshot();
+        assert_eq!(snapshot.pc, 7);
+        assert_eq!(snapshot.registers, vec![39, 1, 1, 1, 2]);
+
+        // One iteration later
+        assert!(vm.run_until_breakpoint()?);
+        assert_eq!(vm.snapshot().registers, vec![39, 2, 1, 2, 3]);
+
+        // Without breakpoints the program runs to completion
+        vm.clear_breakpoints();
+        assert!(!vm.run_until_breakpoint()?);
+        assert_eq!(vm.output(), 63245986);
+
+        // A terminated program is not restarted
+        let (snapshot, steps) = (vm.snapshot(), vm.step_count());
+        assert!(!vm.single_step()?);
+        assert!(!vm.run_until_breakpoint()?);
+        assert_eq!(vm.snapshot(), snapshot);
+        assert_eq!(vm.step_count(), steps);
+        assert_eq!(vm.output(), 63245986);
+
+        assert!(vm.add_breakpoint_label("_missing").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn stop_stepping_after_fault() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/test_jmp_oob.tr"))?;
+
+        // mov r0, 9999
+        assert!(vm.single_step()?);
+        // jmp r0
+        let error = vm.single_step().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VmError>(),
+            Some(VmError::JumpOutOfBounds { target: 9999, .. })
+        ));
+
+        // The faulting jump is not executed again
+        let (snapshot, steps) = (vm.snapshot(), vm.step_count());
+        assert!(!snapshot.running);
+        assert!(!vm.single_step()?);
+        assert!(!vm.run_until_breakpoint()?);
+        assert_eq!(vm.snapshot(), snapshot);
+        assert_eq!(vm.step_count(), steps);
+
+        // Resetting the state allows stepping again
+        vm.reset_state();
+        assert!(vm.single_step()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn trace_collatz() -> Result<(), Report> {
+        let mut vm = Parser::load_program(&String::from("../assets/collatz_v0.tr"))?;
+        let mut trace = vec![];
+        let result = vm.run_vm_with_trace(
+            (vec![8], vec![]),
+            |_: &[u8]| {},
+            |record| trace.push(record.clone()),
+        )?;
+        println!("Trace length = {}", trace.len());
+
+        assert_eq!(result, 0);
+        assert_eq!(trace.len(), 34);
+        assert_eq!(trace.len(), vm.step_count());
+
+        // 8 -> 4 -> 2 -> 1 takes three updates of the counter
+        assert_eq!(
+            trace.last(),
+            Some(&StepRecord {
+                step: 34,
+                pc: 16,
+                instr: Instruction::Answer(Argument::Imm(0)),
+                flag: false,
+                registers: vec![1, 0, 3],
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_inline_inputs() -> Result<(), Report> {
+        let opt = Opt::from_iter(["tinyvm", "../assets/fib.tr", "--input", "39", "-i", "2"]);
+        assert_eq!(opt.input_tape()?, vec![39, 2]);
+
+        let tape = [
+            "tinyvm",
+            "../assets/collatz_v0.tr",
+            "--tape-file",
+            "../assets/collatz.tape",
+        ];
+        let opt = Opt::from_iter(tape);
+        assert_eq!(
+            opt.input_tape()?,
+            Parser::load_tape_file(&"../assets/collatz.tape")?
+        );
+
+        // Running without any input is an explicit error
+        let opt = Opt::from_iter(["tinyvm", "../assets/fib.tr"]);
+        assert_eq!(
+            opt.input_tape().unwrap_err().to_string(),
+            "No input given, use --tape-file or --input"
+        );
+
+        let both = ["tinyvm", "../assets/fib.tr", "-t", "none.tape", "-i", "1"];
+        assert!(Opt::from_iter_safe(both).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_verify_hash() -> Result<(), Report> {
+        let path = env::temp_dir().join("tinyvm_fib.sha1");
+        let path = path.to_str().unwrap();
+
+        let opt = Opt::from_iter(["tinyvm", "../assets/fib.tr", "-i", "39", "--hash-out", path]);
+        let (output, hash) = run_cli(&opt)?;
+        println!("Hash = {}", hash);
+
+        assert_eq!(output, 63245986);
+        assert_eq!(fs::read_to_string(path)?, format!("{}\n", hash));
+
+        let opt = Opt::from_iter([
+            "tinyvm",
+            "../assets/fib.tr",
+            "-i",
+            "39",
+            "--verify-hash",
+            path,
+        ]);
+        assert_eq!(run_cli(&opt)?.1, hash);
+
+        // Another input gives another digest
+        let opt = Opt::from_iter([
+            "tinyvm",
+            "../assets/fib.tr",
+            "-i",
+            "38",
+            "--verify-hash",
+            path,
+        ]);
+        let error = run_cli(&opt).unwrap_err().to_string();
+        assert!(error.starts_with("Hash mismatch"));
+
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,7 @@
+#![forbid(unsafe_code)]
+
+use color_eyre::Report;
+
+fn main() -> Result<(), Report> {
+    tinyvm::from_cli()
+}
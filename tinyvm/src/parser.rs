@@ -1,24 +1,175 @@
-use color_eyre::{
-    eyre::{eyre, WrapErr},
-    Help, Report,
-};
+use color_eyre::{eyre::WrapErr, Report};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::{info, warn};
 
 use std::{
     collections::HashMap,
-    fmt::Debug,
-    fs::File,
+    error::Error,
+    fmt::{self, Debug},
+    fs::{self, File},
     io::{self, BufRead},
     path::Path,
 };
 
 use crate::vm::TinyVM;
 
+/// Errors raised while parsing a `TinyRAM` program
+#[derive(Debug)]
+pub enum ParseError {
+    /// The program file could not be read
+    Io(io::Error),
+    /// The program file is empty or only holds whitespace
+    EmptyProgram,
+    /// The header line is missing or malformed
+    BadHeader(String),
+    /// The version of the tinyRAM spec is not supported
+    UnsupportedVersion(f32),
+    /// The word size is not a power of two divisible by 8, or exceeds 64
+    InvalidWordSize(u16),
+    /// The architecture of the VM is unknown
+    UnknownArch,
+    /// A label is defined more than once
+    DuplicateLabel { line: usize, ident: String },
+    /// A label is used but never defined
+    UndefinedLabel(String),
+    /// A constant is defined twice or shares its name with a label
+    DuplicateConstant { line: usize, ident: String },
+    /// A constant is used before its definition
+    UndefinedConstant { line: usize, ident: String },
+    /// A line is neither an instruction, a label nor a comment
+    InvalidInstruction { line: usize, text: String },
+    /// A line looks like an instruction but its opcode is unknown
+    UnknownOpcode { line: usize, opcode: String },
+    /// A known opcode is given the wrong number of operands
+    WrongArity {
+        line: usize,
+        opcode: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// The operands of a known opcode are malformed, starting at the given byte offset
+    InvalidOperands {
+        line: usize,
+        opcode: String,
+        text: String,
+        column: usize,
+    },
+    /// A register index exceeds the number of registers
+    RegisterOutOfRange(u16),
+    /// An immediate jump target is outside of the program
+    JumpOutOfRange { target: i64, program_size: usize },
+    /// A macro definition or invocation is malformed
+    InvalidMacro { line: usize, reason: String },
+    /// The program holds no instruction after its header
+    NoInstructions,
+    /// No answer instruction can be reached from the start of the program
+    NoReachableAnswer,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Cannot read program: {}", e),
+            Self::EmptyProgram => write!(f, "The program is empty"),
+            Self::BadHeader(reason) => write!(
+                f,
+                "Line 1: Incorrect parameters ({}), the first line should be \
+                 '; TinyRAM V=[version] M=[arch] W=[wordsize] K=[registers]'",
+                reason
+            ),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported version: {}", version),
+            Self::InvalidWordSize(word_size) => write!(
+                f,
+                "Word size should be a power of two divisible by 8, at most 64, got {}",
+                word_size
+            ),
+            Self::UnknownArch => write!(f, "Unknown VM architecture"),
+            Self::DuplicateLabel { line, ident } => {
+                write!(f, "Line {}: Duplicate label: '{}'", line, ident)
+            }
+            Self::UndefinedLabel(ident) => write!(f, "Undefined label '{}'", ident),
+            Self::DuplicateConstant { line, ident } => {
+                write!(f, "Line {}: Duplicate constant: '{}'", line, ident)
+            }
+            Self::UndefinedConstant { line, ident } => write!(
+                f,
+                "Constant '{}' is used before its definition at line {}",
+                ident, line
+            ),
+            Self::InvalidInstruction { line, text } => {
+                write!(f, "Line {}: Invalid content '{}'", line, text)
+            }
+            Self::UnknownOpcode { line, opcode } => {
+                write!(f, "Line {}: Unknown opcode '{}'", line, opcode)
+            }
+            Self::WrongArity {
+                line,
+                opcode,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Line {}: '{}' expects {} operands, got {}",
+                line, opcode, expected, actual
+            ),
+            Self::InvalidOperands {
+                line,
+                opcode,
+                text,
+                column,
+            } => {
+                let width = text[*column..]
+                    .find(|c: char| c.is_whitespace() || c == ',')
+                    .unwrap_or(text.len() - column)
+                    .max(1);
+                write!(
+                    f,
+                    "Line {}: Invalid operands for '{}' in '{}'\n    {}\n    {}{}",
+                    line,
+                    opcode,
+                    text,
+                    text,
+                    " ".repeat(*column),
+                    "^".repeat(width)
+                )
+            }
+            Self::RegisterOutOfRange(index) => write!(f, "Register 'r{}' does not exist", index),
+            Self::JumpOutOfRange {
+                target,
+                program_size,
+            } => write!(
+                f,
+                "Jump target {} is outside of the program of {} instructions",
+                target, program_size
+            ),
+            Self::InvalidMacro { line, reason } => {
+                write!(f, "Line {}: Invalid macro ({})", line, reason)
+            }
+            Self::NoInstructions => write!(f, "The program has no instruction"),
+            Self::NoReachableAnswer => write!(f, "No answer instruction is reachable"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 /// Defines a register
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Register {
     /// Register index
     pub index: u16,
@@ -53,10 +204,34 @@ pub struct Params {
     pub registers: u16,
     /// M parameter: chitecture type of the VM
     pub arch: ArchType,
+    /// Maximum size of the memory, in words
+    pub max_memory: usize,
+}
+
+impl Default for Params {
+    /// Params of header-less programs: V=2.00 M=hv W=32 K=16
+    fn default() -> Self {
+        Self {
+            version: 2.0,
+            word_size: 32,
+            registers: 16,
+            arch: ArchType::Harvard,
+            max_memory: Self::addressable_words(32),
+        }
+    }
+}
+
+impl Params {
+    /// Number of words addressable with the given word size
+    pub fn addressable_words(word_size: u16) -> usize {
+        let bytes = 1usize.checked_shl(word_size.into()).unwrap_or(usize::MAX);
+
+        bytes / usize::from((word_size / 8).max(1))
+    }
 }
 
 /// Enum encompassing all value types
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Argument {
     /// Value
     Imm(i64),
@@ -67,7 +242,7 @@ pub enum Argument {
 }
 
 /// Enum listing all instructions of the `TinyRAM` VM
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Instruction {
     And(Register, Register, Argument),
     Or(Register, Register, Argument),
@@ -105,7 +280,235 @@ pub enum Instruction {
     Answer(Argument),
 }
 
-/// Parser form the `TinyRAM` programs
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arch = match self.arch {
+            ArchType::Harvard => "hv",
+            ArchType::VonNeumann => "vn",
+            ArchType::Unknown => "??",
+        };
+
+        write!(
+            f,
+            "; TinyRAM V={:.2} M={} W={} K={}",
+            self.version, arch, self.word_size, self.registers
+        )
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "r{}", self.index)
+    }
+}
+
+impl fmt::Display for Argument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Imm(value) => write!(f, "{}", value),
+            Self::Reg(reg) => write!(f, "{}", reg),
+            Self::Label(ident) => write!(f, "{}", ident),
+        }
+    }
+}
+
+impl Instruction {
+    /// Return the opcode of the instruction as written in programs
+    pub const fn opcode(&self) -> &'static str {
+        Self::OPCODES[self.index()]
+    }
+
+    /// Return the position of the opcode of the instruction in `OPCODES`
+    pub const fn index(&self) -> usize {
+        match self {
+            Self::And(..) => 0,
+            Self::Or(..) => 1,
+            Self::Xor(..) => 2,
+            Self::Not(..) => 3,
+            Self::Add(..) => 4,
+            Self::Sub(..) => 5,
+            Self::MulL(..) => 6,
+            Self::UMulH(..) => 7,
+            Self::SMulH(..) => 8,
+            Self::UDiv(..) => 9,
+            Self::UMod(..) => 10,
+            Self::Shl(..) => 11,
+            Self::Shr(..) => 12,
+            Self::CmpE(..) => 13,
+            Self::CmpA(..) => 14,
+            Self::CmpAE(..) => 15,
+            Self::CmpG(..) => 16,
+            Self::CmpGE(..) => 17,
+            Self::Mov(..) => 18,
+            Self::CMov(..) => 19,
+            Self::Jmp(..) => 20,
+            Self::CJmp(..) => 21,
+            Self::CnJmp(..) => 22,
+            Self::StoreB(..) => 23,
+            Self::StoreW(..) => 24,
+            Self::LoadB(..) => 25,
+            Self::LoadW(..) => 26,
+            Self::Read(..) => 27,
+            Self::Answer(..) => 28,
+        }
+    }
+
+    /// Opcodes of all instructions, in the order used to encode them
+    pub const OPCODES: [&'static str; 29] = [
+        "and", "or", "xor", "not", "add", "sub", "mull", "umulh", "smulh", "udiv", "umod", "shl",
+        "shr", "cmpe", "cmpa", "cmpae", "cmpg", "cmpge", "mov", "cmov", "jmp", "cjmp", "cnjmp",
+        "store.b", "store.w", "load.b", "load.w", "read", "answer",
+    ];
+
+    /// Number of operands of each opcode, in the order of `OPCODES`
+    pub const ARITIES: [usize; 29] = [
+        3, 3, 3, 2, 3, 3, 3, 3, 3, 3, 3, 3, 3, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 1,
+    ];
+
+    /// Return the number of operands expected by an opcode, if it exists
+    pub fn arity(opcode: &str) -> Option<usize> {
+        Self::OPCODES
+            .iter()
+            .position(|&known| known == opcode)
+            .map(|idx| Self::ARITIES[idx])
+    }
+
+    /// Build an instruction from its opcode, registers and argument
+    pub fn from_parts(opcode: &str, regs: &[Register], arg: Argument) -> Option<Self> {
+        let instr = match regs {
+            [] => match opcode {
+                "jmp" => Self::Jmp(arg),
+                "cjmp" => Self::CJmp(arg),
+                "cnjmp" => Self::CnJmp(arg),
+                "answer" => Self::Answer(arg),
+                _ => return None,
+            },
+            [reg] => {
+                let reg = reg.clone();
+                match opcode {
+                    "not" => Self::Not(reg, arg),
+                    "cmpe" => Self::CmpE(reg, arg),
+                    "cmpa" => Self::CmpA(reg, arg),
+                    "cmpae" => Self::CmpAE(reg, arg),
+                    "cmpg" => Self::CmpG(reg, arg),
+                    "cmpge" => Self::CmpGE(reg, arg),
+                    "mov" => Self::Mov(reg, arg),
+                    "cmov" => Self::CMov(reg, arg),
+                    "load.b" => Self::LoadB(reg, arg),
+                    "load.w" => Self::LoadW(reg, arg),
+                    "read" => Self::Read(reg, arg),
+                    "store.b" => Self::StoreB(arg, reg),
+                    "store.w" => Self::StoreW(arg, reg),
+                    _ => return None,
+                }
+            }
+            [reg1, reg2] => {
+                let (reg1, reg2) = (reg1.clone(), reg2.clone());
+                match opcode {
+                    "and" => Self::And(reg1, reg2, arg),
+                    "or" => Self::Or(reg1, reg2, arg),
+                    "xor" => Self::Xor(reg1, reg2, arg),
+                    "add" => Self::Add(reg1, reg2, arg),
+                    "sub" => Self::Sub(reg1, reg2, arg),
+                    "mull" => Self::MulL(reg1, reg2, arg),
+                    "umulh" => Self::UMulH(reg1, reg2, arg),
+                    "smulh" => Self::SMulH(reg1, reg2, arg),
+                    "udiv" => Self::UDiv(reg1, reg2, arg),
+                    "umod" => Self::UMod(reg1, reg2, arg),
+                    "shl" => Self::Shl(reg1, reg2, arg),
+                    "shr" => Self::Shr(reg1, reg2, arg),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        Some(instr)
+    }
+
+    /// Split the instruction into its registers and argument
+    pub fn parts(&self) -> (Vec<Register>, Argument) {
+        match self {
+            Self::Jmp(arg) | Self::CJmp(arg) | Self::CnJmp(arg) | Self::Answer(arg) => {
+                (vec![], arg.clone())
+            }
+            Self::Not(reg, arg)
+            | Self::Mov(reg, arg)
+            | Self::CMov(reg, arg)
+            | Self::LoadB(reg, arg)
+            | Self::LoadW(reg, arg)
+            | Self::Read(reg, arg)
+            | Self::CmpE(reg, arg)
+            | Self::CmpGE(reg, arg)
+            | Self::CmpG(reg, arg)
+            | Self::CmpA(reg, arg)
+            | Self::CmpAE(reg, arg)
+            | Self::StoreB(arg, reg)
+            | Self::StoreW(arg, reg) => (vec![reg.clone()], arg.clone()),
+            Self::And(reg1, reg2, arg)
+            | Self::Or(reg1, reg2, arg)
+            | Self::Xor(reg1, reg2, arg)
+            | Self::Add(reg1, reg2, arg)
+            | Self::Sub(reg1, reg2, arg)
+            | Self::MulL(reg1, reg2, arg)
+            | Self::UMulH(reg1, reg2, arg)
+            | Self::SMulH(reg1, reg2, arg)
+            | Self::UDiv(reg1, reg2, arg)
+            | Self::UMod(reg1, reg2, arg)
+            | Self::Shl(reg1, reg2, arg)
+            | Self::Shr(reg1, reg2, arg) => (vec![reg1.clone(), reg2.clone()], arg.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opcode = self.opcode();
+
+        match self {
+            Self::Jmp(arg) | Self::CJmp(arg) | Self::CnJmp(arg) | Self::Answer(arg) => {
+                write!(f, "{} {}", opcode, arg)
+            }
+            // For store instructions arguments are swapped
+            Self::StoreB(arg, reg) | Self::StoreW(arg, reg) => {
+                write!(f, "{} {}, {}", opcode, arg, reg)
+            }
+            Self::Not(reg, arg)
+            | Self::Mov(reg, arg)
+            | Self::CMov(reg, arg)
+            | Self::LoadB(reg, arg)
+            | Self::LoadW(reg, arg)
+            | Self::Read(reg, arg)
+            | Self::CmpE(reg, arg)
+            | Self::CmpGE(reg, arg)
+            | Self::CmpG(reg, arg)
+            | Self::CmpA(reg, arg)
+            | Self::CmpAE(reg, arg) => write!(f, "{} {}, {}", opcode, reg, arg),
+            Self::And(reg1, reg2, arg)
+            | Self::Or(reg1, reg2, arg)
+            | Self::Xor(reg1, reg2, arg)
+            | Self::Add(reg1, reg2, arg)
+            | Self::Sub(reg1, reg2, arg)
+            | Self::MulL(reg1, reg2, arg)
+            | Self::UMulH(reg1, reg2, arg)
+            | Self::SMulH(reg1, reg2, arg)
+            | Self::UDiv(reg1, reg2, arg)
+            | Self::UMod(reg1, reg2, arg)
+            | Self::Shl(reg1, reg2, arg)
+            | Self::Shr(reg1, reg2, arg) => write!(f, "{} {}, {}, {}", opcode, reg1, reg2, arg),
+        }
+    }
+}
+
+/// Macro defined in a program
+struct Macro {
+    /// Patterns matching each parameter in the body
+    params: Vec<Regex>,
+    /// Lines of the body
+    body: Vec<String>,
+}
+
+/// Parser for the `TinyRAM` programs
 pub struct Parser;
 
 impl Parser {
@@ -118,9 +521,13 @@ impl Parser {
 
         let lines = Self::read_lines(filename)?;
         let mut tape = vec![];
-        for (_idx, line) in lines.enumerate() {
-            let line = line.unwrap();
-            let value = line.parse::<u64>()? as usize;
+        for (idx, line) in lines.enumerate() {
+            let line =
+                line.wrap_err_with(|| format!("Line {}: Could not read the tape", idx + 1))?;
+            let value = line
+                .parse::<u64>()
+                .wrap_err_with(|| format!("Line {}: Invalid tape value '{}'", idx + 1, line))?
+                as usize;
             tape.push(value);
         }
 
@@ -130,29 +537,120 @@ impl Parser {
     }
 
     /// Parse `TinyRAM` program into a `TinyRAM` VM
-    pub fn load_program<P>(filename: &P) -> Result<TinyVM, Report>
+    pub fn load_program<P>(filename: &P) -> Result<TinyVM, ParseError>
+    where
+        P: AsRef<Path> + Debug,
+    {
+        info!("Processing file {:?}", filename.as_ref());
+        let source = fs::read_to_string(filename)?;
+
+        Self::load_program_str(&source)
+    }
+
+    /// Parse `TinyRAM` program with macro definitions into a `TinyRAM` VM
+    pub fn load_program_with_macros<P>(filename: &P) -> Result<TinyVM, ParseError>
     where
         P: AsRef<Path> + Debug,
     {
         info!("Processing file {:?}", filename.as_ref());
-        let mut lines = Self::read_lines(filename)?;
+        let source = fs::read_to_string(filename)?;
+
+        Self::load_program_str_with_macros(&source)
+    }
+
+    /// Parse `TinyRAM` program into a `TinyRAM` VM, with default params if it has no header
+    pub fn load_program_with_defaults<P>(filename: &P) -> Result<TinyVM, ParseError>
+    where
+        P: AsRef<Path> + Debug,
+    {
+        info!("Processing file {:?}", filename.as_ref());
+        let source = fs::read_to_string(filename)?;
+
+        Self::load_program_str_with_defaults(&source)
+    }
+
+    /// Parse `TinyRAM` program from its source into a `TinyRAM` VM
+    pub fn load_program_str(source: &str) -> Result<TinyVM, ParseError> {
+        Self::parse_source(source, false, false)
+    }
+
+    /// Parse `TinyRAM` program from its source, with default params if it has no header
+    ///
+    /// A program whose first line is not a `; TinyRAM ...` header runs with
+    /// `Params::default()`, and a present header still overrides them.
+    pub fn load_program_str_with_defaults(source: &str) -> Result<TinyVM, ParseError> {
+        Self::parse_source(source, false, true)
+    }
+
+    /// Parse `TinyRAM` program with macro definitions from its source into a `TinyRAM` VM
+    ///
+    /// A macro is defined by a `.macro NAME [params]` line followed by its body
+    /// and a `.endm` line. Invoking `NAME args` expands the body with each
+    /// parameter replaced by the matching argument, and errors in the expansion
+    /// are reported at the line of the invocation.
+    pub fn load_program_str_with_macros(source: &str) -> Result<TinyVM, ParseError> {
+        Self::parse_source(source, true, false)
+    }
+
+    /// Parse `TinyRAM` program from its source, expanding macros if requested
+    ///
+    /// Blank and comment lines may precede the header. With `defaults`, a
+    /// missing header is replaced by the default params.
+    fn parse_source(source: &str, macros: bool, defaults: bool) -> Result<TinyVM, ParseError> {
+        if source.trim().is_empty() {
+            return Err(ParseError::EmptyProgram);
+        }
+
+        let mut lines = source
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line))
+            .peekable();
+
+        // Skip the banner preceding the header, if any
+        while let Some(&(_, line)) = lines.peek() {
+            let line = line.trim();
+            if Self::is_header(line) || !(line.is_empty() || line.starts_with(';')) {
+                break;
+            }
+            lines.next();
+        }
 
         // Check header
-        let first_line = lines.next().unwrap().unwrap();
-        let params = Self::read_params(&first_line)
-            .wrap_err_with(|| "Line 1: Incorrect parameters")
-            .with_suggestion(|| {
-                "The first line should be '; TinyRAM V=[version] M=[arch] W=[wordsize] K=[registers]'"
-            })?;
+        let params = match lines.peek() {
+            None => return Err(ParseError::BadHeader(String::from("empty program"))),
+            Some(&(_, line)) if Self::is_header(line) => {
+                lines.next();
+                Self::read_params(line)?
+            }
+            Some(_) if defaults => Params::default(),
+            Some(_) => {
+                return Err(ParseError::BadHeader(String::from(
+                    "The machine parameters should be stated before the first instruction",
+                )))
+            }
+        };
 
         Self::check_params(params)?;
 
+        let mut lines: Vec<_> = lines
+            .map(|(line_number, line)| (line_number, line.to_string()))
+            .collect();
+        if macros {
+            lines = Self::expand_macros(lines)?;
+        }
+
         // Parsing
         let mut instructions = vec![];
         let mut labels = vec![];
+        let mut constants = HashMap::new();
 
-        for (idx, line) in lines.enumerate() {
-            let line = line.unwrap();
+        for (line_number, line) in &lines {
+            // Trailing comments are ignored whatever precedes them
+            let line = match line.find(';') {
+                Some(idx) => &line[..idx],
+                _ => line,
+            };
             let line = line.trim();
 
             if line.is_empty()
@@ -160,43 +658,312 @@ impl Parser {
                 || Self::parse_whitespace(line).is_some()
             {
                 continue;
+            } else if let Some((ident, value)) = Self::parse_constant(line) {
+                if constants.contains_key(&ident) {
+                    return Err(ParseError::DuplicateConstant {
+                        line: *line_number,
+                        ident,
+                    });
+                }
+                constants.insert(ident, (value, *line_number));
+                continue;
             } else if let Some(instr) = Self::parse_instruction(line) {
-                instructions.push(instr);
+                instructions.push(Self::substitute_constants(instr, &constants));
                 continue;
             } else if let Some(label) = Self::parse_label(line) {
                 labels.push(Label {
                     ident: label,
                     address: instructions.len(),
-                    line: idx + 2,
+                    line: *line_number,
                 });
                 continue;
             }
 
-            return Err(eyre!("Line {}: Invalid content '{}'", idx + 2, line));
+            return Err(Self::invalid_line(*line_number, line));
+        }
+
+        if instructions.is_empty() {
+            return Err(ParseError::NoInstructions);
         }
 
         // Resolution
         let resolved_labels = Self::check_and_resolve_labels(&labels)?;
+        Self::check_constants(&instructions, &labels, &constants)?;
         Self::check_instructions(params, &instructions, &resolved_labels)?;
 
-        Ok(TinyVM::new(params, instructions, resolved_labels))
+        let vm = TinyVM::new(params, instructions, resolved_labels, source.to_string());
+        Self::check_termination(&vm, false)?;
+
+        Ok(vm)
+    }
+
+    /// Replace the argument of an instruction by its value if it names a defined constant
+    fn substitute_constants(
+        instr: Instruction,
+        constants: &HashMap<String, (i64, usize)>,
+    ) -> Instruction {
+        let (regs, arg) = instr.parts();
+
+        match arg {
+            Argument::Label(ref ident) if constants.contains_key(ident) => {
+                let value = Argument::Imm(constants[ident].0);
+                Instruction::from_parts(instr.opcode(), &regs, value).unwrap_or(instr)
+            }
+            _ => instr,
+        }
+    }
+
+    /// Check that constants do not collide with labels and are defined before use
+    fn check_constants(
+        instructions: &[Instruction],
+        labels: &[Label],
+        constants: &HashMap<String, (i64, usize)>,
+    ) -> Result<(), ParseError> {
+        for label in labels {
+            if let Some(&(_, line)) = constants.get(&label.ident) {
+                return Err(ParseError::DuplicateConstant {
+                    line: line.max(label.line),
+                    ident: label.ident.clone(),
+                });
+            }
+        }
+
+        // Constants still appearing as labels were used before being defined
+        for instr in instructions {
+            if let (_, Argument::Label(ident)) = instr.parts() {
+                if let Some(&(_, line)) = constants.get(&ident) {
+                    return Err(ParseError::UndefinedConstant { line, ident });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace macro definitions by nothing and macro invocations by their bodies
+    fn expand_macros(lines: Vec<(usize, String)>) -> Result<Vec<(usize, String)>, ParseError> {
+        let mut macros: HashMap<String, Macro> = HashMap::new();
+        let mut expanded = vec![];
+        let mut lines = lines.into_iter();
+
+        while let Some((line_number, line)) = lines.next() {
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some(".macro") => {
+                    let invalid = |reason: &str| ParseError::InvalidMacro {
+                        line: line_number,
+                        reason: reason.to_string(),
+                    };
+
+                    let name = words.next().ok_or_else(|| invalid("missing name"))?;
+                    let params = words
+                        .flat_map(|word| word.split(','))
+                        .filter(|param| !param.is_empty())
+                        .map(|param| Regex::new(&format!(r"\b{}\b", regex::escape(param))).unwrap())
+                        .collect();
+
+                    let mut body = vec![];
+                    loop {
+                        match lines.next() {
+                            Some((_, line)) if line.trim() == ".endm" => break,
+                            Some((_, line)) if line.trim().starts_with(".macro") => {
+                                return Err(invalid("nested definition"))
+                            }
+                            Some((_, line)) => body.push(line),
+                            _ => return Err(invalid("missing .endm")),
+                        }
+                    }
+
+                    if macros
+                        .insert(name.to_string(), Macro { params, body })
+                        .is_some()
+                    {
+                        return Err(invalid("duplicate definition"));
+                    }
+                }
+                Some(".endm") => {
+                    return Err(ParseError::InvalidMacro {
+                        line: line_number,
+                        reason: String::from(".endm without .macro"),
+                    })
+                }
+                Some(name) if macros.contains_key(name) => {
+                    let definition = &macros[name];
+                    let args: Vec<_> = words
+                        .flat_map(|word| word.split(','))
+                        .filter(|arg| !arg.is_empty())
+                        .collect();
+
+                    if args.len() != definition.params.len() {
+                        return Err(ParseError::InvalidMacro {
+                            line: line_number,
+                            reason: format!(
+                                "'{}' expects {} arguments, got {}",
+                                name,
+                                definition.params.len(),
+                                args.len()
+                            ),
+                        });
+                    }
+
+                    for body_line in &definition.body {
+                        let mut body_line = body_line.clone();
+                        for (param, arg) in definition.params.iter().zip(&args) {
+                            body_line = param.replace_all(&body_line, *arg).into_owned();
+                        }
+                        expanded.push((line_number, body_line));
+                    }
+                }
+                _ => expanded.push((line_number, line)),
+            }
+        }
+
+        Ok(expanded)
+    }
+
+    /// Check that an answer instruction is statically reachable from the start of the program
+    ///
+    /// This only warns unless `strict` is set. Jumps to registers and self-modifying
+    /// (Von Neumann) programs cannot be followed, so they are assumed to terminate.
+    pub fn check_termination(vm: &TinyVM, strict: bool) -> Result<(), ParseError> {
+        let reachable = match vm.params().arch {
+            ArchType::VonNeumann => true,
+            _ => Self::answer_reachable(&vm.instructions(), vm.labels()),
+        };
+
+        if reachable {
+            Ok(())
+        } else if strict {
+            Err(ParseError::NoReachableAnswer)
+        } else {
+            warn!("{}", ParseError::NoReachableAnswer);
+            Ok(())
+        }
+    }
+
+    /// Walk the control flow from the first instruction, looking for an answer
+    fn answer_reachable(instructions: &[Instruction], labels: &HashMap<String, usize>) -> bool {
+        let mut visited = vec![false; instructions.len()];
+        let mut pending = vec![0];
+
+        while let Some(pc) = pending.pop() {
+            let instr = match instructions.get(pc) {
+                Some(instr) if !visited[pc] => instr,
+                _ => continue,
+            };
+            visited[pc] = true;
+
+            let target = |arg: &Argument| match arg {
+                Argument::Imm(value) => usize::try_from(*value).ok(),
+                Argument::Label(ident) => labels.get(ident).copied(),
+                Argument::Reg(_) => None,
+            };
+
+            match instr {
+                Instruction::Answer(_) => return true,
+                Instruction::Jmp(arg) => match arg {
+                    Argument::Reg(_) => return true,
+                    arg => pending.extend(target(arg)),
+                },
+                Instruction::CJmp(arg) | Instruction::CnJmp(arg) => match arg {
+                    Argument::Reg(_) => return true,
+                    arg => pending.extend(target(arg).into_iter().chain([pc + 1])),
+                },
+                _ => pending.push(pc + 1),
+            }
+        }
+
+        false
+    }
+
+    /// Describe why a line could not be parsed
+    fn invalid_line(line: usize, text: &str) -> ParseError {
+        lazy_static! {
+            static ref RE: Regex = Regex::new("^[a-z][a-z0-9]*(\\.[a-z0-9]+)?$").unwrap();
+        }
+
+        let code = match text.find(';') {
+            Some(idx) => text[..idx].trim(),
+            _ => text,
+        };
+        // Tokens of the line with their byte offset
+        let mut tokens = vec![];
+        let mut start = None;
+        for (idx, c) in code.char_indices().chain([(code.len(), ' ')]) {
+            match (start, c.is_whitespace() || c == ',') {
+                (None, false) => start = Some(idx),
+                (Some(begin), true) => {
+                    tokens.push((begin, &code[begin..idx]));
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+
+        let opcode = tokens.first().map_or("", |&(_, token)| token);
+        let operands = tokens.get(1..).unwrap_or_default();
+        let actual = operands.len();
+
+        if let Some(expected) = Instruction::arity(opcode).filter(|&n| n != actual) {
+            ParseError::WrongArity {
+                line,
+                opcode: opcode.to_string(),
+                expected,
+                actual,
+            }
+        } else if Instruction::arity(opcode).is_some() {
+            // Registers come first, except for stores which end with theirs
+            let is_register = |idx: usize| match opcode {
+                "store.b" | "store.w" => idx == 1,
+                _ => idx + 1 < actual,
+            };
+            let column = operands
+                .iter()
+                .enumerate()
+                .find(|&(idx, &(_, token))| {
+                    if is_register(idx) {
+                        Self::parse_register(token).is_none()
+                    } else {
+                        Self::parse_argument(token).is_none()
+                    }
+                })
+                .map_or(0, |(_, &(offset, _))| offset);
+
+            ParseError::InvalidOperands {
+                line,
+                opcode: opcode.to_string(),
+                text: code.to_string(),
+                column,
+            }
+        } else if RE.is_match(opcode) {
+            ParseError::UnknownOpcode {
+                line,
+                opcode: opcode.to_string(),
+            }
+        } else {
+            ParseError::InvalidInstruction {
+                line,
+                text: text.to_string(),
+            }
+        }
     }
 
     /// Check if `TinyRAM` params are valid
     #[allow(clippy::float_cmp)]
-    fn check_params(params: Params) -> Result<(), Report> {
+    fn check_params(params: Params) -> Result<(), ParseError> {
         if params.version != 2.0 {
-            return Err(eyre!("Unsupported version: {}", params.version));
-        } else if params.word_size % 8 != 0 && params.word_size.is_power_of_two() {
-            return Err(eyre!(
-                "Word size should be a power of two and divisible by 8"
-            ));
+            return Err(ParseError::UnsupportedVersion(params.version));
+        } else if !params.word_size.is_multiple_of(8)
+            || !params.word_size.is_power_of_two()
+            || params.word_size > 64
+        {
+            return Err(ParseError::InvalidWordSize(params.word_size));
         }
 
         match params.arch {
-            ArchType::Harvard => Ok(()),
-            ArchType::VonNeumann => Err(eyre!("Tinyrust only supports Harvard architecture (hv)")),
-            ArchType::Unknown => Err(eyre!("Unknown VM architecture")),
+            ArchType::Harvard | ArchType::VonNeumann => Ok(()),
+            ArchType::Unknown => Err(ParseError::UnknownArch),
         }
     }
 
@@ -205,12 +972,12 @@ impl Parser {
         params: Params,
         instructions: &[Instruction],
         resolved_labels: &HashMap<String, usize>,
-    ) -> Result<(), Report> {
+    ) -> Result<(), ParseError> {
         info!("Checking instructions");
 
         let check_reg = |reg: &Register| {
             if reg.index >= params.registers {
-                Err(eyre!("Register 'r{}' does not exist", reg.index))
+                Err(ParseError::RegisterOutOfRange(reg.index))
             } else {
                 Ok(())
             }
@@ -222,18 +989,31 @@ impl Parser {
                 if resolved_labels.contains_key(ident as &str) {
                     Ok(())
                 } else {
-                    Err(eyre!("Undefined label '{}'", ident))
+                    Err(ParseError::UndefinedLabel(ident.clone()))
                 }
             }
             Argument::Imm(_) => Ok(()),
         };
 
+        // Von Neumann programs may jump to code they write beyond their end
+        let check_target = |arg: &Argument| match (params.arch, arg) {
+            (ArchType::Harvard, Argument::Imm(target))
+                if usize::try_from(*target).map_or(true, |pc| pc >= instructions.len()) =>
+            {
+                Err(ParseError::JumpOutOfRange {
+                    target: *target,
+                    program_size: instructions.len(),
+                })
+            }
+            _ => check_arg(arg),
+        };
+
         for instr in instructions {
             match instr {
-                Instruction::Jmp(arg)
-                | Instruction::CJmp(arg)
-                | Instruction::CnJmp(arg)
-                | Instruction::Answer(arg) => {
+                Instruction::Jmp(arg) | Instruction::CJmp(arg) | Instruction::CnJmp(arg) => {
+                    check_target(arg)?;
+                }
+                Instruction::Answer(arg) => {
                     check_arg(arg)?;
                 }
                 Instruction::Not(reg, arg)
@@ -274,40 +1054,56 @@ impl Parser {
     }
 
     /// Check if labels are valid and resolve the labels
-    fn check_and_resolve_labels(labels: &[Label]) -> Result<HashMap<String, usize>, Report> {
+    fn check_and_resolve_labels(labels: &[Label]) -> Result<HashMap<String, usize>, ParseError> {
         info!("Resolving labels");
 
         let mut hashmap = HashMap::new();
         for label in labels {
             let duplicate = hashmap.insert(label.ident.clone(), label.address);
             if duplicate.is_some() {
-                return Err(eyre!(
-                    "Line {}: Duplicate label: '{}'",
-                    label.line,
-                    label.ident,
-                ));
+                return Err(ParseError::DuplicateLabel {
+                    line: label.line,
+                    ident: label.ident.clone(),
+                });
             }
         }
         Ok(hashmap)
     }
 
+    /// Check if a line looks like a `; TinyRAM ...` header
+    fn is_header(line: &str) -> bool {
+        let mut parts = line.split_whitespace();
+
+        parts.next() == Some(";") && parts.next() == Some("TinyRAM")
+    }
+
     /// Read the `TimyRAM` VM params from the first line of the program file
-    fn read_params(first_line: &str) -> Result<Params, Report> {
+    fn read_params(first_line: &str) -> Result<Params, ParseError> {
+        let bad_header = |reason: &str| ParseError::BadHeader(String::from(reason));
         let parts: Vec<_> = first_line.split_whitespace().collect();
 
         if parts.len() != 6 {
-            return Err(eyre!("First line should state machine parameters"));
+            return Err(bad_header("First line should state machine parameters"));
         } else if parts[0] != ";" {
-            return Err(eyre!("First line should be a comment (start by ';')"));
+            return Err(bad_header("First line should be a comment (start by ';')"));
         } else if parts[1] != "TinyRAM" {
-            return Err(eyre!("Magic string 'TinyRAM' is missing"));
+            return Err(bad_header("Magic string 'TinyRAM' is missing"));
         }
 
-        let version = parts[2][2..].parse::<f32>()?;
-        let word_size = parts[4][2..].parse::<u16>()?;
-        let registers = parts[5][2..].parse::<u16>()?;
+        // Value of a 'X=value' parameter
+        let value = |part: &str| part.get(2..).unwrap_or_default().to_string();
+
+        let version = value(parts[2])
+            .parse::<f32>()
+            .map_err(|_| bad_header("Invalid version"))?;
+        let word_size = value(parts[4])
+            .parse::<u16>()
+            .map_err(|_| bad_header("Invalid word size"))?;
+        let registers = value(parts[5])
+            .parse::<u16>()
+            .map_err(|_| bad_header("Invalid number of registers"))?;
 
-        let arch = match &parts[3][2..] {
+        let arch = match value(parts[3]).as_str() {
             "hv" => ArchType::Harvard,
             "vn" => ArchType::VonNeumann,
             _ => ArchType::Unknown,
@@ -318,6 +1114,7 @@ impl Parser {
             word_size,
             registers,
             arch,
+            max_memory: Params::addressable_words(word_size),
         })
     }
 
@@ -347,97 +1144,77 @@ impl Parser {
             operands[i - 1].retain(|c| !c.is_whitespace() && c != ',');
         }
 
-        let instr = match nargs {
-            1 => {
-                let arg = match Self::parse_argument(&operands[0]) {
-                    Some(x) => x,
-                    _ => return None,
-                };
-                match opcode {
-                    "jmp" => Instruction::Jmp(arg),
-                    "cjmp" => Instruction::CJmp(arg),
-                    "cnjmp" => Instruction::CnJmp(arg),
-                    "answer" => Instruction::Answer(arg),
-                    _ => return None,
-                }
-            }
+        match nargs {
+            1 => Instruction::from_parts(opcode, &[], Self::parse_argument(&operands[0])?),
             2 => {
                 // For store instructions arguments are swapped
                 let (reg, arg) = match opcode {
-                    "store.b" | "store.w" => (
-                        Self::parse_register(&operands[1]),
-                        Self::parse_argument(&operands[0]),
-                    ),
-                    _ => (
-                        Self::parse_register(&operands[0]),
-                        Self::parse_argument(&operands[1]),
-                    ),
+                    "store.b" | "store.w" => (&operands[1], &operands[0]),
+                    _ => (&operands[0], &operands[1]),
                 };
 
-                if reg.is_none() || arg.is_none() {
-                    return None;
-                }
-                let reg = reg.unwrap();
-                let arg = arg.unwrap();
-
-                match opcode {
-                    "not" => Instruction::Not(reg, arg),
-                    "cmpe" => Instruction::CmpE(reg, arg),
-                    "cmpa" => Instruction::CmpA(reg, arg),
-                    "cmpae" => Instruction::CmpAE(reg, arg),
-                    "cmpg" => Instruction::CmpG(reg, arg),
-                    "cmpge" => Instruction::CmpGE(reg, arg),
-                    "mov" => Instruction::Mov(reg, arg),
-                    "cmov" => Instruction::CMov(reg, arg),
-                    "load.b" => Instruction::LoadB(reg, arg),
-                    "load.w" => Instruction::LoadW(reg, arg),
-                    "read" => Instruction::Read(reg, arg),
-                    "store.b" => Instruction::StoreW(arg, reg),
-                    "store.w" => Instruction::StoreW(arg, reg),
-                    _ => return None,
-                }
+                Instruction::from_parts(
+                    opcode,
+                    &[Self::parse_register(reg)?],
+                    Self::parse_argument(arg)?,
+                )
             }
-            3 => {
-                let reg1 = Self::parse_register(&operands[0]);
-                let reg2 = Self::parse_register(&operands[1]);
-                let arg = Self::parse_argument(&operands[2]);
+            3 => Instruction::from_parts(
+                opcode,
+                &[
+                    Self::parse_register(&operands[0])?,
+                    Self::parse_register(&operands[1])?,
+                ],
+                Self::parse_argument(&operands[2])?,
+            ),
+            _ => None,
+        }
+    }
 
-                if reg1.is_none() || reg2.is_none() || arg.is_none() {
-                    return None;
-                }
-                let reg1 = reg1.unwrap();
-                let reg2 = reg2.unwrap();
-                let arg = arg.unwrap();
+    /// Parse a `.equ NAME value` constant definition
+    fn parse_constant(line: &str) -> Option<(String, i64)> {
+        let mut parts = line.split_whitespace();
 
-                match opcode {
-                    "and" => Instruction::And(reg1, reg2, arg),
-                    "or" => Instruction::Or(reg1, reg2, arg),
-                    "xor" => Instruction::Xor(reg1, reg2, arg),
-                    "add" => Instruction::Add(reg1, reg2, arg),
-                    "sub" => Instruction::Sub(reg1, reg2, arg),
-                    "mull" => Instruction::MulL(reg1, reg2, arg),
-                    "umulh" => Instruction::UMulH(reg1, reg2, arg),
-                    "smulh" => Instruction::SMulH(reg1, reg2, arg),
-                    "udiv" => Instruction::UDiv(reg1, reg2, arg),
-                    "umod" => Instruction::UMod(reg1, reg2, arg),
-                    "shl" => Instruction::Shl(reg1, reg2, arg),
-                    "shr" => Instruction::Shr(reg1, reg2, arg),
+        if parts.next() != Some(".equ") {
+            return None;
+        }
 
-                    _ => return None,
-                }
-            }
-            _ => return None,
-        };
+        let ident = Self::parse_label_ident(parts.next()?)?;
+        let value = Self::parse_immediate(parts.next()?)?;
 
-        Some(instr)
+        match parts.next() {
+            Some(part) if Self::parse_comment(part).is_none() => None,
+            _ => Some((ident, value)),
+        }
     }
 
-    /// Parse value
+    /// Parse value, in decimal or with a `0x`, `0b` or `0o` prefix
     fn parse_immediate(s: &str) -> Option<i64> {
-        match s.parse::<i64>() {
-            Ok(x) => Some(x),
-            _ => None,
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            _ => (false, s),
+        };
+
+        let (radix, digits) = match unsigned.get(..2) {
+            Some("0x" | "0X") => (16, &unsigned[2..]),
+            Some("0b") => (2, &unsigned[2..]),
+            Some("0o") => (8, &unsigned[2..]),
+            _ => return s.parse::<i64>().ok(),
+        };
+
+        // Signs are only accepted before the prefix
+        if digits.starts_with(['+', '-']) {
+            return None;
         }
+
+        // Prefixed values cover the whole word, e.g. 0xFFFFFFFFFFFFFFFF
+        let value = u64::from_str_radix(digits, radix).ok()? as i64;
+
+        Some(if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        })
     }
 
     /// Parse current argument
@@ -481,10 +1258,11 @@ impl Parser {
     /// Parse the label identifier
     fn parse_label_ident(s: &str) -> Option<String> {
         lazy_static! {
-            static ref RE: Regex = Regex::new("_[0-9a-zA-Z_]+").unwrap();
+            static ref RE: Regex = Regex::new("^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
         }
 
-        if RE.is_match(s) {
+        // Register names are not valid labels
+        if RE.is_match(s) && Self::parse_register(s).is_none() {
             Some(s.to_string())
         } else {
             None
@@ -526,3 +1304,510 @@ impl Parser {
         Ok(io::BufReader::new(file).lines())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_invalid(name: &str) -> ParseError {
+        Parser::load_program(&format!("../assets/invalid/{}.tr", name)).unwrap_err()
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(load_invalid("missing"), ParseError::Io(_)));
+        assert!(matches!(
+            load_invalid("bad_header"),
+            ParseError::BadHeader(_)
+        ));
+        assert!(matches!(
+            load_invalid("unsupported_version"),
+            ParseError::UnsupportedVersion(version) if version == 1.0
+        ));
+        assert!(matches!(
+            load_invalid("unknown_arch"),
+            ParseError::UnknownArch
+        ));
+        assert!(matches!(
+            load_invalid("duplicate_label"),
+            ParseError::DuplicateLabel { line: 6, ident } if ident == "_main"
+        ));
+        assert!(matches!(
+            load_invalid("undefined_label"),
+            ParseError::UndefinedLabel(ident) if ident == "_nowhere"
+        ));
+        assert!(matches!(
+            load_invalid("invalid_instruction"),
+            ParseError::UnknownOpcode { line: 5, opcode } if opcode == "frobnicate"
+        ));
+        assert!(matches!(
+            load_invalid("register_out_of_range"),
+            ParseError::RegisterOutOfRange(2)
+        ));
+    }
+
+    #[test]
+    fn parse_error_into_report() {
+        let report: Report = load_invalid("undefined_label").into();
+
+        assert_eq!(report.to_string(), "Undefined label '_nowhere'");
+    }
+
+    #[test]
+    fn parse_immediates() {
+        assert_eq!(Parser::parse_immediate("42"), Some(42));
+        assert_eq!(Parser::parse_immediate("-42"), Some(-42));
+        assert_eq!(Parser::parse_immediate("0xFF"), Some(255));
+        assert_eq!(Parser::parse_immediate("0Xff"), Some(255));
+        assert_eq!(Parser::parse_immediate("-0x10"), Some(-16));
+        assert_eq!(Parser::parse_immediate("0b1010"), Some(10));
+        assert_eq!(Parser::parse_immediate("0o17"), Some(15));
+        assert_eq!(Parser::parse_immediate("0xFFFFFFFFFFFFFFFF"), Some(-1));
+
+        assert_eq!(Parser::parse_immediate("0xZZ"), None);
+        assert_eq!(Parser::parse_immediate("0b102"), None);
+        assert_eq!(Parser::parse_immediate("0x-5"), None);
+        assert_eq!(Parser::parse_immediate("0x"), None);
+    }
+
+    #[test]
+    fn parse_arguments_with_prefixes() {
+        assert!(matches!(
+            Parser::parse_argument("r10"),
+            Some(Argument::Reg(Register { index: 10 }))
+        ));
+        assert!(matches!(
+            Parser::parse_argument("_loop"),
+            Some(Argument::Label(ident)) if ident == "_loop"
+        ));
+        assert!(matches!(
+            Parser::parse_argument("0x1F"),
+            Some(Argument::Imm(31))
+        ));
+    }
+
+    #[test]
+    fn parse_labels() {
+        assert_eq!(Parser::parse_label("loop:"), Some(String::from("loop")));
+        assert_eq!(Parser::parse_label("_start:"), Some(String::from("_start")));
+        assert_eq!(Parser::parse_label("main_2:"), Some(String::from("main_2")));
+
+        assert_eq!(Parser::parse_label("1bad:"), None);
+        assert_eq!(Parser::parse_label("r0:"), None);
+        assert_eq!(Parser::parse_label("bad label:"), None);
+        assert_eq!(Parser::parse_label("loop"), None);
+
+        assert!(matches!(
+            Parser::parse_argument("r0"),
+            Some(Argument::Reg(Register { index: 0 }))
+        ));
+        assert!(matches!(
+            Parser::parse_argument("loop"),
+            Some(Argument::Label(ident)) if ident == "loop"
+        ));
+    }
+
+    #[test]
+    fn check_word_sizes() -> Result<(), ParseError> {
+        let check = |word_size: u16| {
+            let header = format!("; TinyRAM V=2.00 M=hv W={} K=2", word_size);
+            Parser::check_params(Parser::read_params(&header)?)
+        };
+
+        check(8)?;
+        check(16)?;
+        check(32)?;
+        check(64)?;
+
+        assert!(matches!(check(12), Err(ParseError::InvalidWordSize(12))));
+        assert!(matches!(check(24), Err(ParseError::InvalidWordSize(24))));
+        assert!(matches!(check(4), Err(ParseError::InvalidWordSize(4))));
+        assert!(matches!(check(128), Err(ParseError::InvalidWordSize(128))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_program_from_str() -> Result<(), ParseError> {
+        let source = "; TinyRAM V=2.00 M=hv W=64 K=2\n\
+                      mov r0, 0x2a\n\
+                      end:\n\
+                      answer r0\n";
+        let vm = Parser::load_program_str(source)?;
+
+        assert_eq!(vm.source(), source);
+        assert_eq!(
+            vm.instructions(),
+            vec![
+                Instruction::Mov(Register { index: 0 }, Argument::Imm(42)),
+                Instruction::Answer(Argument::Reg(Register { index: 0 })),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_program_without_header() -> Result<(), ParseError> {
+        let source = "mov r15, 7\n\
+                      answer r15\n";
+        let vm = Parser::load_program_str_with_defaults(source)?;
+
+        let params = vm.params();
+        assert_eq!(params.word_size, 32);
+        assert_eq!(params.registers, 16);
+        assert!(matches!(params.arch, ArchType::Harvard));
+        assert_eq!(vm.instructions().len(), 2);
+
+        // A present header overrides the defaults
+        let vm =
+            Parser::load_program_str_with_defaults("; TinyRAM V=2.00 M=hv W=8 K=2\nanswer 0\n")?;
+        assert_eq!(vm.params().word_size, 8);
+
+        // Without defaults the header is required
+        assert!(matches!(
+            Parser::load_program_str(source),
+            Err(ParseError::BadHeader(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_program_after_banner() -> Result<(), ParseError> {
+        let source = "; Copyright (c) the tinyrust authors\n\
+                      ; Licensed under the MIT license\n\
+                      \n\
+                      ; TinyRAM V=2.00 M=hv W=16 K=2\n\
+                      mov r0, 7\n\
+                      answer r0\n";
+        let vm = Parser::load_program_str(source)?;
+        assert_eq!(vm.params().word_size, 16);
+        assert_eq!(vm.instructions().len(), 2);
+
+        // Lines keep their number in the file
+        let error =
+            Parser::load_program_str(&source.replace("answer r0", "answer r0, r1")).unwrap_err();
+        assert!(error.to_string().starts_with("Line 6:"), "{}", error);
+
+        // The header must come before the first instruction
+        let late = "; Banner\nmov r0, 7\n; TinyRAM V=2.00 M=hv W=16 K=2\nanswer r0\n";
+        assert!(matches!(
+            Parser::load_program_str(late),
+            Err(ParseError::BadHeader(_))
+        ));
+        assert!(matches!(
+            Parser::load_program_str("; Only a banner\n\n"),
+            Err(ParseError::BadHeader(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_empty_programs() {
+        let error = load_invalid("empty");
+        assert!(matches!(error, ParseError::EmptyProgram));
+        assert_eq!(error.to_string(), "The program is empty");
+        assert!(matches!(
+            load_invalid("whitespace_only"),
+            ParseError::EmptyProgram
+        ));
+
+        let error = load_invalid("header_only");
+        assert!(matches!(error, ParseError::NoInstructions));
+        assert_eq!(error.to_string(), "The program has no instruction");
+
+        // Labels and comments are not instructions
+        let source = "; TinyRAM V=2.00 M=hv W=16 K=2\n_end:\n; done\n";
+        assert!(matches!(
+            Parser::load_program_str(source),
+            Err(ParseError::NoInstructions)
+        ));
+    }
+
+    #[test]
+    fn default_memory_size() {
+        assert_eq!(Params::addressable_words(8), 256);
+        assert_eq!(Params::addressable_words(16), 32768);
+        assert_eq!(Params::addressable_words(64), usize::MAX / 8);
+    }
+
+    #[test]
+    fn check_answer_reachability() -> Result<(), ParseError> {
+        let unreachable = "; TinyRAM V=2.00 M=hv W=64 K=2\n\
+                           mov r0, 0\n\
+                           _loop:\n\
+                           add r0, r0, 1\n\
+                           cmpe r0, 10\n\
+                           cjmp _loop\n\
+                           jmp _loop\n\
+                           answer r0\n";
+        let vm = Parser::load_program_str(unreachable)?;
+        assert!(Parser::check_termination(&vm, false).is_ok());
+        assert!(matches!(
+            Parser::check_termination(&vm, true),
+            Err(ParseError::NoReachableAnswer)
+        ));
+
+        // Both successors of a conditional jump are followed
+        let reachable = "; TinyRAM V=2.00 M=hv W=64 K=2\n\
+                         mov r0, 0\n\
+                         _loop:\n\
+                         add r0, r0, 1\n\
+                         cmpe r0, 10\n\
+                         cnjmp _loop\n\
+                         jmp _end\n\
+                         mov r1, 1\n\
+                         _end:\n\
+                         answer r0\n";
+        let vm = Parser::load_program_str(reachable)?;
+        Parser::check_termination(&vm, true)?;
+
+        let vm = Parser::load_program(&"../assets/fib.tr")?;
+        Parser::check_termination(&vm, true)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_invalid_lines() {
+        let error = |line: &str| {
+            let source = format!(
+                "; TinyRAM V=2.00 M=hv W=64 K=2\nmov r0, 1\n{}\nanswer r0\n",
+                line
+            );
+            Parser::load_program_str(&source).unwrap_err().to_string()
+        };
+
+        assert_eq!(error("addd r0, r1, 1"), "Line 3: Unknown opcode 'addd'");
+        assert_eq!(
+            error("add r0 r1 ; missing operand"),
+            "Line 3: 'add' expects 3 operands, got 2"
+        );
+        assert_eq!(
+            error("store.w r0, 0"),
+            [
+                "Line 3: Invalid operands for 'store.w' in 'store.w r0, 0'",
+                "    store.w r0, 0",
+                "                ^",
+            ]
+            .join("\n")
+        );
+        assert_eq!(error("42 r0"), "Line 3: Invalid content '42 r0'");
+    }
+
+    #[test]
+    fn check_operand_counts() {
+        let error = |line: &str| {
+            let source = format!("; TinyRAM V=2.00 M=hv W=64 K=3\n{}\nanswer r0\n", line);
+            Parser::load_program_str(&source).unwrap_err()
+        };
+        let arity = |line: &str| match error(line) {
+            ParseError::WrongArity {
+                line: 2,
+                expected,
+                actual,
+                ..
+            } => Some((expected, actual)),
+            _ => None,
+        };
+
+        // Too few operands
+        assert_eq!(arity("add r0, r1"), Some((3, 2)));
+        assert_eq!(arity("mov r0"), Some((2, 1)));
+        assert_eq!(arity("jmp"), Some((1, 0)));
+
+        // Too many operands
+        assert_eq!(arity("mov r0, r1, 2"), Some((2, 3)));
+        assert_eq!(arity("answer r0, 1"), Some((1, 2)));
+        assert_eq!(arity("store.w 0, r0, r1"), Some((2, 3)));
+        assert_eq!(arity("sub r0, r1, r2, 1"), Some((3, 4)));
+
+        assert_eq!(
+            error("cmpe r0, r1, r2").to_string(),
+            "Line 2: 'cmpe' expects 2 operands, got 3"
+        );
+
+        for (opcode, arity) in Instruction::OPCODES.iter().zip(Instruction::ARITIES) {
+            assert_eq!(Instruction::arity(opcode), Some(arity));
+        }
+        assert_eq!(Instruction::arity("addd"), None);
+    }
+
+    #[test]
+    fn check_jump_targets() -> Result<(), ParseError> {
+        let program = |target: &str| {
+            let source = format!(
+                "; TinyRAM V=2.00 M=hv W=64 K=2\nmov r0, 1\njmp {}\nanswer r0\n",
+                target
+            );
+            Parser::load_program_str(&source)
+        };
+
+        program("2")?;
+        assert_eq!(
+            program("9999").unwrap_err().to_string(),
+            "Jump target 9999 is outside of the program of 3 instructions"
+        );
+        assert!(matches!(
+            program("-1"),
+            Err(ParseError::JumpOutOfRange {
+                target: -1,
+                program_size: 3
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn expand_macros() -> Result<(), Report> {
+        let source = "; TinyRAM V=2.00 M=hv W=64 K=2\n\
+                      .macro inc r\n\
+                      add r, r, 1\n\
+                      .endm\n\
+                      mov r0, 40\n\
+                      inc r0\n\
+                      inc r0\n\
+                      store.w 0, r0\n\
+                      answer 0\n";
+
+        let mut vm = Parser::load_program_str_with_macros(source)?;
+        assert_eq!(
+            vm.instructions()[1],
+            Instruction::Add(
+                Register { index: 0 },
+                Register { index: 0 },
+                Argument::Imm(1)
+            )
+        );
+        assert_eq!(vm.run_vm((vec![], vec![]))?, 42);
+
+        // Macros are only expanded on request
+        assert!(matches!(
+            Parser::load_program_str(source),
+            Err(ParseError::InvalidInstruction { line: 2, .. })
+        ));
+
+        // Errors in expansions are reported at the invocation
+        let error = |source: &str| {
+            Parser::load_program_str_with_macros(source)
+                .unwrap_err()
+                .to_string()
+        };
+        let invalid = source.replace("inc r0\n", "inc r5\n");
+        assert_eq!(error(&invalid), "Register 'r5' does not exist");
+
+        let invalid = source.replace("inc r0\ninc r0", "inc r0\nincc r0");
+        assert_eq!(error(&invalid), "Line 7: Unknown opcode 'incc'");
+
+        let invalid = source.replace("inc r0\ninc r0", "inc r0\ninc r0, r1");
+        assert_eq!(
+            error(&invalid),
+            "Line 7: Invalid macro ('inc' expects 1 arguments, got 2)"
+        );
+
+        let invalid = source.replace(".endm\n", "");
+        assert_eq!(error(&invalid), "Line 2: Invalid macro (missing .endm)");
+
+        Ok(())
+    }
+
+    #[test]
+    fn define_constants() -> Result<(), Report> {
+        let source = "; TinyRAM V=2.00 M=hv W=64 K=2\n\
+                      .equ LIMIT 100\n\
+                      .equ STEP 0x21 ; in hexadecimal\n\
+                      mov r0, 0\n\
+                      _loop:\n\
+                      add r0, r0, STEP\n\
+                      cmpg r0, LIMIT\n\
+                      cnjmp _loop\n\
+                      store.w 0, r0\n\
+                      answer 0\n";
+
+        let mut vm = Parser::load_program_str(source)?;
+        assert_eq!(
+            vm.instructions()[2],
+            Instruction::CmpG(Register { index: 0 }, Argument::Imm(100))
+        );
+        assert_eq!(vm.run_vm((vec![], vec![]))?, 132);
+
+        let error = |source: &str| Parser::load_program_str(source).unwrap_err();
+        assert!(matches!(
+            error(&source.replace(".equ LIMIT 100\n", "")),
+            ParseError::UndefinedLabel(ident) if ident == "LIMIT"
+        ));
+        assert_eq!(
+            error(&source.replace("cmpg r0, LIMIT\n", "cmpg r0, LIMIT\n.equ LIMIT 5\n"))
+                .to_string(),
+            "Line 8: Duplicate constant: 'LIMIT'"
+        );
+        assert!(matches!(
+            error(&source.replace("_loop", "STEP")),
+            ParseError::DuplicateConstant { line: 5, ident } if ident == "STEP"
+        ));
+
+        // Constants have to be defined before being used
+        let late = source
+            .replace(".equ LIMIT 100\n", "")
+            .replace("answer 0\n", "answer 0\n.equ LIMIT 100\n");
+        assert_eq!(
+            error(&late).to_string(),
+            "Constant 'LIMIT' is used before its definition at line 10"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_trailing_comments() -> Result<(), ParseError> {
+        let source = "; TinyRAM V=2.00 M=hv W=64 K=3\n\
+                      _start: ; entry point\n\
+                      add r0, r1, r2 ; sum\n\
+                      add r0, r1, r2;sum\n\
+                      answer r0; done\n";
+        let vm = Parser::load_program_str(source)?;
+
+        let add = Instruction::Add(
+            Register { index: 0 },
+            Register { index: 1 },
+            Argument::Reg(Register { index: 2 }),
+        );
+        assert_eq!(
+            vm.instructions(),
+            vec![
+                add.clone(),
+                add,
+                Instruction::Answer(Argument::Reg(Register { index: 0 }))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn underline_invalid_operand() {
+        let source = "; TinyRAM V=2.00 M=hv W=64 K=3\n    add r0 rX r2\nanswer r0\n";
+        let error = Parser::load_program_str(source).unwrap_err();
+        println!("{}", error);
+
+        assert!(matches!(
+            error,
+            ParseError::InvalidOperands {
+                line: 2,
+                column: 7,
+                ..
+            }
+        ));
+        assert_eq!(
+            error.to_string().lines().collect::<Vec<_>>(),
+            vec![
+                "Line 2: Invalid operands for 'add' in 'add r0 rX r2'",
+                "    add r0 rX r2",
+                "           ^^",
+            ]
+        );
+    }
+}
@@ -1,15 +1,277 @@
 use color_eyre::{eyre::eyre, Report};
+use serde::{Deserialize, Serialize};
 use tracing::info;
 
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::parser::{ArchType, Argument, Instruction, Params, Register};
+
+/// Number of steps between two checks of the elapsed time
+const TIMEOUT_CHECK_INTERVAL: usize = 4096;
+
+/// Number of bytes of an encoded instruction in Von Neumann memory
+pub const INSTRUCTION_SIZE: usize = 16;
+
+/// Version of the scheme used to feed the VM states to the run callbacks
+///
+/// Version 1 fed the whole memory at each step, version 2 only feeds the
+/// memory size and the bytes written since the previous step.
+pub const HASH_SCHEME_VERSION: u32 = 2;
+
+/// Errors raised by the `TinyRAM` VM at runtime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// The program did not terminate within the allowed number of steps
+    StepLimitExceeded(usize),
+    /// A memory access went beyond the memory size, in words
+    MemoryOutOfBounds { address: usize, max_memory: usize },
+    /// A jump targets a pc outside of the program
+    JumpOutOfBounds { target: usize, program_size: usize },
+    /// The pc reached an address holding no valid instruction
+    Segfault { pc: usize, program_len: usize },
+    /// The program answered with a non-zero code
+    ErrorCode(usize),
+    /// An arithmetic instruction overflowed the word while trapping on overflows
+    Overflow { pc: usize, opcode: &'static str },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StepLimitExceeded(limit) => write!(f, "Step limit of {} exceeded", limit),
+            Self::MemoryOutOfBounds {
+                address,
+                max_memory,
+            } => write!(
+                f,
+                "Memory access at address {} exceeds the memory size of {} words",
+                address, max_memory
+            ),
+            Self::JumpOutOfBounds {
+                target,
+                program_size,
+            } => write!(
+                f,
+                "Jump to pc {} is outside of the program of {} instructions",
+                target, program_size
+            ),
+            Self::Segfault { pc, program_len } => write!(
+                f,
+                "Segmentation fault at pc {} in a program of {} instructions",
+                pc, program_len
+            ),
+            Self::Overflow { pc, opcode } => {
+                write!(f, "Overflow of '{}' at pc {}", opcode, pc)
+            }
+            Self::ErrorCode(code) => {
+                write!(f, "🔥 Program terminated with error code {} 🔥", code)
+            }
+        }
+    }
+}
+
+impl Error for VmError {}
+
+/// Byte order of the words fed to the state callback
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Endianness {
+    /// Most significant byte first
+    #[default]
+    Big,
+    /// Least significant byte first
+    Little,
+}
 
-use crate::parser::{Argument, Instruction, Params, Register};
+impl Endianness {
+    /// Return the bytes of a word in this byte order
+    pub const fn word_bytes(self, value: usize) -> [u8; std::mem::size_of::<usize>()] {
+        match self {
+            Self::Big => value.to_be_bytes(),
+            Self::Little => value.to_le_bytes(),
+        }
+    }
+}
+
+/// Behaviour of "add", "sub", "mull" and "shl" when the result does not fit in a word
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OverflowMode {
+    /// Keep the low bits of the result
+    #[default]
+    Wrap,
+    /// Clamp the result to the largest word, or to 0 when "sub" borrows
+    Saturate,
+    /// Stop the run with an error
+    Trap,
+}
+
+/// Cost of executing each kind of instruction, accumulated during runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CostModel {
+    /// Cost of "and", "or", "xor" and "not"
+    pub bitwise: usize,
+    /// Cost of "add" and "sub"
+    pub arithmetic: usize,
+    /// Cost of "mull", "umulh" and "smulh"
+    pub multiply: usize,
+    /// Cost of "udiv" and "umod"
+    pub divide: usize,
+    /// Cost of "shl" and "shr"
+    pub shift: usize,
+    /// Cost of the comparisons
+    pub compare: usize,
+    /// Cost of "mov" and "cmov"
+    pub mov: usize,
+    /// Cost of the jumps
+    pub jump: usize,
+    /// Cost of the loads and stores
+    pub memory: usize,
+    /// Cost of "read"
+    pub read: usize,
+    /// Cost of "answer"
+    pub answer: usize,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            bitwise: 1,
+            arithmetic: 1,
+            multiply: 4,
+            divide: 8,
+            shift: 1,
+            compare: 1,
+            mov: 1,
+            jump: 1,
+            memory: 4,
+            read: 2,
+            answer: 1,
+        }
+    }
+}
+
+impl CostModel {
+    /// Model where every instruction costs the same
+    pub const fn uniform(cost: usize) -> Self {
+        Self {
+            bitwise: cost,
+            arithmetic: cost,
+            multiply: cost,
+            divide: cost,
+            shift: cost,
+            compare: cost,
+            mov: cost,
+            jump: cost,
+            memory: cost,
+            read: cost,
+            answer: cost,
+        }
+    }
+
+    /// Return the cost of an instruction
+    pub const fn cost(&self, instr: &Instruction) -> usize {
+        match instr {
+            Instruction::And(..)
+            | Instruction::Or(..)
+            | Instruction::Xor(..)
+            | Instruction::Not(..) => self.bitwise,
+            Instruction::Add(..) | Instruction::Sub(..) => self.arithmetic,
+            Instruction::MulL(..) | Instruction::UMulH(..) | Instruction::SMulH(..) => {
+                self.multiply
+            }
+            Instruction::UDiv(..) | Instruction::UMod(..) => self.divide,
+            Instruction::Shl(..) | Instruction::Shr(..) => self.shift,
+            Instruction::CmpE(..)
+            | Instruction::CmpA(..)
+            | Instruction::CmpAE(..)
+            | Instruction::CmpG(..)
+            | Instruction::CmpGE(..) => self.compare,
+            Instruction::Mov(..) | Instruction::CMov(..) => self.mov,
+            Instruction::Jmp(..) | Instruction::CJmp(..) | Instruction::CnJmp(..) => self.jump,
+            Instruction::StoreB(..)
+            | Instruction::StoreW(..)
+            | Instruction::LoadB(..)
+            | Instruction::LoadW(..) => self.memory,
+            Instruction::Read(..) => self.read,
+            Instruction::Answer(..) => self.answer,
+        }
+    }
+}
+
+/// Copy of the state of the VM, taken between two steps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSnapshot {
+    /// Indicates if the VM is still running
+    pub running: bool,
+    /// The program counter of the VM
+    pub pc: usize,
+    /// Indicates if the flag is raised
+    pub flag: bool,
+    /// Values of the registers
+    pub registers: Vec<usize>,
+    /// Contents of the memory
+    pub memory: Vec<u8>,
+    /// Number of words left to read in each tape
+    pub tape_remaining: (usize, usize),
+}
+
+/// Summary of a program, computed from its instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramStats {
+    /// Number of instructions
+    pub instructions: usize,
+    /// Number of distinct labels
+    pub labels: usize,
+    /// Highest index of the registers used, if any
+    pub max_register: Option<u16>,
+    /// Whether the program loads or stores memory, which adds to the hashing cost
+    pub uses_memory: bool,
+}
+
+impl fmt::Display for ProgramStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} instructions, {} labels",
+            self.instructions, self.labels
+        )?;
+        match self.max_register {
+            Some(index) => write!(f, ", highest register r{}", index)?,
+            _ => write!(f, ", no register")?,
+        }
+        match self.uses_memory {
+            true => write!(f, ", uses memory"),
+            false => write!(f, ", no memory access"),
+        }
+    }
+}
+
+/// Record of an executed step, with the state it left the VM in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepRecord {
+    /// Number of steps executed, including this one
+    pub step: usize,
+    /// The program counter of the executed instruction
+    pub pc: usize,
+    /// The executed instruction
+    pub instr: Instruction,
+    /// Indicates if the flag is raised after the step
+    pub flag: bool,
+    /// Values of the registers after the step
+    pub registers: Vec<usize>,
+}
 
 /// Struct reprensenting the current state of the `TinyRAM` VM
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct State {
     /// Indicates is the VM is currently running
     running: bool,
+    /// Indicates if the program answered or failed since it was started
+    halted: bool,
     /// The program counter of the VM
     pc: usize,
     /// Indicates if the flag is raised
@@ -22,8 +284,18 @@ struct State {
     tape1: Vec<usize>,
     /// Reprensents the tape storing the inputs
     tape2: Vec<usize>,
+    /// Position of the next word to read in each tape
+    tape_pos: (usize, usize),
     /// Represents the memory of the VM
     memory: Vec<u8>,
+    /// Number of steps executed since the last reset
+    steps: usize,
+    /// Cost of the steps executed since the last reset
+    cost: usize,
+    /// Number of executions of each opcode since the last reset, in the order of `OPCODES`
+    opcode_counts: [usize; Instruction::OPCODES.len()],
+    /// Addresses of the memory bytes written since the state was last processed
+    dirty: BTreeSet<usize>,
 }
 
 impl State {
@@ -31,49 +303,79 @@ impl State {
     fn init(program: Vec<Instruction>, register_nb: usize) -> Self {
         Self {
             running: false,
+            halted: false,
             pc: 0,
             flag: false,
             registers: vec![0; register_nb],
             program,
             tape1: vec![],
             tape2: vec![],
+            tape_pos: (0, 0),
             memory: vec![],
+            steps: 0,
+            cost: 0,
+            opcode_counts: [0; Instruction::OPCODES.len()],
+            dirty: BTreeSet::new(),
         }
     }
 
     /// Allow the state to be processed by a callback
-    fn process_state<F>(&self, func: &mut F)
+    ///
+    /// The callback receives the pc, the flag, the registers and the memory
+    /// size, followed by the address and value of each memory byte written
+    /// since the previous call, in increasing address order. The digest of a
+    /// run thus covers every memory write without rehashing the whole memory
+    /// at each step (see `HASH_SCHEME_VERSION`).
+    fn process_state<F>(&mut self, func: &mut F, endianness: Endianness)
     where
         F: FnMut(&[u8]),
     {
-        func(&self.pc.to_be_bytes());
+        func(&endianness.word_bytes(self.pc));
 
         func(&[self.flag as u8]);
 
         for el in &self.registers {
-            func(&el.to_be_bytes());
+            func(&endianness.word_bytes(*el));
+        }
+
+        func(&endianness.word_bytes(self.memory.len()));
+        for addr in std::mem::take(&mut self.dirty) {
+            func(&endianness.word_bytes(addr));
+            func(&[self.memory[addr]]);
         }
-        for el in &self.memory {
-            func(&el.to_be_bytes());
+    }
+
+    /// Write bytes to memory at the given address, growing it if needed
+    fn write_memory(&mut self, addr: usize, bytes: &[u8]) {
+        let end = addr + bytes.len();
+        if self.memory.len() < end {
+            self.memory.resize(end, 0);
         }
+
+        self.memory[addr..end].copy_from_slice(bytes);
+        self.dirty.extend(addr..end);
     }
 
     /// Reset state
     fn reset(&mut self) {
-        let reg = self.registers.len();
-
         self.running = false;
+        self.halted = false;
         self.pc = 0;
         self.flag = false;
-        self.registers = vec![0; reg];
-        self.tape1 = vec![];
-        self.tape2 = vec![];
-        self.memory = vec![];
+        self.registers.fill(0);
+        self.tape1.clear();
+        self.tape2.clear();
+        self.tape_pos = (0, 0);
+        self.memory.clear();
+        self.steps = 0;
+        self.cost = 0;
+        self.opcode_counts.fill(0);
+        self.dirty.clear();
     }
 }
 
 /// Structure representing the `TinyRAM` VM
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TinyVM {
     /// VM params
     params: Params,
@@ -83,6 +385,20 @@ pub struct TinyVM {
     state: State,
     /// Output of the program run by the VM
     result: usize,
+    /// Maximum number of steps allowed for a run
+    max_steps: Option<usize>,
+    /// First memory word and number of words holding the outputs of the program
+    output_region: (usize, usize),
+    /// Cost of each kind of instruction
+    cost_model: CostModel,
+    /// Byte order of the words fed to the state callback
+    endianness: Endianness,
+    /// Behaviour of the arithmetic instructions on overflow
+    overflow_mode: OverflowMode,
+    /// Source of the program
+    source: String,
+    /// Program counters where `run_until_breakpoint` pauses
+    breakpoints: BTreeSet<usize>,
 }
 
 impl TinyVM {
@@ -91,59 +407,225 @@ impl TinyVM {
         params: Params,
         program: Vec<Instruction>,
         resolved_labels: HashMap<String, usize>,
+        source: String,
     ) -> Self {
         let state = State::init(program, params.registers.into());
 
-        Self {
+        let mut vm = Self {
             params,
             resolved_labels,
             state,
             result: 1,
-        }
+            max_steps: None,
+            output_region: (0, 1),
+            cost_model: CostModel::default(),
+            endianness: Endianness::default(),
+            overflow_mode: OverflowMode::default(),
+            source,
+            breakpoints: BTreeSet::new(),
+        };
+        vm.load_program_memory();
+
+        vm
+    }
+
+    /// Limit the number of steps a run can execute
+    pub const fn with_step_limit(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Read the outputs of the program from `len` memory words starting at word `start`
+    pub const fn with_output_region(mut self, start: usize, len: usize) -> Self {
+        self.output_region = (start, len);
+        self
+    }
+
+    /// Replace the default cost model of the instructions
+    pub const fn with_cost_model(mut self, cost_model: CostModel) -> Self {
+        self.cost_model = cost_model;
+        self
+    }
+
+    /// Select the byte order of the words fed to the state callback, big-endian by default
+    pub const fn with_endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Select the behaviour of the arithmetic instructions on overflow, wrapping by default
+    pub const fn with_overflow_mode(mut self, overflow_mode: OverflowMode) -> Self {
+        self.overflow_mode = overflow_mode;
+        self
+    }
+
+    /// Limit the memory of the VM, in words
+    pub const fn with_max_memory(mut self, max_memory: usize) -> Self {
+        self.params.max_memory = max_memory;
+        self
     }
 
     /// Load the input tapes into the VM
     pub fn load_tapes(&mut self, tape: (Vec<usize>, Vec<usize>)) {
         self.state.tape1 = tape.0;
         self.state.tape2 = tape.1;
+        self.state.tape_pos = (0, 0);
     }
 
     /// Read the next word in the primary input tape
     fn read_primary_tape(&mut self) -> usize {
-        self.state.tape1.pop().unwrap_or(0)
+        let value = self.state.tape1.get(self.state.tape_pos.0).copied();
+        self.state.tape_pos.0 += 1;
+        value.unwrap_or(0)
     }
 
     /// Read the next word in the secondary input tape
     fn read_secondary_tape(&mut self) -> usize {
-        self.state.tape2.pop().unwrap_or(0)
+        let value = self.state.tape2.get(self.state.tape_pos.1).copied();
+        self.state.tape_pos.1 += 1;
+        value.unwrap_or(0)
     }
 
     /// Launch the VM
     fn start(&mut self) {
         info!("TinyVM started");
         self.state.running = true;
+        self.state.halted = false;
     }
 
     /// Halt the VM
     fn stop(&mut self) {
         info!("TinyVM stopped");
         self.state.running = false;
+        self.state.halted = true;
     }
 
-    /// Run the current instruction marked by the pc
-    fn step(&mut self) -> Result<(), Report> {
-        let instr = {
-            match self.state.program.get(self.state.pc) {
-                Some(instr) => instr.clone(),
-                _ => Self::segfault(),
+    /// Run the current instruction marked by the pc, returning it
+    fn step(&mut self) -> Result<Instruction, Report> {
+        let instr = match self.params.arch {
+            ArchType::VonNeumann => self
+                .state
+                .pc
+                .checked_mul(INSTRUCTION_SIZE)
+                .and_then(|addr| self.decode(addr)),
+            _ => self.state.program.get(self.state.pc).cloned(),
+        };
+        let instr = match instr {
+            Some(instr) => instr,
+            _ => {
+                self.stop();
+                return Err(VmError::Segfault {
+                    pc: self.state.pc,
+                    program_len: self.state.program.len(),
+                }
+                .into());
+            }
+        };
+
+        self.state.pc = match self.execute(&instr) {
+            Ok(pc) => pc,
+            Err(error) => {
+                self.stop();
+                return Err(error);
             }
         };
+        self.state.steps += 1;
+        self.state.cost += self.cost_model.cost(&instr);
+        self.state.opcode_counts[instr.index()] += 1;
+
+        Ok(instr)
+    }
+
+    /// Execute a single instruction, returning whether the program is still running
+    ///
+    /// Once the program has answered or failed nothing is executed until the
+    /// state is reset.
+    pub fn single_step(&mut self) -> Result<bool, Report> {
+        if self.state.halted {
+            return Ok(false);
+        } else if !self.state.running {
+            self.start();
+        }
+        self.check_step_limit()?;
+        self.step()?;
+
+        Ok(self.state.running)
+    }
 
-        self.state.pc = self.execute(instr)?;
+    /// Pause `run_until_breakpoint` when reaching the given pc
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Pause `run_until_breakpoint` when reaching the given label
+    pub fn add_breakpoint_label(&mut self, ident: &str) -> Result<(), Report> {
+        let pc = *self
+            .resolved_labels
+            .get(ident)
+            .ok_or_else(|| eyre!("Undefined label '{}'", ident))?;
+        self.add_breakpoint(pc);
 
         Ok(())
     }
 
+    /// Return the pcs of the current breakpoints
+    pub const fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    /// Remove all breakpoints
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Run the program until a breakpoint is reached, returning whether it paused
+    ///
+    /// At least one instruction is executed so that a paused VM can be resumed.
+    pub fn run_until_breakpoint(&mut self) -> Result<bool, Report> {
+        while self.single_step()? {
+            if self.breakpoints.contains(&self.state.pc) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Execute the loaded program lazily, yielding a snapshot after each step
+    ///
+    /// The iterator ends after the step that stops the VM, or after the first
+    /// error, and the output of the program can still be read afterwards.
+    pub fn steps(&mut self) -> impl Iterator<Item = Result<VmSnapshot, Report>> + '_ {
+        let mut done = false;
+
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+
+            let step = self.single_step();
+            done = !matches!(step, Ok(true));
+            Some(step.map(|_| self.snapshot()))
+        })
+    }
+
+    /// Take a snapshot of the current state of the VM
+    pub fn snapshot(&self) -> VmSnapshot {
+        let state = &self.state;
+
+        VmSnapshot {
+            running: state.running,
+            pc: state.pc,
+            flag: state.flag,
+            registers: state.registers.clone(),
+            memory: state.memory.clone(),
+            tape_remaining: (
+                state.tape1.len().saturating_sub(state.tape_pos.0),
+                state.tape2.len().saturating_sub(state.tape_pos.1),
+            ),
+        }
+    }
+
     /// Print the current state of the memory
     fn display_memory(&self) {
         info!("memory: {:?}", self.state.memory);
@@ -175,15 +657,139 @@ impl TinyVM {
         self.state.program.clone()
     }
 
-    /// Run the program loaded in the VM
-    fn run<F>(&mut self, mut callback: F) -> Result<usize, Report>
+    /// Return the params of the VM
+    pub(crate) const fn params(&self) -> Params {
+        self.params
+    }
+
+    /// Summarize the size of the current program and the resources it uses
+    pub fn program_stats(&self) -> ProgramStats {
+        let program = &self.state.program;
+        let max_register = program
+            .iter()
+            .flat_map(|instr| {
+                let (regs, arg) = instr.parts();
+                let arg_reg = match arg {
+                    Argument::Reg(reg) => Some(reg),
+                    _ => None,
+                };
+                regs.into_iter().chain(arg_reg)
+            })
+            .map(|reg| reg.index)
+            .max();
+        let uses_memory = program.iter().any(|instr| {
+            matches!(
+                instr,
+                Instruction::StoreB(..)
+                    | Instruction::StoreW(..)
+                    | Instruction::LoadB(..)
+                    | Instruction::LoadW(..)
+            )
+        });
+
+        ProgramStats {
+            instructions: program.len(),
+            labels: self.resolved_labels.len(),
+            max_register,
+            uses_memory,
+        }
+    }
+
+    /// Return the resolved labels of the current program
+    pub(crate) const fn labels(&self) -> &HashMap<String, usize> {
+        &self.resolved_labels
+    }
+
+    /// Return the exact source the current program was parsed from
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Render the program loaded in the VM as `TinyRAM` source
+    pub fn disassemble(&self) -> String {
+        let mut labels: Vec<_> = self.resolved_labels.iter().collect();
+        labels.sort_by(|(ident1, address1), (ident2, address2)| {
+            address1.cmp(address2).then(ident1.cmp(ident2))
+        });
+        let mut labels = labels.into_iter().peekable();
+
+        let mut lines = vec![self.params.to_string()];
+        for address in 0..=self.state.program.len() {
+            while let Some((ident, _)) = labels.next_if(|(_, &label)| label == address) {
+                lines.push(format!("{}:", ident));
+            }
+
+            if let Some(instr) = self.state.program.get(address) {
+                lines.push(format!("    {}", instr));
+            }
+        }
+
+        lines.join("\n") + "\n"
+    }
+
+    /// Run the program loaded in the VM, recording each step if a trace is given
+    fn run<F>(
+        &mut self,
+        mut callback: F,
+        mut trace: Option<&mut dyn FnMut(&StepRecord)>,
+    ) -> Result<usize, Report>
+    where
+        F: FnMut(&[u8]),
+    {
+        self.start();
+        while self.state.running {
+            self.check_step_limit()?;
+            let pc = self.state.pc;
+            let instr = self.step()?;
+            self.state.process_state(&mut callback, self.endianness);
+
+            if let Some(ref mut trace) = trace {
+                trace(&StepRecord {
+                    step: self.state.steps,
+                    pc,
+                    instr,
+                    flag: self.state.flag,
+                    registers: self.state.registers.clone(),
+                });
+            }
+        }
+
+        Ok(self.result)
+    }
+
+    /// Halt the VM if the step limit has been reached
+    fn check_step_limit(&mut self) -> Result<(), Report> {
+        match self.max_steps {
+            Some(limit) if self.state.steps >= limit => {
+                self.stop();
+                Err(VmError::StepLimitExceeded(limit).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Run the program loaded in the VM, aborting once the timeout is exceeded
+    pub fn run_with_timeout<F>(
+        &mut self,
+        timeout: Duration,
+        mut callback: F,
+    ) -> Result<usize, Report>
     where
         F: FnMut(&[u8]),
     {
+        let start = Instant::now();
+
         self.start();
         while self.state.running {
+            self.check_step_limit()?;
             self.step()?;
-            self.state.process_state(&mut callback);
+            self.state.process_state(&mut callback, self.endianness);
+
+            if self.state.steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && start.elapsed() > timeout
+            {
+                self.stop();
+                return Err(eyre!("timeout"));
+            }
         }
 
         Ok(self.result)
@@ -194,6 +800,14 @@ impl TinyVM {
         self.run_vm_with_callback(input, |_: &[u8]| {})
     }
 
+    /// Run the VM on a public input, returning the output and resetting the state
+    pub fn run_with_input(&mut self, input: Vec<usize>) -> Result<usize, Report> {
+        let output = self.run_vm((input, vec![]));
+        self.reset_state();
+
+        output
+    }
+
     /// Run the VM with a callback and the selected input
     pub fn run_vm_with_callback<F>(
         &mut self,
@@ -206,27 +820,223 @@ impl TinyVM {
         self.load_tapes(input);
 
         info!("✨ All good to go! ✨");
-        match self.run(callback)? {
+        let result = self.run(callback, None)?;
+        self.conclude(result)
+    }
+
+    /// Run the VM with a callback and the selected input, passing a record of each step to `trace`
+    pub fn run_vm_with_trace<F, T>(
+        &mut self,
+        input: (Vec<usize>, Vec<usize>),
+        callback: F,
+        mut trace: T,
+    ) -> Result<usize, Report>
+    where
+        F: FnMut(&[u8]),
+        T: FnMut(&StepRecord),
+    {
+        self.load_tapes(input);
+
+        info!("✨ All good to go! ✨");
+        let result = self.run(callback, Some(&mut trace))?;
+        self.conclude(result)
+    }
+
+    /// Return the output of a terminated program, or its error code
+    fn conclude(&self, result: usize) -> Result<usize, Report> {
+        match result {
             0 => {
                 info!("✨ TinyVM terminated without error ✨");
                 self.display_state();
 
                 Ok(self.output())
             }
-            x => Err(eyre!("🔥 Program terminated with error code {} 🔥", x)),
+            x => Err(VmError::ErrorCode(x).into()),
         }
     }
 
     /// Displays the output of the program
     pub fn output(&self) -> usize {
-        let val: [u8; 8] = <[u8; 8]>::try_from(&self.state.memory[0..8]).unwrap();
+        self.read_memory_word(0)
+    }
 
-        usize::from_le_bytes(val)
+    /// Return the code given to `answer` by the last run, 1 if it did not answer
+    pub const fn answer_code(&self) -> usize {
+        self.result
+    }
+
+    /// Return the values of the `len` words of the output region, starting at word `start`
+    ///
+    /// Words beyond the addressable memory read as 0, like unwritten memory.
+    pub fn outputs(&self) -> Vec<usize> {
+        let (start, len) = self.output_region;
+
+        (0..len)
+            .map(|offset| {
+                start
+                    .checked_add(offset)
+                    .and_then(|word| word.checked_mul(8))
+                    .map_or(0, |addr| self.read_memory_word(addr))
+            })
+            .collect()
+    }
+
+    /// Return the number of steps executed since the last reset
+    pub const fn step_count(&self) -> usize {
+        self.state.steps
+    }
+
+    /// Return the cost of the steps executed since the last reset
+    pub const fn total_cost(&self) -> usize {
+        self.state.cost
+    }
+
+    /// Return how often each opcode was executed since the last reset
+    ///
+    /// Opcodes that were never executed are left out.
+    pub fn opcode_histogram(&self) -> HashMap<&'static str, usize> {
+        Instruction::OPCODES
+            .iter()
+            .zip(self.state.opcode_counts)
+            .filter(|&(_, count)| count > 0)
+            .map(|(&opcode, count)| (opcode, count))
+            .collect()
+    }
+
+    /// Return the current values of the registers
+    pub fn registers(&self) -> &[usize] {
+        &self.state.registers
+    }
+
+    /// Return the current contents of the memory, as bytes
+    pub fn memory(&self) -> &[u8] {
+        &self.state.memory
+    }
+
+    /// Indicate if the flag is currently raised
+    pub const fn flag(&self) -> bool {
+        self.state.flag
+    }
+
+    /// Return the current program counter
+    pub const fn pc(&self) -> usize {
+        self.state.pc
     }
 
     /// Reset the state of the VM to initial state
+    ///
+    /// The pc, flag, registers, memory, tapes and result are restored while the
+    /// program and its labels are kept, so that the VM can be reused for
+    /// another input.
     pub fn reset_state(&mut self) {
         self.state.reset();
+        self.result = 1;
+        self.load_program_memory();
+    }
+
+    /// Write the encoded program at the start of memory on Von Neumann VMs
+    fn load_program_memory(&mut self) {
+        if let ArchType::VonNeumann = self.params.arch {
+            let memory: Vec<u8> = self
+                .state
+                .program
+                .iter()
+                .flat_map(|instr| self.encode(instr))
+                .collect();
+            self.state.write_memory(0, &memory);
+        }
+    }
+
+    /// Encode an instruction as a header word followed by its argument word
+    ///
+    /// The header holds the opcode (offset by one so that zeroed memory is
+    /// invalid) in bits 0-4, the immediate flag in bit 5 and the registers
+    /// in bits 8-23 and 24-39.
+    fn encode(&self, instr: &Instruction) -> [u8; INSTRUCTION_SIZE] {
+        let opcode = instr.index();
+        let (regs, arg) = instr.parts();
+
+        let (imm, value) = match arg {
+            Argument::Imm(x) => (1, Self::to_unsigned(x)),
+            Argument::Reg(reg) => (0, reg.index.into()),
+            Argument::Label(ident) => (1, self.resolved_labels[&ident] as u64),
+        };
+
+        let mut header = (opcode as u64 + 1) | (imm << 5);
+        for (i, reg) in regs.iter().enumerate() {
+            header |= u64::from(reg.index) << (8 + 16 * i);
+        }
+
+        let mut bytes = [0; INSTRUCTION_SIZE];
+        bytes[..8].copy_from_slice(&header.to_le_bytes());
+        bytes[8..].copy_from_slice(&value.to_le_bytes());
+        bytes
+    }
+
+    /// Decode the instruction stored in memory at the given address
+    fn decode(&self, addr: usize) -> Option<Instruction> {
+        let header = self.read_memory_word(addr) as u64;
+        let value = self.read_memory_word(addr.checked_add(8)?) as u64;
+
+        let opcode = Instruction::OPCODES.get(((header & 0x1f) as usize).checked_sub(1)?)?;
+        let arg = if header & 0x20 == 0 {
+            Argument::Reg(Register {
+                index: u16::try_from(value).ok()?,
+            })
+        } else {
+            Argument::Imm(Self::to_signed(value))
+        };
+        let regs = [
+            Register {
+                index: (header >> 8) as u16,
+            },
+            Register {
+                index: (header >> 24) as u16,
+            },
+        ];
+
+        // Only one number of registers matches a given opcode
+        let instr = (0..=regs.len())
+            .find_map(|n| Instruction::from_parts(opcode, &regs[..n], arg.clone()))?;
+
+        // Overwritten instructions may reference missing registers
+        let (regs, arg) = instr.parts();
+        let in_range = |reg: &Register| reg.index < self.params.registers;
+        match arg {
+            Argument::Reg(reg) if !in_range(&reg) => None,
+            _ if !regs.iter().all(in_range) => None,
+            _ => Some(instr),
+        }
+    }
+
+    /// Check that an access of `len` bytes at the given address fits in memory
+    fn check_memory_access(&self, addr: usize, len: usize) -> Result<(), Report> {
+        let max_memory = self.params.max_memory;
+        let word_bytes = usize::from(self.params.word_size / 8).max(1);
+
+        match addr.checked_add(len) {
+            Some(end) if end <= max_memory.saturating_mul(word_bytes) => Ok(()),
+            _ => Err(VmError::MemoryOutOfBounds {
+                address: addr,
+                max_memory,
+            }
+            .into()),
+        }
+    }
+
+    /// Read the word stored in memory at the given address
+    fn read_memory_word(&self, addr: usize) -> usize {
+        // Memory that was never written reads as 0
+        let mut val = [0; 8];
+        for (offset, byte) in val.iter_mut().enumerate() {
+            *byte = addr
+                .checked_add(offset)
+                .and_then(|addr| self.state.memory.get(addr))
+                .copied()
+                .unwrap_or(0);
+        }
+
+        usize::from_le_bytes(val)
     }
 
     /// Read value from the designated register
@@ -241,67 +1051,67 @@ impl TinyVM {
 
     /// Convert unsigned to signed
     fn to_signed(x: u64) -> i64 {
-        unsafe { std::mem::transmute::<u64, i64>(x) }
+        x as i64
     }
 
     /// Convert signed to unsigned
     fn to_unsigned(x: i64) -> u64 {
-        unsafe { std::mem::transmute::<i64, u64>(x) }
+        x as u64
     }
 
     /// Execute the instruction at the current pc
-    fn execute(&mut self, instr: Instruction) -> Result<usize, Report> {
+    fn execute(&mut self, instr: &Instruction) -> Result<usize, Report> {
         let mut next_pc = self.state.pc + 1;
 
         match instr {
             // Bit operations
-            Instruction::And(reg1, reg2, arg) => self.and(&reg1, &reg2, &arg),
-            Instruction::Or(reg1, reg2, arg) => self.or(&reg1, &reg2, &arg),
-            Instruction::Xor(reg1, reg2, arg) => self.xor(&reg1, &reg2, &arg),
-            Instruction::Not(reg, arg) => self.not(&reg, &arg),
+            Instruction::And(reg1, reg2, arg) => self.and(reg1, reg2, arg),
+            Instruction::Or(reg1, reg2, arg) => self.or(reg1, reg2, arg),
+            Instruction::Xor(reg1, reg2, arg) => self.xor(reg1, reg2, arg),
+            Instruction::Not(reg, arg) => self.not(reg, arg),
 
             // Integer operations
-            Instruction::Add(reg1, reg2, arg) => self.add(&reg1, &reg2, &arg),
-            Instruction::Sub(reg1, reg2, arg) => self.sub(&reg1, &reg2, &arg),
-            Instruction::MulL(reg1, reg2, arg) => self.mull(&reg1, &reg2, &arg),
-            Instruction::UMulH(_reg1, _reg2, _arg) => unimplemented!("UMulH"),
-            Instruction::SMulH(_reg1, _reg2, _arg) => unimplemented!("SMulH"),
-            Instruction::UDiv(reg1, reg2, arg) => self.udiv(&reg1, &reg2, &arg),
-            Instruction::UMod(reg1, reg2, arg) => self.umod(&reg1, &reg2, &arg),
+            Instruction::Add(reg1, reg2, arg) => self.add(reg1, reg2, arg)?,
+            Instruction::Sub(reg1, reg2, arg) => self.sub(reg1, reg2, arg)?,
+            Instruction::MulL(reg1, reg2, arg) => self.mull(reg1, reg2, arg)?,
+            Instruction::UMulH(reg1, reg2, arg) => self.umulh(reg1, reg2, arg),
+            Instruction::SMulH(reg1, reg2, arg) => self.smulh(reg1, reg2, arg),
+            Instruction::UDiv(reg1, reg2, arg) => self.udiv(reg1, reg2, arg),
+            Instruction::UMod(reg1, reg2, arg) => self.umod(reg1, reg2, arg),
 
             // Shift operations
-            Instruction::Shl(reg1, reg2, arg) => self.shl(&reg1, &reg2, &arg),
-            Instruction::Shr(reg1, reg2, arg) => self.shr(&reg1, &reg2, &arg),
+            Instruction::Shl(reg1, reg2, arg) => self.shl(reg1, reg2, arg)?,
+            Instruction::Shr(reg1, reg2, arg) => self.shr(reg1, reg2, arg),
 
             // Compare operations
-            Instruction::CmpE(reg, arg) => self.cmpe(&reg, &arg),
-            Instruction::CmpA(reg, arg) => self.cmpa(&reg, &arg),
-            Instruction::CmpAE(reg, arg) => self.cmpae(&reg, &arg),
-            Instruction::CmpG(reg, arg) => self.cmpg(&reg, &arg),
-            Instruction::CmpGE(reg, arg) => self.cmpge(&reg, &arg),
+            Instruction::CmpE(reg, arg) => self.cmpe(reg, arg),
+            Instruction::CmpA(reg, arg) => self.cmpa(reg, arg),
+            Instruction::CmpAE(reg, arg) => self.cmpae(reg, arg),
+            Instruction::CmpG(reg, arg) => self.cmpg(reg, arg),
+            Instruction::CmpGE(reg, arg) => self.cmpge(reg, arg),
 
             // Move operations
-            Instruction::Mov(reg, arg) => self.mov(&reg, &arg),
-            Instruction::CMov(reg, arg) => self.cmov(&reg, &arg),
+            Instruction::Mov(reg, arg) => self.mov(reg, arg),
+            Instruction::CMov(reg, arg) => self.cmov(reg, arg),
 
             // Jump operations
-            Instruction::Jmp(arg) => next_pc = self.jmp(&arg),
-            Instruction::CJmp(arg) => next_pc = self.cjmp(&arg),
-            Instruction::CnJmp(arg) => next_pc = self.cnjmp(&arg),
+            Instruction::Jmp(arg) => next_pc = self.jmp(arg)?,
+            Instruction::CJmp(arg) => next_pc = self.cjmp(arg)?,
+            Instruction::CnJmp(arg) => next_pc = self.cnjmp(arg)?,
 
             // Memory operations
-            Instruction::StoreB(arg, reg) => self.store_b(&arg, &reg),
-            Instruction::StoreW(arg, reg) => self.store_w(&arg, &reg),
-            Instruction::LoadB(reg, arg) => self.load_b(&reg, &arg),
-            Instruction::LoadW(reg, arg) => self.load_w(&reg, &arg),
+            Instruction::StoreB(arg, reg) => self.store_b(arg, reg)?,
+            Instruction::StoreW(arg, reg) => self.store_w(arg, reg)?,
+            Instruction::LoadB(reg, arg) => self.load_b(reg, arg)?,
+            Instruction::LoadW(reg, arg) => self.load_w(reg, arg)?,
 
             // Input operation
-            Instruction::Read(reg, arg) => self.read(&reg, &arg),
+            Instruction::Read(reg, arg) => self.read(reg, arg),
 
             // Answer operation
             Instruction::Answer(arg) => {
                 next_pc -= 1;
-                self.answer(&arg);
+                self.answer(arg);
             }
         }
 
@@ -311,15 +1121,19 @@ impl TinyVM {
     /// Resolve argument as label, register or value
     fn resolve(&self, arg: &Argument) -> usize {
         match arg {
-            Argument::Imm(x) => Self::to_unsigned(*x) as usize,
+            // Immediates are truncated to the word size like any other value
+            Argument::Imm(x) => Self::to_unsigned(*x) as usize & self.value_mask(),
             Argument::Reg(reg) => self.read_reg(reg),
             Argument::Label(ident) => self.resolved_labels[ident as &str],
         }
     }
 
-    /// Defines the segfault instruction
-    const fn segfault() -> Instruction {
-        Instruction::Answer(Argument::Imm(1))
+    /// Mask keeping the bits of a value that fit in a word
+    const fn value_mask(&self) -> usize {
+        match 1usize.checked_shl(self.params.word_size as u32) {
+            Some(bound) => bound - 1,
+            _ => usize::MAX,
+        }
     }
 
     /// Defines the `TinyRAM` "and" instruction
@@ -362,7 +1176,8 @@ impl TinyVM {
     fn not(&mut self, reg: &Register, arg: &Argument) {
         let value = self.resolve(arg);
 
-        let result = !value;
+        // Only the bits of the word are flipped
+        let result = !value & self.value_mask();
         let zero = result == 0;
 
         self.write_reg(reg, result);
@@ -370,83 +1185,133 @@ impl TinyVM {
     }
 
     /// Defines the `TinyRAM` "add" instruction
-    fn add(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) {
-        let msb_mask = 1 << (self.params.word_size - 1);
-
-        // HOTFIX: 2^64 will overflow otherwise
-        let value_mask = if self.params.word_size == 64 {
-            usize::MAX
-        } else {
-            (1 << self.params.word_size) - 1
-        };
+    ///
+    /// The flag is raised on an unsigned carry out of the word.
+    fn add(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) -> Result<(), Report> {
+        let value_mask = self.value_mask();
 
         let value1 = self.read_reg(reg2);
         let value2 = self.resolve(arg);
 
-        let result = (value1 + value2) & value_mask;
-        let carry = (result & msb_mask) > 0;
+        // The sum is computed on a wider type to keep the carry out of the word
+        let sum = value1 as u128 + value2 as u128;
+        let carry = (sum >> self.params.word_size) & 1 == 1;
+        let result = self.on_overflow("add", sum as usize & value_mask, value_mask, carry)?;
 
         self.write_reg(reg1, result);
         self.state.flag = carry;
+
+        Ok(())
     }
 
     /// Defines the `TinyRAM` "sub" instruction
-    fn sub(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) {
-        let msb_mask = 1 << (self.params.word_size - 1);
+    ///
+    /// The flag is raised when a borrow occurs, i.e. when the argument is
+    /// greater than the register value.
+    fn sub(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) -> Result<(), Report> {
+        let value_mask = self.value_mask();
 
-        // HOTFIX: 2^64 will overflow otherwise
-        let value_mask = if self.params.word_size == 64 {
-            usize::MAX
-        } else {
-            (1 << self.params.word_size) - 1
-        };
+        let value1 = self.read_reg(reg2) & value_mask;
+        let value2 = self.resolve(arg) & value_mask;
 
-        let value1 = self.read_reg(reg2);
-        let value2 = self.resolve(arg);
-
-        let result = (value_mask - value2 + value1 + 1) & value_mask;
-        let carry = (result & msb_mask) > 0;
+        let borrow = value1 < value2;
+        let result =
+            self.on_overflow("sub", value1.wrapping_sub(value2) & value_mask, 0, borrow)?;
 
         self.write_reg(reg1, result);
-        self.state.flag = !carry;
+        self.state.flag = borrow;
+
+        Ok(())
     }
 
-    /// Defines the `TinyRAM` "mull" instruction
-    fn mull(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) {
-        // HOTFIX: 2^64 will overflow otherwise
-        let value_mask = if self.params.word_size == 64 {
-            usize::MAX
-        } else {
-            (1 << self.params.word_size) - 1
-        };
+    /// Full product of a register by an argument, both read as unsigned words
+    ///
+    /// The product of two words needs up to twice the word size, so it is
+    /// computed on a `u128` to never overflow, even with 64-bit words.
+    fn unsigned_product(&self, reg: &Register, arg: &Argument) -> u128 {
+        let value_mask = self.value_mask();
 
-        let value1 = self.read_reg(reg2);
-        let value2 = self.resolve(arg);
+        let value1 = self.read_reg(reg) & value_mask;
+        let value2 = self.resolve(arg) & value_mask;
 
-        let result = value1 * value2;
-        let carry = result > value_mask;
-        let result = result & value_mask;
+        value1 as u128 * value2 as u128
+    }
+
+    /// Defines the `TinyRAM` "mull" instruction
+    ///
+    /// The flag is raised when the high word of the product is not zero.
+    fn mull(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) -> Result<(), Report> {
+        let value_mask = self.value_mask();
+
+        let product = self.unsigned_product(reg2, arg);
+        let carry = product >> self.params.word_size != 0;
+        let result = self.on_overflow("mull", product as usize & value_mask, value_mask, carry)?;
 
         self.write_reg(reg1, result);
         self.state.flag = carry;
+
+        Ok(())
+    }
+
+    /// Defines the `TinyRAM` "umulh" instruction
+    ///
+    /// Stores the high word of the product, raising the flag when it is not
+    /// zero like "mull" does.
+    fn umulh(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) {
+        let product = self.unsigned_product(reg2, arg);
+        let result = (product >> self.params.word_size) as usize & self.value_mask();
+
+        self.write_reg(reg1, result);
+        self.state.flag = result != 0;
+    }
+
+    /// Defines the `TinyRAM` "smulh" instruction
+    ///
+    /// Stores the high word of the signed product, raising the flag when the
+    /// product does not fit in a signed word.
+    fn smulh(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) {
+        let value_mask = self.value_mask();
+
+        let value1 = self.signed_word(self.read_reg(reg2) & value_mask) as i128;
+        let value2 = self.signed_word(self.resolve(arg) & value_mask) as i128;
+        let product = value1 * value2;
+
+        let result = (product >> self.params.word_size) as usize & value_mask;
+        let bound = 1_i128 << (self.params.word_size - 1);
+
+        self.write_reg(reg1, result);
+        self.state.flag = product < -bound || product >= bound;
+    }
+
+    /// Pick the result of an arithmetic instruction according to the overflow mode
+    fn on_overflow(
+        &self,
+        opcode: &'static str,
+        wrapped: usize,
+        saturated: usize,
+        overflow: bool,
+    ) -> Result<usize, Report> {
+        match (overflow, self.overflow_mode) {
+            (false, _) | (true, OverflowMode::Wrap) => Ok(wrapped),
+            (true, OverflowMode::Saturate) => Ok(saturated),
+            (true, OverflowMode::Trap) => Err(VmError::Overflow {
+                pc: self.state.pc,
+                opcode,
+            }
+            .into()),
+        }
     }
 
     /// Defines the `TinyRAM` "udiv" instruction
     fn udiv(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) {
-        // HOTFIX: 2^64 will overflow otherwise
-        let value_mask = if self.params.word_size == 64 {
-            usize::MAX
-        } else {
-            (1 << self.params.word_size) - 1
-        };
+        let value_mask = self.value_mask();
 
         let value1 = self.resolve(arg);
+        let value2 = self.read_reg(reg2);
 
-        let (result, flag) = if value1 == 0 {
-            (0, true)
-        } else {
-            let value2 = self.read_reg(reg2);
-            ((value2 / value1) & value_mask, false)
+        let (result, flag) = match value2.checked_div(value1) {
+            Some(quotient) => (quotient & value_mask, false),
+            _ => (0, true),
         };
 
         self.write_reg(reg1, result);
@@ -455,12 +1320,7 @@ impl TinyVM {
 
     /// Defines the `TinyRAM` "umod" instruction
     fn umod(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) {
-        // HOTFIX: 2^64 will overflow otherwise
-        let value_mask = if self.params.word_size == 64 {
-            usize::MAX
-        } else {
-            (1 << self.params.word_size) - 1
-        };
+        let value_mask = self.value_mask();
 
         let value1 = self.resolve(arg);
 
@@ -476,24 +1336,32 @@ impl TinyVM {
     }
 
     /// Defines the `TinyRAM` "shl" instruction
-    fn shl(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) {
+    fn shl(&mut self, reg1: &Register, reg2: &Register, arg: &Argument) -> Result<(), Report> {
         let value1 = self.resolve(arg);
         let value2 = self.read_reg(reg2);
 
-        // HOTFIX: 2^64 will overflow otherwise
-        let value_mask = if self.params.word_size == 64 {
-            usize::MAX
-        } else {
-            (1 << self.params.word_size) - 1
-        };
+        let value_mask = self.value_mask();
 
         let msb_mask = 1 << (self.params.word_size - 1);
 
-        let result = (value2 << value1) & value_mask;
+        // Shifting all the bits out of the word yields 0
+        let amount = u32::try_from(value1).ok();
+        let shifted = amount.and_then(|amount| value2.checked_shl(amount));
+
+        // Bits set beyond the word are lost by the shift
+        let kept_bits = amount
+            .and_then(|amount| u32::from(self.params.word_size).checked_sub(amount))
+            .unwrap_or(0);
+        let lost = (value2 & value_mask).checked_shr(kept_bits).unwrap_or(0) != 0;
+
+        let result = shifted.unwrap_or(0) & value_mask;
+        let result = self.on_overflow("shl", result, value_mask, lost)?;
         let carry = (result & msb_mask) > 0;
 
         self.write_reg(reg1, result);
         self.state.flag = carry;
+
+        Ok(())
     }
 
     /// Defines the `TinyRAM` "shr" instruction
@@ -501,65 +1369,65 @@ impl TinyVM {
         let value1 = self.resolve(arg);
         let value2 = self.read_reg(reg2);
 
-        // HOTFIX: 2^64 will overflow otherwise
-        let value_mask = if self.params.word_size == 64 {
-            usize::MAX
-        } else {
-            (1 << self.params.word_size) - 1
-        };
+        let value_mask = self.value_mask();
 
         let lsb_mask = 1;
 
-        let result = (value2 >> value1) & value_mask;
+        // Shifting all the bits out of the word yields 0
+        let shifted = u32::try_from(value1)
+            .ok()
+            .and_then(|amount| value2.checked_shr(amount));
+        let result = shifted.unwrap_or(0) & value_mask;
         let carry = (result & lsb_mask) > 0;
 
         self.write_reg(reg1, result);
         self.state.flag = carry;
     }
 
-    /// Defines the `TinyRAM` "cmpe" instruction
+    /// Defines the `TinyRAM` "cmpe" instruction, the flag is set if `[reg] == A`
     fn cmpe(&mut self, reg: &Register, arg: &Argument) {
-        let value1 = self.resolve(arg);
-        let value2 = self.read_reg(reg);
+        let reg_value = self.read_reg(reg);
+        let arg_value = self.resolve(arg);
 
-        let equal = value1 == value2;
-        self.state.flag = equal;
+        self.state.flag = reg_value == arg_value;
     }
 
-    /// Defines the `TinyRAM` "cmpa" instruction
+    /// Defines the `TinyRAM` "cmpa" instruction, the flag is set if `[reg] > A` as unsigned words
     fn cmpa(&mut self, reg: &Register, arg: &Argument) {
-        let value1 = self.resolve(arg);
-        let value2 = self.read_reg(reg);
+        let reg_value = self.read_reg(reg);
+        let arg_value = self.resolve(arg);
 
-        let above = value1 < value2;
-        self.state.flag = above;
+        self.state.flag = reg_value > arg_value;
     }
 
-    /// Defines the `TinyRAM` "cmpae" instruction
+    /// Defines the `TinyRAM` "cmpae" instruction, the flag is set if `[reg] >= A` as unsigned words
     fn cmpae(&mut self, reg: &Register, arg: &Argument) {
-        let value1 = self.resolve(arg);
-        let value2 = self.read_reg(reg);
+        let reg_value = self.read_reg(reg);
+        let arg_value = self.resolve(arg);
 
-        let above = value1 <= value2;
-        self.state.flag = above;
+        self.state.flag = reg_value >= arg_value;
     }
 
-    /// Defines the `TinyRAM` "cmpg" instruction
+    /// Defines the `TinyRAM` "cmpg" instruction, the flag is set if `[reg] > A` as signed words
     fn cmpg(&mut self, reg: &Register, arg: &Argument) {
-        let value1 = Self::to_signed(self.resolve(arg) as u64);
-        let value2 = Self::to_signed(self.read_reg(reg) as u64);
+        let reg_value = self.signed_word(self.read_reg(reg));
+        let arg_value = self.signed_word(self.resolve(arg));
 
-        let above = value1 < value2;
-        self.state.flag = above;
+        self.state.flag = reg_value > arg_value;
     }
 
-    /// Defines the `TinyRAM` "cmpge" instruction
+    /// Defines the `TinyRAM` "cmpge" instruction, the flag is set if `[reg] >= A` as signed words
     fn cmpge(&mut self, reg: &Register, arg: &Argument) {
-        let value1 = Self::to_signed(self.resolve(arg) as u64);
-        let value2 = Self::to_signed(self.read_reg(reg) as u64);
+        let reg_value = self.signed_word(self.read_reg(reg));
+        let arg_value = self.signed_word(self.resolve(arg));
+
+        self.state.flag = reg_value >= arg_value;
+    }
 
-        let above = value1 <= value2;
-        self.state.flag = above;
+    /// Interpret a word as a two's complement signed value of the word size
+    pub fn signed_word(&self, x: usize) -> i64 {
+        let shift = 64 - u32::from(self.params.word_size);
+        Self::to_signed((x as u64) << shift) >> shift
     }
 
     /// Defines the `TinyRAM` "amswer" instruction
@@ -570,23 +1438,36 @@ impl TinyVM {
     }
 
     /// Defines the `TinyRAM` "jmp" instruction
-    fn jmp(&mut self, arg: &Argument) -> usize {
-        self.resolve(arg)
+    fn jmp(&mut self, arg: &Argument) -> Result<usize, Report> {
+        let target = self.resolve(arg);
+
+        // Von Neumann programs may jump to code they wrote to memory
+        if let ArchType::VonNeumann = self.params.arch {
+            self.check_memory_access(target.saturating_mul(INSTRUCTION_SIZE), INSTRUCTION_SIZE)?;
+        } else if target >= self.state.program.len() {
+            return Err(VmError::JumpOutOfBounds {
+                target,
+                program_size: self.state.program.len(),
+            }
+            .into());
+        }
+
+        Ok(target)
     }
 
     /// Defines the `TinyRAM` "cjmp" instruction
-    fn cjmp(&mut self, arg: &Argument) -> usize {
+    fn cjmp(&mut self, arg: &Argument) -> Result<usize, Report> {
         if self.state.flag {
             self.jmp(arg)
         } else {
-            self.state.pc + 1
+            Ok(self.state.pc + 1)
         }
     }
 
     /// Defines the `TinyRAM` "cnjmp" instruction
-    fn cnjmp(&mut self, arg: &Argument) -> usize {
+    fn cnjmp(&mut self, arg: &Argument) -> Result<usize, Report> {
         if self.state.flag {
-            self.state.pc + 1
+            Ok(self.state.pc + 1)
         } else {
             self.jmp(arg)
         }
@@ -595,7 +1476,10 @@ impl TinyVM {
     /// Defines the `TinyRAM` "read" instruction
     fn read(&mut self, reg: &Register, arg: &Argument) {
         let tape = self.resolve(arg);
-        let has_tape = (!self.state.tape1.is_empty(), !self.state.tape2.is_empty());
+        let has_tape = (
+            self.state.tape_pos.0 < self.state.tape1.len(),
+            self.state.tape_pos.1 < self.state.tape2.len(),
+        );
 
         let value = match (tape, has_tape) {
             (0, (true, _)) => {
@@ -629,53 +1513,49 @@ impl TinyVM {
     }
 
     /// Defines the `TinyRAM` "store.b" instruction
-    fn store_b(&mut self, arg: &Argument, reg: &Register) {
+    fn store_b(&mut self, arg: &Argument, reg: &Register) -> Result<(), Report> {
         let addr = self.resolve(arg);
         let value = self.read_reg(reg);
+        self.check_memory_access(addr, 1)?;
 
-        // HOTFIX: 2^64 will overflow otherwise
-        let value_mask = if self.params.word_size == 64 {
-            usize::MAX
-        } else {
-            (1 << self.params.word_size) - 1
-        };
+        // Only the low byte of the register is stored
+        let result = value & 0xff;
 
-        let result = value & value_mask;
+        self.state.write_memory(addr, &[result as u8]);
 
-        if self.state.memory.len() <= addr {
-            self.state.memory.resize(addr + 1, 0);
-        }
-
-        self.state.memory[addr] = result as u8;
+        Ok(())
     }
 
     /// Defines the `TinyRAM` "store.w" instruction
-    fn store_w(&mut self, arg: &Argument, reg: &Register) {
+    fn store_w(&mut self, arg: &Argument, reg: &Register) -> Result<(), Report> {
         let addr = self.resolve(arg);
         let value = self.read_reg(reg);
+        self.check_memory_access(addr, 8)?;
 
-        if self.state.memory.len() < addr + 8 {
-            self.state.memory.resize(addr + 8, 0)
-        }
+        self.state.write_memory(addr, &value.to_le_bytes());
 
-        self.state
-            .memory
-            .splice(addr..(addr + 8), value.to_le_bytes());
+        Ok(())
     }
 
     /// Defines the `TinyRAM` "load.b" instruction
-    fn load_b(&mut self, reg: &Register, arg: &Argument) {
+    fn load_b(&mut self, reg: &Register, arg: &Argument) -> Result<(), Report> {
         let addr = self.resolve(arg);
-        let val = self.state.memory[addr] as usize;
+        self.check_memory_access(addr, 1)?;
+        let val = self.state.memory.get(addr).copied().unwrap_or(0) as usize;
 
-        self.write_reg(reg, val as usize);
+        self.write_reg(reg, val);
+
+        Ok(())
     }
 
     /// Defines the `TinyRAM` "load.w" instruction
-    fn load_w(&mut self, reg: &Register, arg: &Argument) {
+    fn load_w(&mut self, reg: &Register, arg: &Argument) -> Result<(), Report> {
         let addr = self.resolve(arg);
-        let val: [u8; 8] = <[u8; 8]>::try_from(&self.state.memory[addr..(addr + 8)]).unwrap();
+        self.check_memory_access(addr, 8)?;
+        let val = self.read_memory_word(addr);
 
-        self.write_reg(reg, usize::from_le_bytes(val));
+        self.write_reg(reg, val);
+
+        Ok(())
     }
 }
@@ -0,0 +1,35 @@
+use color_eyre::Report;
+
+use std::process::Command;
+
+#[test]
+fn check_programs() -> Result<(), Report> {
+    let check = |program: &str| {
+        Command::new(env!("CARGO_BIN_EXE_tinyvm"))
+            .args(["--check", program])
+            .output()
+    };
+
+    let good = check("../assets/fib.tr")?;
+    let stdout = String::from_utf8_lossy(&good.stdout);
+    println!("{}", stdout);
+
+    assert!(good.status.success());
+    assert_eq!(
+        stdout.trim(),
+        "OK: 15 instructions, 3 labels, highest register r4, uses memory"
+    );
+
+    let bad = check("../assets/invalid/undefined_label.tr")?;
+    let stderr = String::from_utf8_lossy(&bad.stderr);
+    println!("{}", stderr);
+
+    assert!(!bad.status.success());
+    assert!(stderr.contains("Undefined label '_nowhere'"));
+
+    // Programs that cannot terminate are rejected too
+    let looping = check("../assets/infinite_loop.tr")?;
+    assert!(!looping.status.success());
+
+    Ok(())
+}